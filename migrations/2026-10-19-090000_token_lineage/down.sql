@@ -0,0 +1,4 @@
+-- This file should undo anything in `up.sql`
+DROP INDEX IF EXISTS tokens_parent;
+ALTER TABLE tokens DROP COLUMN parent;
+ALTER TABLE tokens DROP COLUMN scopes;
@@ -0,0 +1,6 @@
+-- Your SQL goes here
+
+ALTER TABLE tokens ADD COLUMN scopes TEXT;
+ALTER TABLE tokens ADD COLUMN parent TEXT REFERENCES tokens(id);
+
+CREATE INDEX IF NOT EXISTS tokens_parent ON tokens(parent);
@@ -0,0 +1,10 @@
+-- Your SQL goes here
+
+CREATE TABLE IF NOT EXISTS signing_keys (
+  kid TEXT NOT NULL UNIQUE PRIMARY KEY,
+  public TEXT NOT NULL,
+  private TEXT NOT NULL,
+  created_at TEXT NOT NULL,
+  retired_at TEXT,
+  active BOOLEAN NOT NULL DEFAULT FALSE
+);
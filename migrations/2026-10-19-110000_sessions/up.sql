@@ -0,0 +1,10 @@
+-- Your SQL goes here
+
+CREATE TABLE IF NOT EXISTS sessions (
+  id TEXT NOT NULL UNIQUE PRIMARY KEY,
+  user_id TEXT NOT NULL,
+  sub TEXT NOT NULL,
+  auth_time TEXT NOT NULL,
+  amr TEXT NOT NULL,
+  expires_at TEXT NOT NULL
+);
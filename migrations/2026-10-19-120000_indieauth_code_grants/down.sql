@@ -0,0 +1,4 @@
+-- This file should undo anything in `up.sql`
+ALTER TABLE indieauth_codes DROP COLUMN sub;
+ALTER TABLE indieauth_codes DROP COLUMN scope;
+ALTER TABLE indieauth_codes DROP COLUMN me;
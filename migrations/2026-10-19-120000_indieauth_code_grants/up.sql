@@ -0,0 +1,5 @@
+-- Your SQL goes here
+
+ALTER TABLE indieauth_codes ADD COLUMN me TEXT;
+ALTER TABLE indieauth_codes ADD COLUMN scope TEXT;
+ALTER TABLE indieauth_codes ADD COLUMN sub TEXT;
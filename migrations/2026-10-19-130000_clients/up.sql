@@ -0,0 +1,12 @@
+-- Your SQL goes here
+
+CREATE TABLE IF NOT EXISTS clients (
+  id TEXT NOT NULL UNIQUE PRIMARY KEY,
+  name TEXT NOT NULL,
+  secret_hash TEXT,
+  public_key TEXT,
+  audiences TEXT NOT NULL DEFAULT '',
+  scopes TEXT NOT NULL DEFAULT '',
+  token_lifetime INTEGER NOT NULL DEFAULT 900,
+  created_at TEXT NOT NULL
+);
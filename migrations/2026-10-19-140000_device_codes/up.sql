@@ -0,0 +1,13 @@
+-- Your SQL goes here
+
+CREATE TABLE IF NOT EXISTS device_codes (
+  device_code TEXT NOT NULL UNIQUE PRIMARY KEY,
+  user_code TEXT NOT NULL UNIQUE,
+  client_id TEXT NOT NULL,
+  scope TEXT,
+  "status" TEXT NOT NULL DEFAULT 'pending',
+  sub TEXT,
+  expires_at TEXT NOT NULL,
+  "interval" INTEGER NOT NULL,
+  last_polled_at TEXT
+);
@@ -0,0 +1,8 @@
+-- Your SQL goes here
+
+CREATE TABLE IF NOT EXISTS pushed_authorization_requests (
+  request_uri TEXT NOT NULL UNIQUE PRIMARY KEY,
+  client_id TEXT NOT NULL,
+  request TEXT NOT NULL,
+  expires_at TEXT NOT NULL
+);
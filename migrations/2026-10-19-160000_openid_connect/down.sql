@@ -0,0 +1,4 @@
+-- This file should undo anything in `up.sql`
+ALTER TABLE indieauth_codes DROP COLUMN amr;
+ALTER TABLE indieauth_codes DROP COLUMN auth_time;
+ALTER TABLE indieauth_codes DROP COLUMN nonce;
@@ -0,0 +1,5 @@
+-- Your SQL goes here
+
+ALTER TABLE indieauth_codes ADD COLUMN nonce TEXT;
+ALTER TABLE indieauth_codes ADD COLUMN auth_time TEXT;
+ALTER TABLE indieauth_codes ADD COLUMN amr TEXT;
@@ -0,0 +1,3 @@
+-- This file should undo anything in `up.sql`
+ALTER TABLE clients DROP COLUMN sector_identifier;
+ALTER TABLE clients DROP COLUMN subject_type;
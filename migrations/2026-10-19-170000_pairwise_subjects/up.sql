@@ -0,0 +1,4 @@
+-- Your SQL goes here
+
+ALTER TABLE clients ADD COLUMN subject_type TEXT NOT NULL DEFAULT 'public';
+ALTER TABLE clients ADD COLUMN sector_identifier TEXT;
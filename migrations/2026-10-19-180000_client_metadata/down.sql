@@ -0,0 +1,5 @@
+-- This file should undo anything in `up.sql`
+ALTER TABLE clients DROP COLUMN id_token_lifetime;
+ALTER TABLE clients DROP COLUMN first_party;
+ALTER TABLE clients DROP COLUMN client_type;
+ALTER TABLE clients DROP COLUMN redirect_uris;
@@ -0,0 +1,6 @@
+-- Your SQL goes here
+
+ALTER TABLE clients ADD COLUMN redirect_uris TEXT NOT NULL DEFAULT '';
+ALTER TABLE clients ADD COLUMN client_type TEXT NOT NULL DEFAULT 'confidential';
+ALTER TABLE clients ADD COLUMN first_party BOOLEAN NOT NULL DEFAULT 0;
+ALTER TABLE clients ADD COLUMN id_token_lifetime INTEGER NOT NULL DEFAULT 600;
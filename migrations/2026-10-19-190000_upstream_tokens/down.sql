@@ -0,0 +1,13 @@
+-- This file should undo anything in `up.sql`
+CREATE TABLE IF NOT EXISTS gitlab_tokens (
+  id TEXT NOT NULL UNIQUE PRIMARY KEY,
+  user_id INTEGER NOT NULL,
+  access_token TEXT NOT NULL,
+  refresh_token TEXT NOT NULL
+);
+
+INSERT INTO gitlab_tokens (id, user_id, access_token, refresh_token)
+  SELECT id, CAST(user_id AS INTEGER), access_token, COALESCE(refresh_token, '')
+  FROM upstream_tokens WHERE provider = 'gitlab';
+
+DROP TABLE IF EXISTS upstream_tokens;
@@ -0,0 +1,14 @@
+-- Your SQL goes here
+
+CREATE TABLE IF NOT EXISTS upstream_tokens (
+  id TEXT NOT NULL UNIQUE PRIMARY KEY,
+  provider TEXT NOT NULL,
+  user_id TEXT NOT NULL,
+  access_token TEXT NOT NULL,
+  refresh_token TEXT
+);
+
+INSERT INTO upstream_tokens (id, provider, user_id, access_token, refresh_token)
+  SELECT id, 'gitlab', CAST(user_id AS TEXT), access_token, refresh_token FROM gitlab_tokens;
+
+DROP TABLE gitlab_tokens;
@@ -0,0 +1,9 @@
+-- Your SQL goes here
+
+CREATE TABLE IF NOT EXISTS mastodon_apps (
+  instance TEXT NOT NULL UNIQUE PRIMARY KEY,
+  client_id TEXT NOT NULL,
+  client_secret TEXT NOT NULL,
+  redirect_uri TEXT NOT NULL,
+  created_at TEXT NOT NULL
+);
@@ -0,0 +1,15 @@
+-- Your SQL goes here
+
+CREATE TABLE IF NOT EXISTS passkeys (
+  id TEXT NOT NULL UNIQUE PRIMARY KEY,
+  user_id TEXT NOT NULL,
+  sub TEXT NOT NULL,
+  name TEXT NOT NULL,
+  public_key TEXT NOT NULL,
+  alg INTEGER NOT NULL,
+  sign_count INTEGER NOT NULL,
+  created_at TEXT NOT NULL,
+  last_used_at TEXT
+);
+
+CREATE INDEX IF NOT EXISTS passkeys_user_id ON passkeys (user_id);
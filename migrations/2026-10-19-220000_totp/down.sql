@@ -0,0 +1,5 @@
+-- This file should undo anything in `up.sql`
+ALTER TABLE clients DROP COLUMN require_mfa;
+DROP INDEX IF EXISTS recovery_codes_user_id;
+DROP TABLE IF EXISTS recovery_codes;
+DROP TABLE IF EXISTS totp_secrets;
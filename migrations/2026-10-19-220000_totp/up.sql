@@ -0,0 +1,21 @@
+-- Your SQL goes here
+
+CREATE TABLE IF NOT EXISTS totp_secrets (
+  user_id TEXT NOT NULL UNIQUE PRIMARY KEY,
+  secret TEXT NOT NULL,
+  confirmed BOOLEAN NOT NULL DEFAULT 0,
+  last_step BIGINT,
+  failures INTEGER NOT NULL DEFAULT 0,
+  created_at TEXT NOT NULL
+);
+
+CREATE TABLE IF NOT EXISTS recovery_codes (
+  hash TEXT NOT NULL UNIQUE PRIMARY KEY,
+  user_id TEXT NOT NULL,
+  created_at TEXT NOT NULL,
+  used_at TEXT
+);
+
+CREATE INDEX IF NOT EXISTS recovery_codes_user_id ON recovery_codes (user_id);
+
+ALTER TABLE clients ADD COLUMN require_mfa BOOLEAN NOT NULL DEFAULT 0;
@@ -0,0 +1,3 @@
+-- This file should undo anything in `up.sql`
+DROP INDEX IF EXISTS email_logins_email;
+DROP TABLE IF EXISTS email_logins;
@@ -0,0 +1,11 @@
+-- Your SQL goes here
+
+CREATE TABLE IF NOT EXISTS email_logins (
+  id TEXT NOT NULL UNIQUE PRIMARY KEY,
+  email TEXT NOT NULL,
+  created_at TEXT NOT NULL,
+  expires_at TEXT NOT NULL,
+  used_at TEXT
+);
+
+CREATE INDEX IF NOT EXISTS email_logins_email ON email_logins (email);
@@ -0,0 +1,13 @@
+-- Your SQL goes here
+
+CREATE TABLE IF NOT EXISTS login_keys (
+  id TEXT NOT NULL UNIQUE PRIMARY KEY,
+  user_id TEXT NOT NULL,
+  sub TEXT NOT NULL,
+  name TEXT NOT NULL,
+  public_key TEXT NOT NULL,
+  created_at TEXT NOT NULL,
+  last_used_at TEXT
+);
+
+CREATE INDEX IF NOT EXISTS login_keys_sub ON login_keys (sub);
@@ -0,0 +1,3 @@
+-- Your SQL goes here
+-- each PASETO version gets keys of its own; the ones from before were v2 keys
+ALTER TABLE signing_keys ADD COLUMN version TEXT NOT NULL DEFAULT 'v2';
@@ -0,0 +1,2 @@
+-- This file should undo anything in `up.sql`
+ALTER TABLE indieauth_codes DROP COLUMN custom_claims;
@@ -0,0 +1,2 @@
+-- Your SQL goes here
+ALTER TABLE indieauth_codes ADD COLUMN custom_claims TEXT;
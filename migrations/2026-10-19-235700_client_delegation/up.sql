@@ -0,0 +1,2 @@
+-- Your SQL goes here
+ALTER TABLE clients ADD COLUMN delegation BOOLEAN NOT NULL DEFAULT 0;
@@ -0,0 +1,10 @@
+-- Your SQL goes here
+
+CREATE TABLE IF NOT EXISTS users (
+  sub TEXT NOT NULL UNIQUE PRIMARY KEY,
+  name TEXT NOT NULL,
+  website TEXT,
+  email TEXT,
+  email_verified BOOLEAN NOT NULL DEFAULT 0,
+  updated_at TEXT NOT NULL
+);
@@ -0,0 +1,7 @@
+-- This file should undo anything in `up.sql`
+DROP INDEX IF EXISTS recovery_codes_sub;
+ALTER TABLE recovery_codes RENAME COLUMN sub TO user_id;
+ALTER TABLE totp_secrets RENAME COLUMN sub TO user_id;
+CREATE INDEX IF NOT EXISTS recovery_codes_user_id ON recovery_codes (user_id);
+ALTER TABLE totp_secrets DROP COLUMN recovery_failed_at;
+ALTER TABLE totp_secrets DROP COLUMN recovery_failures;
@@ -0,0 +1,17 @@
+-- Your SQL goes here
+ALTER TABLE totp_secrets ADD COLUMN recovery_failures INTEGER NOT NULL DEFAULT 0;
+ALTER TABLE totp_secrets ADD COLUMN recovery_failed_at TEXT;
+-- second factors were kept under the upstream user id; move them to the subject it
+-- logged in as, so every way of logging in as someone asks for them
+UPDATE OR IGNORE totp_secrets SET user_id = (
+    SELECT sub FROM sessions WHERE sessions.user_id = totp_secrets.user_id
+    ORDER BY expires_at DESC LIMIT 1
+) WHERE user_id IN (SELECT user_id FROM sessions);
+UPDATE recovery_codes SET user_id = (
+    SELECT sub FROM sessions WHERE sessions.user_id = recovery_codes.user_id
+    ORDER BY expires_at DESC LIMIT 1
+) WHERE user_id IN (SELECT user_id FROM sessions);
+ALTER TABLE totp_secrets RENAME COLUMN user_id TO sub;
+ALTER TABLE recovery_codes RENAME COLUMN user_id TO sub;
+DROP INDEX IF EXISTS recovery_codes_user_id;
+CREATE INDEX IF NOT EXISTS recovery_codes_sub ON recovery_codes (sub);
@@ -0,0 +1,3 @@
+-- Your SQL goes here
+-- the subject a token was issued for, when its own is the pairwise one a client sees
+ALTER TABLE tokens ADD COLUMN user_sub TEXT;
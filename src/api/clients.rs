@@ -0,0 +1,857 @@
+use std::{
+    collections::HashMap,
+    num::NonZeroU32,
+    sync::{Arc, Mutex},
+};
+
+use base64::{
+    engine::general_purpose::{
+        STANDARD as BASE64_STANDARD, URL_SAFE_NO_PAD as BASE64_URL_SAFE_NO_PAD,
+    },
+    Engine,
+};
+use chrono::Utc;
+use diesel::prelude::*;
+use ring::{
+    constant_time, pbkdf2,
+    rand::{SecureRandom, SystemRandom},
+};
+use rocket::{
+    delete,
+    fairing::AdHoc,
+    get,
+    http::Status,
+    post, put,
+    request::{self, FromRequest},
+    serde::{json::Json, Deserialize, Serialize},
+    Request, State,
+};
+use serde_json::Value;
+use tracing::instrument;
+
+use crate::{
+    fetch::Fetcher,
+    jws, models, paseto, schema,
+    session::{Session, AMR_MFA},
+    subject, BaseUrl, MainDatabase,
+};
+
+use super::{redirect, Error, Result};
+
+const PBKDF2_ITERATIONS: u32 = 100_000;
+
+pub const CLIENT_ASSERTION_TYPE: &str = "urn:ietf:params:oauth:client-assertion-type:jwt-bearer";
+
+/// How long a `client_assertion` can be valid for, in seconds. Its `jti` is kept
+/// that long to catch replays.
+const MAX_ASSERTION_LIFETIME: i64 = 5 * 60;
+
+pub(crate) fn random_string(len: usize) -> Result<String> {
+    let mut buf = vec![0; len];
+    SystemRandom::new()
+        .fill(&mut buf)
+        .map_err(|_| Error::OAuth2("failed to generate random data".into()))?;
+    Ok(BASE64_URL_SAFE_NO_PAD.encode(buf))
+}
+
+pub fn hash_secret(secret: &str) -> Result<String> {
+    let mut salt = [0; 16];
+    SystemRandom::new()
+        .fill(&mut salt)
+        .map_err(|_| Error::OAuth2("failed to generate random data".into()))?;
+    let mut hash = [0; 32];
+    pbkdf2::derive(
+        pbkdf2::PBKDF2_HMAC_SHA256,
+        NonZeroU32::new(PBKDF2_ITERATIONS).unwrap(),
+        &salt,
+        secret.as_bytes(),
+        &mut hash,
+    );
+    Ok(format!(
+        "pbkdf2-sha256${PBKDF2_ITERATIONS}${}${}",
+        BASE64_URL_SAFE_NO_PAD.encode(salt),
+        BASE64_URL_SAFE_NO_PAD.encode(hash)
+    ))
+}
+
+pub fn verify_secret(secret: &str, stored: &str) -> bool {
+    let mut parts = stored.split('$');
+    let algorithm = parts.next();
+    let iterations = parts
+        .next()
+        .and_then(|i| i.parse().ok())
+        .and_then(NonZeroU32::new);
+    let mut decode = || {
+        parts
+            .next()
+            .and_then(|p| BASE64_URL_SAFE_NO_PAD.decode(p).ok())
+    };
+    match (algorithm, iterations, decode(), decode()) {
+        (Some("pbkdf2-sha256"), Some(iterations), Some(salt), Some(hash)) => pbkdf2::verify(
+            pbkdf2::PBKDF2_HMAC_SHA256,
+            iterations,
+            &salt,
+            secret.as_bytes(),
+            &hash,
+        )
+        .is_ok(),
+        _ => false,
+    }
+}
+
+pub const CONFIDENTIAL: &str = "confidential";
+pub const PUBLIC: &str = "public";
+
+impl models::Client {
+    pub fn is_public(&self) -> bool {
+        self.client_type == PUBLIC
+    }
+
+    pub fn redirect_uris(&self) -> Vec<&str> {
+        self.redirect_uris.split_whitespace().collect()
+    }
+
+    pub fn audiences(&self) -> Vec<&str> {
+        self.audiences.split_whitespace().collect()
+    }
+
+    pub fn scopes(&self) -> Vec<&str> {
+        self.scopes.split_whitespace().collect()
+    }
+}
+
+/// The `client_assertion`s we've accepted and that haven't expired yet, by client
+/// and `jti`, so none can be used twice.
+#[derive(Debug, Clone, Default)]
+pub struct SeenAssertions(Arc<Mutex<HashMap<(String, String), i64>>>);
+
+impl SeenAssertions {
+    /// Remember an assertion until `exp`, unless it was seen already.
+    fn remember(&self, client_id: &str, jti: &str, exp: i64) -> Result {
+        let now = Utc::now().timestamp();
+        let mut seen = self.0.lock().unwrap();
+        seen.retain(|_, exp| *exp >= now);
+        match seen.insert((client_id.to_string(), jti.to_string()), exp) {
+            Some(_) => Err(Error::InvalidClient),
+            None => Ok(()),
+        }
+    }
+}
+
+/// What a client can present about itself outside the request body: HTTP Basic
+/// credentials, and the URL a `client_assertion` has to be addressed to.
+#[derive(Debug)]
+pub struct ClientAuth {
+    basic: Option<(String, String)>,
+    endpoint: String,
+    seen: SeenAssertions,
+}
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for ClientAuth {
+    type Error = Error;
+
+    async fn from_request(request: &'r Request<'_>) -> request::Outcome<Self, Self::Error> {
+        let basic = request
+            .headers()
+            .get_one("Authorization")
+            .and_then(|h| h.strip_prefix("Basic "))
+            .and_then(|creds| BASE64_STANDARD.decode(creds).ok())
+            .and_then(|creds| String::from_utf8(creds).ok())
+            .and_then(|creds| {
+                let (id, secret) = creds.split_once(':')?;
+                Some((id.to_string(), secret.to_string()))
+            });
+        let endpoint = request
+            .rocket()
+            .state::<BaseUrl>()
+            .expect("base URL fairing attached")
+            .url_of(request);
+        let seen = request
+            .rocket()
+            .state::<SeenAssertions>()
+            .expect("client registration fairing attached")
+            .clone();
+        request::Outcome::Success(ClientAuth {
+            basic,
+            endpoint,
+            seen,
+        })
+    }
+}
+
+impl ClientAuth {
+    /// Whether the client tried to authenticate at all, here or in `creds`.
+    pub fn presented(&self, creds: &Credentials) -> bool {
+        self.basic.is_some() || creds.client_secret.is_some() || creds.client_assertion.is_some()
+    }
+}
+
+/// Client credentials as they appear in a token request body.
+#[derive(Debug, Default)]
+pub struct Credentials {
+    pub client_id: Option<String>,
+    pub client_secret: Option<String>,
+    pub client_assertion_type: Option<String>,
+    pub client_assertion: Option<String>,
+}
+
+/// Check a signed assertion, returning its `jti` and `exp`. It has to be short-lived,
+/// so it doesn't need remembering for long.
+fn check_assertion(
+    client: &models::Client,
+    assertion: &str,
+    endpoint: &str,
+) -> Result<(String, i64)> {
+    let public = client
+        .public_key
+        .as_deref()
+        .and_then(|key| hex::decode(key).ok())
+        .ok_or(Error::InvalidClient)?;
+    let claims = jws::verify_eddsa(assertion, &public).map_err(|_| Error::InvalidClient)?;
+    let claim = |name: &str| claims.get(name).and_then(Value::as_str);
+    if claim("iss") != Some(client.id.as_str()) || claim("sub") != Some(client.id.as_str()) {
+        return Err(Error::InvalidClient);
+    }
+    let audience_ok = match claims.get("aud") {
+        Some(Value::String(aud)) => aud == endpoint,
+        Some(Value::Array(auds)) => auds.iter().any(|aud| aud.as_str() == Some(endpoint)),
+        _ => false,
+    };
+    let now = Utc::now().timestamp();
+    let (Some(jti), Some(iat), Some(exp)) = (
+        claim("jti"),
+        claims.get("iat").and_then(Value::as_i64),
+        claims.get("exp").and_then(Value::as_i64),
+    ) else {
+        return Err(Error::InvalidClient);
+    };
+    let short_lived = exp - iat <= MAX_ASSERTION_LIFETIME && exp - now <= MAX_ASSERTION_LIFETIME;
+    if !audience_ok || exp < now || !short_lived {
+        return Err(Error::InvalidClient);
+    }
+    Ok((jti.to_string(), exp))
+}
+
+/// Authenticate a confidential client, with a secret or a signed `client_assertion`.
+/// Each assertion works once.
+pub async fn authenticate(
+    db: &MainDatabase,
+    auth: &ClientAuth,
+    creds: Credentials,
+) -> Result<models::Client> {
+    let (client_id, secret) = match (&auth.basic, creds.client_id, creds.client_secret) {
+        (Some((id, secret)), _, _) => (Some(id.clone()), Some(secret.clone())),
+        (None, id, secret) => (id, secret),
+    };
+    let assertion = match (creds.client_assertion_type, creds.client_assertion) {
+        (Some(ty), Some(assertion)) if ty == CLIENT_ASSERTION_TYPE => Some(assertion),
+        (None, None) => None,
+        _ => return Err(Error::InvalidClient),
+    };
+    // with an assertion, the client id may come from the (not yet verified) subject
+    let client_id = client_id
+        .or_else(|| {
+            let assertion = assertion.as_deref()?;
+            let claims = assertion.split('.').nth(1)?;
+            let claims: Value =
+                serde_json::from_slice(&BASE64_URL_SAFE_NO_PAD.decode(claims).ok()?).ok()?;
+            claims.get("sub")?.as_str().map(String::from)
+        })
+        .ok_or(Error::InvalidClient)?;
+    let client: models::Client = db
+        .run(move |c| {
+            use schema::clients::dsl;
+            dsl::clients.find(&client_id).get_result(c).optional()
+        })
+        .await?
+        .ok_or(Error::InvalidClient)?;
+    let authenticated = match (secret, assertion, client.secret_hash.as_deref()) {
+        (Some(secret), None, Some(hash)) => verify_secret(&secret, hash),
+        (None, Some(assertion), _) => match check_assertion(&client, &assertion, &auth.endpoint) {
+            Ok((jti, exp)) => auth.seen.remember(&client.id, &jti, exp).is_ok(),
+            Err(_) => false,
+        },
+        _ => false,
+    };
+    if !authenticated {
+        return Err(Error::InvalidClient);
+    }
+    Ok(client)
+}
+
+/// Look up a registered client. IndieAuth clients, identified by their URL, aren't
+/// in here.
+pub async fn find(db: &MainDatabase, client_id: &str) -> Result<Option<models::Client>> {
+    let client_id = client_id.to_string();
+    Ok(db
+        .run(move |c| {
+            use schema::clients::dsl;
+            dsl::clients.find(&client_id).get_result(c).optional()
+        })
+        .await?)
+}
+
+/// Refuse a session that didn't pass a second factor to clients that want one.
+pub async fn check_mfa(db: &MainDatabase, client_id: &str, session: &Session) -> Result {
+    let required = find(db, client_id)
+        .await?
+        .map_or(false, |client| client.require_mfa);
+    if required && !session.amr.iter().any(|amr| amr == AMR_MFA) {
+        return Err(Error::Forbidden(format!(
+            "{client_id} needs a second factor, log in again with one"
+        )));
+    }
+    Ok(())
+}
+
+/// Find out who the client is. Confidential clients have to authenticate; public
+/// ones, registered or identified by their URL like IndieAuth clients, can't.
+pub async fn identify(db: &MainDatabase, auth: &ClientAuth, creds: Credentials) -> Result<String> {
+    if auth.presented(&creds) {
+        let claimed = creds.client_id.clone();
+        let client = authenticate(db, auth, creds).await?;
+        if claimed.map_or(false, |id| id != client.id) {
+            return Err(Error::InvalidClient);
+        }
+        return Ok(client.id);
+    }
+    let client_id = creds.client_id.ok_or(Error::Grant("invalid_request"))?;
+    match find(db, &client_id).await? {
+        Some(client) if !client.is_public() => Err(Error::InvalidClient),
+        _ => Ok(client_id),
+    }
+}
+
+fn require_admin(tok: &paseto::Token) -> Result {
+    if !tok.is_admin() {
+        return Err(Error::Forbidden("only admins can manage clients".into()));
+    }
+    Ok(())
+}
+
+fn check_subject_type(subject_type: &str) -> Result {
+    match subject_type {
+        subject::PUBLIC | subject::PAIRWISE => Ok(()),
+        _ => Err(Error::Grant("invalid_client_metadata")),
+    }
+}
+
+fn check_redirect_uris(uris: &[String]) -> Result {
+    for uri in uris {
+        redirect::check(uri)?;
+    }
+    Ok(())
+}
+
+/// Pairwise clients need a sector to derive subjects from, see [`subject::check_sector`].
+async fn check_sector(fetcher: &Fetcher, client: &models::Client) -> Result {
+    if client.subject_type != subject::PAIRWISE {
+        return Ok(());
+    }
+    let identifier = client.sector_identifier.as_deref();
+    subject::check_sector(fetcher, identifier, &client.redirect_uris()).await
+}
+
+#[derive(Deserialize, Debug, Default)]
+#[serde(crate = "rocket::serde")]
+pub struct NewClient {
+    name: String,
+    #[serde(default)]
+    redirect_uris: Vec<String>,
+    #[serde(default)]
+    audiences: Vec<String>,
+    #[serde(default)]
+    scopes: Vec<String>,
+    /// `confidential` (the default) or `public`. Public clients have no credentials.
+    client_type: Option<String>,
+    /// A hex-encoded Ed25519 public key. Clients registered with one authenticate
+    /// with signed assertions instead of a secret.
+    public_key: Option<String>,
+    /// For access tokens, in seconds.
+    token_lifetime: Option<i32>,
+    /// For ID tokens, in seconds.
+    id_token_lifetime: Option<i32>,
+    /// `public` (the default) or `pairwise`.
+    subject_type: Option<String>,
+    /// An https URI listing the client's redirect URIs in a JSON array. Pairwise
+    /// clients whose sector identifiers share a host see the same subjects.
+    sector_identifier: Option<String>,
+    #[serde(default)]
+    first_party: bool,
+    /// Require a second factor before consenting.
+    #[serde(default)]
+    require_mfa: bool,
+    /// Let the client exchange tokens issued to other clients, not just its own.
+    #[serde(default)]
+    delegation: bool,
+}
+
+impl NewClient {
+    /// Check the metadata, returning the client and its secret, if it gets one.
+    fn into_client(self) -> Result<(models::Client, Option<String>)> {
+        let client_type = self.client_type.unwrap_or_else(|| CONFIDENTIAL.to_string());
+        let secret = match (client_type.as_str(), &self.public_key) {
+            (PUBLIC, None) => None,
+            (CONFIDENTIAL, Some(key)) => match hex::decode(key) {
+                Ok(key) if key.len() == 32 => None,
+                _ => {
+                    return Err(Error::InvalidSigningKey(
+                        "expected 32 hex-encoded bytes".into(),
+                    ))
+                }
+            },
+            (CONFIDENTIAL, None) => Some(random_string(32)?),
+            _ => return Err(Error::Grant("invalid_client_metadata")),
+        };
+        let subject_type = self
+            .subject_type
+            .unwrap_or_else(|| subject::PUBLIC.to_string());
+        check_subject_type(&subject_type)?;
+        check_redirect_uris(&self.redirect_uris)?;
+        let client = models::Client {
+            id: rusty_ulid::generate_ulid_string(),
+            name: self.name,
+            secret_hash: secret.as_deref().map(hash_secret).transpose()?,
+            public_key: self.public_key,
+            audiences: self.audiences.join(" "),
+            scopes: self.scopes.join(" "),
+            token_lifetime: self.token_lifetime.unwrap_or(15 * 60),
+            created_at: Utc::now().to_rfc3339(),
+            subject_type,
+            sector_identifier: self.sector_identifier,
+            redirect_uris: self.redirect_uris.join(" "),
+            client_type,
+            first_party: self.first_party,
+            id_token_lifetime: self.id_token_lifetime.unwrap_or(10 * 60),
+            require_mfa: self.require_mfa,
+            delegation: self.delegation,
+        };
+        Ok((client, secret))
+    }
+}
+
+async fn insert(conn: &MainDatabase, client: models::Client) -> Result {
+    conn.run(move |c| {
+        diesel::insert_into(schema::clients::table)
+            .values(&client)
+            .execute(c)
+    })
+    .await?;
+    Ok(())
+}
+
+#[derive(Serialize, Debug)]
+#[serde(crate = "rocket::serde")]
+pub struct RegisteredClient {
+    client_id: String,
+    /// Only ever shown here, we just keep a hash.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    client_secret: Option<String>,
+}
+
+/// Register a client.
+#[post("/clients", data = "<new>")]
+#[instrument(skip(conn, fetcher), err)]
+pub async fn register(
+    conn: MainDatabase,
+    fetcher: &State<Fetcher>,
+    tok: paseto::Token,
+    new: Json<NewClient>,
+) -> Result<Json<RegisteredClient>> {
+    require_admin(&tok)?;
+    let (client, secret) = new.into_inner().into_client()?;
+    check_sector(fetcher, &client).await?;
+    let client_id = client.id.clone();
+    insert(&conn, client).await?;
+    Ok(Json(RegisteredClient {
+        client_id,
+        client_secret: secret,
+    }))
+}
+
+/// A registered client, as admins get to see it.
+#[derive(Serialize, Debug)]
+#[serde(crate = "rocket::serde")]
+pub struct ClientInfo {
+    client_id: String,
+    name: String,
+    client_type: String,
+    redirect_uris: Vec<String>,
+    audiences: Vec<String>,
+    scopes: Vec<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    public_key: Option<String>,
+    token_lifetime: i32,
+    id_token_lifetime: i32,
+    subject_type: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    sector_identifier: Option<String>,
+    first_party: bool,
+    require_mfa: bool,
+    delegation: bool,
+    created_at: String,
+}
+
+impl From<models::Client> for ClientInfo {
+    fn from(client: models::Client) -> Self {
+        let split = |s: &str| s.split_whitespace().map(String::from).collect();
+        Self {
+            redirect_uris: split(&client.redirect_uris),
+            audiences: split(&client.audiences),
+            scopes: split(&client.scopes),
+            client_id: client.id,
+            name: client.name,
+            client_type: client.client_type,
+            public_key: client.public_key,
+            token_lifetime: client.token_lifetime,
+            id_token_lifetime: client.id_token_lifetime,
+            subject_type: client.subject_type,
+            sector_identifier: client.sector_identifier,
+            first_party: client.first_party,
+            require_mfa: client.require_mfa,
+            delegation: client.delegation,
+            created_at: client.created_at,
+        }
+    }
+}
+
+#[get("/clients")]
+#[instrument(skip(conn), err)]
+pub async fn list(conn: MainDatabase, tok: paseto::Token) -> Result<Json<Vec<ClientInfo>>> {
+    require_admin(&tok)?;
+    let clients: Vec<models::Client> = conn
+        .run(|c| {
+            use schema::clients::dsl;
+            dsl::clients.order(dsl::created_at).load(c)
+        })
+        .await?;
+    Ok(Json(clients.into_iter().map(ClientInfo::from).collect()))
+}
+
+#[get("/clients/<id>")]
+#[instrument(skip(conn), err)]
+pub async fn show(conn: MainDatabase, tok: paseto::Token, id: String) -> Result<Json<ClientInfo>> {
+    require_admin(&tok)?;
+    let client = find(&conn, &id).await?.ok_or(Error::NotFound)?;
+    Ok(Json(client.into()))
+}
+
+/// Changes to a registered client; anything left out stays as it is. Credentials
+/// and the client type can't be changed, register a new client instead.
+#[derive(Deserialize, Debug)]
+#[serde(crate = "rocket::serde")]
+pub struct ClientChanges {
+    name: Option<String>,
+    redirect_uris: Option<Vec<String>>,
+    audiences: Option<Vec<String>>,
+    scopes: Option<Vec<String>>,
+    token_lifetime: Option<i32>,
+    id_token_lifetime: Option<i32>,
+    subject_type: Option<String>,
+    sector_identifier: Option<String>,
+    first_party: Option<bool>,
+    require_mfa: Option<bool>,
+    delegation: Option<bool>,
+}
+
+#[put("/clients/<id>", data = "<changes>")]
+#[instrument(skip(conn, fetcher), err)]
+pub async fn update(
+    conn: MainDatabase,
+    fetcher: &State<Fetcher>,
+    tok: paseto::Token,
+    id: String,
+    changes: Json<ClientChanges>,
+) -> Result<Json<ClientInfo>> {
+    require_admin(&tok)?;
+    let changes = changes.into_inner();
+    if let Some(subject_type) = &changes.subject_type {
+        check_subject_type(subject_type)?;
+    }
+    if let Some(uris) = &changes.redirect_uris {
+        check_redirect_uris(uris)?;
+    }
+    let mut changed = find(&conn, &id).await?.ok_or(Error::NotFound)?;
+    if let Some(subject_type) = &changes.subject_type {
+        changed.subject_type = subject_type.clone();
+    }
+    if let Some(sector_identifier) = &changes.sector_identifier {
+        changed.sector_identifier = Some(sector_identifier.clone());
+    }
+    if let Some(uris) = &changes.redirect_uris {
+        changed.redirect_uris = uris.join(" ");
+    }
+    check_sector(fetcher, &changed).await?;
+    let update = models::UpdateClient {
+        name: changes.name,
+        redirect_uris: changes.redirect_uris.map(|uris| uris.join(" ")),
+        audiences: changes.audiences.map(|auds| auds.join(" ")),
+        scopes: changes.scopes.map(|scopes| scopes.join(" ")),
+        token_lifetime: changes.token_lifetime,
+        id_token_lifetime: changes.id_token_lifetime,
+        subject_type: changes.subject_type,
+        sector_identifier: changes.sector_identifier,
+        first_party: changes.first_party,
+        require_mfa: changes.require_mfa,
+        delegation: changes.delegation,
+    };
+    let client_id = id.clone();
+    if update != models::UpdateClient::default() {
+        conn.run(move |c| {
+            use schema::clients::dsl;
+            diesel::update(dsl::clients.find(&client_id))
+                .set(&update)
+                .execute(c)
+        })
+        .await?;
+    }
+    let client = find(&conn, &id).await?.ok_or(Error::NotFound)?;
+    Ok(Json(client.into()))
+}
+
+/// Remove a client. Tokens it already has stay valid until they expire or are
+/// revoked.
+#[delete("/clients/<id>")]
+#[instrument(skip(conn), err)]
+pub async fn remove(conn: MainDatabase, tok: paseto::Token, id: String) -> Result {
+    require_admin(&tok)?;
+    let deleted = conn
+        .run(move |c| {
+            use schema::clients::dsl;
+            diesel::delete(dsl::clients.find(&id)).execute(c)
+        })
+        .await?;
+    if deleted == 0 {
+        return Err(Error::NotFound);
+    }
+    Ok(())
+}
+
+/// The `registration` section of Rocket.toml.
+#[derive(Debug, Default, Deserialize)]
+#[serde(crate = "rocket::serde")]
+pub struct Registration {
+    /// Dynamic registration is off unless this is set.
+    initial_access_token: Option<String>,
+}
+
+impl Registration {
+    pub fn enabled(&self) -> bool {
+        self.initial_access_token
+            .as_deref()
+            .map_or(false, |tok| !tok.is_empty())
+    }
+}
+
+/// The `unregistered_clients` section of Rocket.toml: what clients nobody registered,
+/// like IndieAuth ones that are just a URL, can be granted.
+#[derive(Debug, Deserialize)]
+#[serde(crate = "rocket::serde")]
+pub struct Unregistered {
+    #[serde(default = "default_unregistered_scopes")]
+    scopes: Vec<String>,
+}
+
+impl Default for Unregistered {
+    fn default() -> Self {
+        Self {
+            scopes: default_unregistered_scopes(),
+        }
+    }
+}
+
+fn default_unregistered_scopes() -> Vec<String> {
+    [
+        "profile", "email", "create", "update", "delete", "media", "draft",
+    ]
+    .map(String::from)
+    .to_vec()
+}
+
+impl Unregistered {
+    /// Whether a client nobody registered can have `scope`. Never `admin`, whatever
+    /// the configuration says.
+    pub fn allows(&self, scope: &str) -> bool {
+        scope != paseto::ADMIN_SCOPE && self.scopes.iter().any(|s| s == scope)
+    }
+}
+
+/// The scopes in `requested` that `client` can have: what it was registered with,
+/// or if nobody registered it, what [`Unregistered`] allows.
+pub fn permitted_scopes(
+    client: Option<&models::Client>,
+    unregistered: &Unregistered,
+    requested: &str,
+) -> Vec<String> {
+    requested
+        .split_whitespace()
+        .filter(|scope| match client {
+            Some(client) => client.scopes().contains(scope),
+            None => unregistered.allows(scope),
+        })
+        .map(String::from)
+        .collect()
+}
+
+pub fn fairing() -> AdHoc {
+    AdHoc::on_ignite("Client registration", |rocket| async {
+        let registration = rocket
+            .figment()
+            .extract_inner::<Registration>("registration")
+            .unwrap_or_default();
+        let unregistered = rocket
+            .figment()
+            .extract_inner::<Unregistered>("unregistered_clients")
+            .unwrap_or_default();
+        rocket
+            .manage(registration)
+            .manage(unregistered)
+            .manage(SeenAssertions::default())
+    })
+}
+
+/// The bearer token a dynamic registration request came with.
+#[derive(Debug)]
+pub struct InitialAccessToken(Option<String>);
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for InitialAccessToken {
+    type Error = Error;
+
+    async fn from_request(request: &'r Request<'_>) -> request::Outcome<Self, Self::Error> {
+        let tok = request
+            .headers()
+            .get_one("Authorization")
+            .and_then(|h| h.strip_prefix("Bearer "))
+            .map(String::from);
+        request::Outcome::Success(InitialAccessToken(tok))
+    }
+}
+
+/// Client metadata, per RFC 7591 section 2.
+#[derive(Deserialize, Debug)]
+#[serde(crate = "rocket::serde")]
+pub struct ClientMetadata {
+    #[serde(default)]
+    redirect_uris: Vec<String>,
+    client_name: Option<String>,
+    token_endpoint_auth_method: Option<String>,
+    scope: Option<String>,
+    jwks: Option<Value>,
+    subject_type: Option<String>,
+    sector_identifier_uri: Option<String>,
+}
+
+impl ClientMetadata {
+    /// The hex-encoded Ed25519 key of the only key in `jwks`, for `private_key_jwt`.
+    fn public_key(&self) -> Result<String> {
+        let keys = self
+            .jwks
+            .as_ref()
+            .and_then(|jwks| jwks.get("keys"))
+            .and_then(Value::as_array);
+        let x = match keys.map(Vec::as_slice) {
+            Some([key])
+                if key.get("kty").and_then(Value::as_str) == Some("OKP")
+                    && key.get("crv").and_then(Value::as_str) == Some("Ed25519") =>
+            {
+                key.get("x").and_then(Value::as_str)
+            }
+            _ => None,
+        };
+        x.and_then(|x| BASE64_URL_SAFE_NO_PAD.decode(x).ok())
+            .map(hex::encode)
+            .ok_or(Error::Grant("invalid_client_metadata"))
+    }
+}
+
+#[derive(Serialize, Debug)]
+#[serde(crate = "rocket::serde")]
+pub struct ClientRegistration {
+    client_id: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    client_secret: Option<String>,
+    client_id_issued_at: i64,
+    /// Required alongside a secret; ours don't expire.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    client_secret_expires_at: Option<i64>,
+    client_name: String,
+    redirect_uris: Vec<String>,
+    token_endpoint_auth_method: String,
+    scope: String,
+    subject_type: String,
+}
+
+/// Dynamic client registration, per RFC 7591, for whoever holds the initial access
+/// token. Clients registered this way are never first-party and can't get the
+/// admin scope.
+#[post("/register", data = "<metadata>")]
+#[instrument(skip(conn, fetcher, registration, initial), err)]
+pub async fn register_dynamic(
+    conn: MainDatabase,
+    fetcher: &State<Fetcher>,
+    registration: &State<Registration>,
+    initial: InitialAccessToken,
+    metadata: Json<ClientMetadata>,
+) -> Result<(Status, Json<ClientRegistration>)> {
+    let expected = match &registration.initial_access_token {
+        Some(tok) if registration.enabled() => tok,
+        _ => return Err(Error::NotFound),
+    };
+    let presented = initial.0.unwrap_or_default();
+    if constant_time::verify_slices_are_equal(presented.as_bytes(), expected.as_bytes()).is_err() {
+        return Err(Error::InvalidClient);
+    }
+    let metadata = metadata.into_inner();
+    let method = metadata
+        .token_endpoint_auth_method
+        .clone()
+        .unwrap_or_else(|| "client_secret_basic".to_string());
+    let (client_type, public_key) = match method.as_str() {
+        "none" => (PUBLIC, None),
+        "client_secret_basic" | "client_secret_post" => (CONFIDENTIAL, None),
+        "private_key_jwt" => (CONFIDENTIAL, Some(metadata.public_key()?)),
+        _ => return Err(Error::Grant("invalid_client_metadata")),
+    };
+    let scopes = metadata
+        .scope
+        .as_deref()
+        .unwrap_or_default()
+        .split_whitespace()
+        .filter(|s| *s != paseto::ADMIN_SCOPE)
+        .map(String::from)
+        .collect();
+    let new = NewClient {
+        name: metadata
+            .client_name
+            .unwrap_or_else(|| "unnamed client".to_string()),
+        redirect_uris: metadata.redirect_uris,
+        scopes,
+        client_type: Some(client_type.to_string()),
+        public_key,
+        subject_type: metadata.subject_type,
+        sector_identifier: metadata.sector_identifier_uri,
+        ..Default::default()
+    };
+    let (client, secret) = new.into_client()?;
+    check_sector(fetcher, &client).await?;
+    let issued_at = Utc::now().timestamp();
+    let resp = ClientRegistration {
+        client_id: client.id.clone(),
+        client_secret_expires_at: secret.as_ref().map(|_| 0),
+        client_secret: secret,
+        client_id_issued_at: issued_at,
+        client_name: client.name.clone(),
+        redirect_uris: client
+            .redirect_uris()
+            .iter()
+            .map(|s| s.to_string())
+            .collect(),
+        token_endpoint_auth_method: method,
+        scope: client.scopes.clone(),
+        subject_type: client.subject_type.clone(),
+    };
+    insert(&conn, client).await?;
+    Ok((Status::Created, Json(resp)))
+}
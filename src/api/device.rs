@@ -0,0 +1,341 @@
+use askama::Template;
+use chrono::{DateTime, Duration, Utc};
+use diesel::prelude::*;
+use rand::Rng;
+use rocket::{
+    form::Form,
+    get, post,
+    response::Redirect,
+    serde::{json::Json, Serialize},
+    FromForm, State,
+};
+use tracing::instrument;
+
+use crate::{
+    models, paseto, schema,
+    session::{Session, SessionKey},
+    MainDatabase, ResolvedBaseUrl,
+};
+
+use super::{
+    clients::{self, random_string},
+    Error, Result,
+};
+
+pub const GRANT_TYPE: &str = "urn:ietf:params:oauth:grant-type:device_code";
+
+/// How long a device has to be approved, in seconds.
+const DEVICE_CODE_LIFETIME: i64 = 10 * 60;
+const POLL_INTERVAL: i32 = 5;
+
+// no vowels, so codes can't spell anything
+const USER_CODE_CHARS: &[u8] = b"BCDFGHJKLMNPQRSTVWXZ";
+
+fn user_code() -> String {
+    let mut rng = rand::thread_rng();
+    let code: String = (0..8)
+        .map(|_| USER_CODE_CHARS[rng.gen_range(0..USER_CODE_CHARS.len())] as char)
+        .collect();
+    format!("{}-{}", &code[..4], &code[4..])
+}
+
+/// Be forgiving about case and separators in what people type in.
+fn normalize(user_code: &str) -> String {
+    let code: String = user_code
+        .chars()
+        .filter(char::is_ascii_alphabetic)
+        .map(|c| c.to_ascii_uppercase())
+        .collect();
+    if code.len() == 8 {
+        format!("{}-{}", &code[..4], &code[4..])
+    } else {
+        code
+    }
+}
+
+fn expired(expires_at: &str, now: DateTime<Utc>) -> bool {
+    DateTime::parse_from_rfc3339(expires_at).map_or(true, |exp| exp < now)
+}
+
+#[derive(FromForm, Debug)]
+pub struct DeviceAuthorizationRequest {
+    client_id: Option<String>,
+    client_secret: Option<String>,
+    client_assertion_type: Option<String>,
+    client_assertion: Option<String>,
+    scope: Option<String>,
+}
+
+#[derive(Serialize, Debug)]
+#[serde(crate = "rocket::serde")]
+pub struct DeviceAuthorization {
+    device_code: String,
+    user_code: String,
+    verification_uri: String,
+    verification_uri_complete: String,
+    expires_in: i64,
+    interval: i32,
+}
+
+/// Start a device authorization, per RFC 8628.
+///
+/// Only registered clients can, as there's no redirect URI to tell who an
+/// unregistered one is. Confidential ones authenticate, and all of them are held to
+/// their scopes, like with the code flow.
+#[post("/device_authorization", data = "<req>")]
+#[instrument(skip(conn, base, auth, req), err)]
+pub async fn authorize(
+    conn: MainDatabase,
+    base: ResolvedBaseUrl,
+    auth: clients::ClientAuth,
+    req: Form<DeviceAuthorizationRequest>,
+) -> Result<Json<DeviceAuthorization>> {
+    let req = req.into_inner();
+    let creds = clients::Credentials {
+        client_id: req.client_id,
+        client_secret: req.client_secret,
+        client_assertion_type: req.client_assertion_type,
+        client_assertion: req.client_assertion,
+    };
+    let client_id = clients::identify(&conn, &auth, creds).await?;
+    let client = clients::find(&conn, &client_id)
+        .await?
+        .ok_or(Error::InvalidClient)?;
+    let allowed = client.scopes();
+    let mut requested = req.scope.as_deref().unwrap_or_default().split_whitespace();
+    if requested.any(|s| !allowed.contains(&s)) {
+        return Err(Error::Grant("invalid_scope"));
+    }
+    let row = models::DeviceCode {
+        device_code: random_string(32)?,
+        user_code: user_code(),
+        client_id,
+        scope: req.scope,
+        status: "pending".into(),
+        sub: None,
+        expires_at: (Utc::now() + Duration::seconds(DEVICE_CODE_LIFETIME)).to_rfc3339(),
+        interval: POLL_INTERVAL,
+        last_polled_at: None,
+    };
+    let verification_uri = format!("{}/device", base.0);
+    let resp = DeviceAuthorization {
+        device_code: row.device_code.clone(),
+        verification_uri_complete: format!("{verification_uri}?user_code={}", row.user_code),
+        user_code: row.user_code.clone(),
+        verification_uri,
+        expires_in: DEVICE_CODE_LIFETIME,
+        interval: POLL_INTERVAL,
+    };
+    conn.run(move |c| {
+        diesel::insert_into(schema::device_codes::table)
+            .values(&row)
+            .execute(c)
+    })
+    .await?;
+    Ok(Json(resp))
+}
+
+/// A device waiting for approval, as it's shown before approving.
+pub struct Pending {
+    client_id: String,
+    client_name: String,
+    scopes: Vec<String>,
+    /// Whether `admin` is among the scopes.
+    admin: bool,
+    /// Ties the decision to the session it's shown to.
+    csrf: String,
+}
+
+#[derive(Template)]
+#[template(path = "device.html")]
+pub struct Verify {
+    user_code: String,
+    /// The device the code belongs to, once one was entered.
+    pending: Option<Pending>,
+    message: Option<String>,
+}
+
+/// Ask for the code, then show which client it's for and what it asks for.
+#[get("/device?<user_code>")]
+pub async fn verify_page(
+    conn: MainDatabase,
+    session: Option<Session>,
+    session_key: &State<SessionKey>,
+    user_code: Option<String>,
+) -> Result<std::result::Result<Verify, Redirect>> {
+    let Some(session) = session else {
+        return Ok(Err(Redirect::to("/login")));
+    };
+    let Some(user_code) = user_code.as_deref().map(normalize) else {
+        return Ok(Ok(Verify {
+            user_code: String::new(),
+            pending: None,
+            message: None,
+        }));
+    };
+    let code = user_code.clone();
+    let row = conn
+        .run(move |c| {
+            use schema::device_codes::dsl;
+            dsl::device_codes
+                .filter(dsl::user_code.eq(&code))
+                .filter(dsl::status.eq("pending"))
+                .filter(dsl::expires_at.gt(Utc::now().to_rfc3339()))
+                .first::<models::DeviceCode>(c)
+                .optional()
+        })
+        .await?;
+    let Some(row) = row else {
+        return Ok(Ok(Verify {
+            user_code: String::new(),
+            pending: None,
+            message: Some("That code is unknown or has expired. Start again on your device.".into()),
+        }));
+    };
+    let client = clients::find(&conn, &row.client_id)
+        .await?
+        .ok_or(Error::NotFound)?;
+    let scopes: Vec<String> = row
+        .scope
+        .as_deref()
+        .unwrap_or_default()
+        .split_whitespace()
+        .map(String::from)
+        .collect();
+    Ok(Ok(Verify {
+        user_code,
+        pending: Some(Pending {
+            client_id: client.id,
+            client_name: client.name,
+            admin: scopes.iter().any(|s| s == paseto::ADMIN_SCOPE),
+            scopes,
+            csrf: session.csrf_token(session_key)?,
+        }),
+        message: None,
+    }))
+}
+
+#[derive(FromForm, Debug)]
+pub struct Decision {
+    user_code: String,
+    approve: bool,
+    csrf: String,
+}
+
+/// Approve or deny a device. Like consent, this carries a token tied to the session,
+/// so other sites can't approve anything by sending someone here.
+#[post("/device", data = "<decision>")]
+#[instrument(skip(conn, session_key, decision), err)]
+pub async fn verify(
+    session: Session,
+    session_key: &State<SessionKey>,
+    conn: MainDatabase,
+    decision: Form<Decision>,
+) -> Result<Verify> {
+    session.require_owner()?;
+    session.check_csrf(session_key, &decision.csrf)?;
+    let Decision {
+        user_code, approve, ..
+    } = decision.into_inner();
+    let user_code = normalize(&user_code);
+    if approve {
+        let code = user_code.clone();
+        let client_id = conn
+            .run(move |c| {
+                use schema::device_codes::dsl;
+                dsl::device_codes
+                    .filter(dsl::user_code.eq(&code))
+                    .filter(dsl::status.eq("pending"))
+                    .select(dsl::client_id)
+                    .first::<String>(c)
+                    .optional()
+            })
+            .await?;
+        if let Some(client_id) = client_id {
+            clients::check_mfa(&conn, &client_id, &session).await?;
+        }
+    }
+    let updated = conn
+        .run(move |c| {
+            use schema::device_codes::dsl;
+            diesel::update(
+                dsl::device_codes
+                    .filter(dsl::user_code.eq(&user_code))
+                    .filter(dsl::status.eq("pending"))
+                    .filter(dsl::expires_at.gt(Utc::now().to_rfc3339())),
+            )
+            .set(&models::UpdateDeviceCodeStatus {
+                status: if approve { "approved" } else { "denied" }.into(),
+                sub: Some(session.sub),
+            })
+            .execute(c)
+        })
+        .await?;
+    let message = match (updated, approve) {
+        (0, _) => "That code is unknown or has expired. Start again on your device.",
+        (_, true) => "Approved. You can go back to your device now.",
+        (_, false) => "Denied. Your device won't be signed in.",
+    };
+    Ok(Verify {
+        user_code: String::new(),
+        pending: None,
+        message: Some(message.into()),
+    })
+}
+
+/// Handle a token endpoint poll, returning the approved authorization once it's ready.
+pub async fn poll(
+    conn: &MainDatabase,
+    device_code: String,
+    client_id: String,
+) -> Result<models::DeviceCode> {
+    conn.run(move |c| {
+        use schema::device_codes::dsl;
+        c.transaction(|c| {
+            let row: models::DeviceCode = dsl::device_codes
+                .find(&device_code)
+                .get_result(c)
+                .optional()?
+                .ok_or(Error::Grant("invalid_grant"))?;
+            if row.client_id != client_id {
+                return Err(Error::Grant("invalid_grant"));
+            }
+            let now = Utc::now();
+            if expired(&row.expires_at, now) {
+                diesel::delete(dsl::device_codes.find(&device_code)).execute(c)?;
+                return Err(Error::Grant("expired_token"));
+            }
+            match row.status.as_str() {
+                "approved" => {
+                    diesel::delete(dsl::device_codes.find(&device_code)).execute(c)?;
+                    Ok(row)
+                }
+                "denied" => {
+                    diesel::delete(dsl::device_codes.find(&device_code)).execute(c)?;
+                    Err(Error::Grant("access_denied"))
+                }
+                _ => {
+                    let too_fast = row
+                        .last_polled_at
+                        .as_deref()
+                        .and_then(|last| DateTime::parse_from_rfc3339(last).ok())
+                        .map_or(false, |last| {
+                            now - last.with_timezone(&Utc) < Duration::seconds(row.interval.into())
+                        });
+                    diesel::update(dsl::device_codes.find(&device_code))
+                        .set(&models::UpdateDeviceCodePolled {
+                            interval: row.interval + if too_fast { POLL_INTERVAL } else { 0 },
+                            last_polled_at: Some(now.to_rfc3339()),
+                        })
+                        .execute(c)?;
+                    Err(Error::Grant(if too_fast {
+                        "slow_down"
+                    } else {
+                        "authorization_pending"
+                    }))
+                }
+            }
+        })
+    })
+    .await
+}
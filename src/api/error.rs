@@ -1,4 +1,4 @@
-use rocket::{http::Status, response::Responder};
+use rocket::{http::Status, response::Responder, serde::json::Json};
 use thiserror::Error;
 
 #[derive(Debug, Error)]
@@ -9,10 +9,18 @@ pub enum Error {
     NotFound,
     #[error("paseto creation error: {0}")]
     PasetoCreationError(String),
+    #[error("invalid signing key: {0}")]
+    InvalidSigningKey(String),
     #[error("paseto validation error: {0}")]
     PasetoValidationError(String),
+    #[error("invalid DPoP proof: {0}")]
+    InvalidDpopProof(String),
     #[error("no paseto in request")]
     NoPasetoInRequest,
+    #[error("token has been revoked")]
+    TokenRevoked,
+    #[error("forbidden: {0}")]
+    Forbidden(String),
     #[error("wrong indieauth response type: {0}")]
     WrongIndieAuthResponseType(String),
     #[error("Invalid code verifier: {0}")]
@@ -29,14 +37,35 @@ pub enum Error {
     ExchangeError(u16),
     #[error("OAuth2 error: {0}")]
     OAuth2(String),
+    #[error("unsupported grant type: {0}")]
+    UnsupportedGrantType(String),
+    /// An error code from RFC 6749 section 5.2 or its extensions, sent back as JSON.
+    #[error("token request failed: {0}")]
+    Grant(&'static str),
+    #[error("client authentication failed")]
+    InvalidClient,
+    #[error("invalid JWS: {0}")]
+    InvalidJws(String),
 }
 
 pub type Result<T = ()> = std::result::Result<T, Error>;
 
 impl<'r, 'o: 'r> Responder<'r, 'o> for Error {
-    fn respond_to(self, _: &'r rocket::Request<'_>) -> rocket::response::Result<'o> {
+    fn respond_to(self, req: &'r rocket::Request<'_>) -> rocket::response::Result<'o> {
         match self {
+            Error::Grant(code) => (
+                Status::BadRequest,
+                Json(serde_json::json!({ "error": code })),
+            )
+                .respond_to(req),
             Error::NotFound => Err(Status::NotFound),
+            Error::Forbidden(_) => Err(Status::Forbidden),
+            Error::UnsupportedGrantType(_) | Error::InvalidJws(_) => Err(Status::BadRequest),
+            Error::TokenRevoked
+            | Error::NoPasetoInRequest
+            | Error::PasetoValidationError(_)
+            | Error::InvalidDpopProof(_)
+            | Error::InvalidClient => Err(Status::Unauthorized),
             Error::WrongIndieAuthResponseType(_) => Err(Status::BadRequest),
             Error::WrongIndieAuthCodeChallengeMethod(_) | Error::InvalidCodeVerifier(_) => {
                 Err(Status::BadRequest)
@@ -1,10 +1,19 @@
-use super::{Error, Result};
-use crate::{models, oauth::pkce, paseto::Token, schema, MainDatabase};
+use super::{clients, par, redirect, token, Error, Result};
+use crate::{
+    fetch::Fetcher,
+    models,
+    oauth::pkce,
+    paseto, schema,
+    session::{Session, SessionKey},
+    MainDatabase,
+};
 use askama::Template;
 use diesel::prelude::*;
 use rocket::{
+    form::Form,
     response::Redirect,
-    serde::{json::Json, Serialize},
+    serde::{json::Json, Deserialize, Serialize},
+    State,
 };
 
 #[derive(Serialize, Debug, Clone)]
@@ -17,62 +26,182 @@ pub struct Me {
     pub scope: Option<String>,
 }
 
-#[allow(clippy::too_many_arguments)] // :/
-#[rocket::get("/auth?<me>&<client_id>&<redirect_uri>&<state>&<response_type>&<code_challenge>&<code_challenge_method>")]
-#[tracing::instrument(skip(db), err)]
+/// The parameters of an authorization request, other than `client_id`.
+#[derive(rocket::FromForm, Serialize, Deserialize, Debug)]
+#[serde(crate = "rocket::serde")]
+pub struct AuthorizationRequest {
+    /// Optional for IndieAuth, and OpenID Connect clients don't know about it.
+    pub me: Option<String>,
+    pub redirect_uri: String,
+    pub state: String,
+    pub response_type: String,
+    pub code_challenge: String,
+    pub code_challenge_method: String,
+    pub scope: Option<String>,
+    /// Echoed in the ID token, for OpenID Connect clients.
+    pub nonce: Option<String>,
+    /// Namespaced claims to add to the tokens, as a JSON object. They're shown on the
+    /// consent screen.
+    pub custom_claims: Option<String>,
+}
+
+#[derive(rocket::Responder)]
+pub enum Authorization {
+    Consent(Authz),
+    Approved(Redirect),
+}
+
+/// Start an authorization, either straight from the query string or from a request
+/// pushed to [`par::push`] beforehand.
+///
+/// Registered clients are held to their redirect URIs and scopes. Anyone else only
+/// gets the scopes in [`clients::Unregistered`], and never `admin`. Trusted
+/// first-party clients skip the consent screen when the user is already logged in.
+#[rocket::get("/auth?<client_id>&<request_uri>&<params..>")]
+#[tracing::instrument(skip(db, fetcher, par, unregistered, session_key, session), err)]
+#[allow(clippy::too_many_arguments)]
 pub async fn auth(
     db: MainDatabase,
-    me: String,
+    fetcher: &State<Fetcher>,
+    par: &State<par::Config>,
+    unregistered: &State<clients::Unregistered>,
+    session_key: &State<SessionKey>,
+    session: Option<Session>,
     client_id: String,
-    redirect_uri: String,
-    state: String,
-    response_type: String,
-    code_challenge: String,
-    code_challenge_method: String,
-) -> Result<Authz> {
-    let _ = db;
-    match response_type.as_str() {
+    request_uri: Option<String>,
+    params: Option<AuthorizationRequest>,
+) -> Result<Authorization> {
+    let client = clients::find(&db, &client_id).await?;
+    let req = match request_uri {
+        Some(request_uri) => par::take(&db, request_uri, &client_id).await?,
+        None if par.required && client.is_some() => return Err(Error::Grant("invalid_request")),
+        None => params.ok_or(Error::Grant("invalid_request"))?,
+    };
+    let kind = match &client {
+        Some(client) => {
+            if !client
+                .redirect_uris()
+                .iter()
+                .any(|registered| redirect::matches(registered, &req.redirect_uri))
+            {
+                return Err(Error::Grant("invalid_request"));
+            }
+            redirect::check(&req.redirect_uri)?
+        }
+        None => redirect::check_indieauth(fetcher, &client_id, &req.redirect_uri).await?,
+    };
+    let requested = req.scope.as_deref().unwrap_or_default();
+    let scopes = clients::permitted_scopes(client.as_ref(), unregistered, requested);
+    // registered clients know what they may ask for; anyone else just gets less
+    if client.is_some() && scopes.len() != requested.split_whitespace().count() {
+        return Err(Error::Grant("invalid_scope"));
+    }
+    let custom_claims = token::custom_claims(req.custom_claims.as_deref())?;
+    match req.response_type.as_str() {
         "code" | "id" => {}
-        _ => return Err(Error::WrongIndieAuthResponseType(response_type)),
+        _ => return Err(Error::WrongIndieAuthResponseType(req.response_type)),
     }
+    let me = req.me.unwrap_or_else(|| "https://5ht2.me".to_string());
     if me.as_str() != "https://5ht2.me" {
         return Err(Error::NotFound);
     }
-    if code_challenge_method.as_str() != "S256" {
+    if req.code_challenge_method.as_str() != "S256" {
         return Err(Error::WrongIndieAuthCodeChallengeMethod(
-            code_challenge_method,
+            req.code_challenge_method,
         ));
     }
+    let scope = Some(scopes.join(" ")).filter(|s| !s.is_empty());
     let _cid = client_id.clone();
     let code = rusty_ulid::generate_ulid_string();
     let _c = code.clone();
+    let _me = me.clone();
     db.run(move |c| {
         use schema::indieauth_codes::dsl::indieauth_codes;
         diesel::insert_into(indieauth_codes)
             .values(&models::IndieauthCode {
                 code,
                 client_id,
-                redirect_uri,
-                state,
-                response_type,
-                code_challenge,
+                redirect_uri: req.redirect_uri,
+                state: req.state,
+                response_type: req.response_type,
+                code_challenge: req.code_challenge,
                 authorized: false,
+                me: Some(_me),
+                scope,
+                sub: None,
+                nonce: req.nonce,
+                auth_time: None,
+                amr: None,
+                custom_claims: req.custom_claims,
             })
             .execute(c)
             .map_err(Error::Database)
     })
     .await?;
-    Ok(Authz {
-        client_id: _cid,
-        code: _c,
-        me,
-    })
+    match (client, session) {
+        (Some(client), Some(session)) if client.first_party && session.owner => {
+            Ok(Authorization::Approved(approve(&db, session, _c).await?))
+        }
+        (client, session) => Ok(Authorization::Consent(Authz {
+            client_name: client.map_or_else(|| _cid.clone(), |client| client.name),
+            client_id: _cid,
+            code: _c,
+            me,
+            native: kind.is_native(),
+            admin: scopes.iter().any(|s| s == paseto::ADMIN_SCOPE),
+            scopes,
+            custom_claims: custom_claims
+                .into_iter()
+                .map(|(name, value)| (name, value.to_string()))
+                .collect(),
+            csrf: session
+                .as_ref()
+                .filter(|session| session.owner)
+                .map(|session| session.csrf_token(session_key))
+                .transpose()?,
+            visitor: session
+                .filter(|session| !session.owner)
+                .map(|session| session.sub),
+        })),
+    }
 }
 
-#[rocket::get("/auth/authorized?<code>")]
-#[tracing::instrument(skip(db), err)]
-pub async fn authorized(token: Token, db: MainDatabase, code: String) -> Result<Redirect> {
+#[derive(rocket::FromForm, Debug)]
+pub struct Approval {
+    code: String,
+    csrf: String,
+}
+
+/// Approve a code from the consent page. This is a form post carrying a token tied to
+/// the session, so other sites can't approve anything by sending someone here.
+#[rocket::post("/auth/authorized", data = "<approval>")]
+#[tracing::instrument(skip(db, session_key, approval), err)]
+pub async fn authorized(
+    session: Session,
+    session_key: &State<SessionKey>,
+    db: MainDatabase,
+    approval: Form<Approval>,
+) -> Result<Redirect> {
+    session.check_csrf(session_key, &approval.csrf)?;
+    approve(&db, session, approval.into_inner().code).await
+}
+
+/// Mark a code as authorized by the user behind `session`, and send them back to
+/// the client with it.
+async fn approve(db: &MainDatabase, session: Session, code: String) -> Result<Redirect> {
+    session.require_owner()?;
     let _c = code.clone();
+    let client_id = db
+        .run(move |c| {
+            use schema::indieauth_codes::dsl;
+            dsl::indieauth_codes
+                .find(&code)
+                .select(dsl::client_id)
+                .get_result::<String>(c)
+        })
+        .await?;
+    clients::check_mfa(db, &client_id, &session).await?;
+    let code = _c.clone();
     let iac = db
         .run(move |c| {
             use schema::indieauth_codes::dsl::indieauth_codes;
@@ -85,7 +214,12 @@ pub async fn authorized(token: Token, db: MainDatabase, code: String) -> Result<
                 Err(e) => return Err(e),
             };
             match diesel::update(indieauth_codes.find(&iac.code))
-                .set(&models::UpdateIndieauthCodeAuthorized { authorized: true })
+                .set(&models::UpdateIndieauthCodeAuthorized {
+                    authorized: true,
+                    sub: Some(session.sub),
+                    auth_time: Some(session.auth_time),
+                    amr: Some(session.amr.join(" ")),
+                })
                 .execute(c)
                 .map_err(Error::Database)
             {
@@ -107,30 +241,37 @@ pub async fn authorized(token: Token, db: MainDatabase, code: String) -> Result<
 #[template(path = "authz.html")]
 pub struct Authz {
     client_id: String,
+    client_name: String,
+    /// Whether the redirect goes to a native app, which other apps could pose as.
+    native: bool,
     me: String,
     code: String,
+    scopes: Vec<String>,
+    /// Whether `admin` is among the scopes.
+    admin: bool,
+    /// Names and JSON values.
+    custom_claims: Vec<(String, String)>,
+    /// Missing unless an owner is logged in.
+    csrf: Option<String>,
+    /// Who's logged in, if it's someone other than an owner, like a RelMeAuth login.
+    visitor: Option<String>,
 }
 
-#[rocket::post("/auth?<code>&<redirect_uri>&<client_id>&<code_verifier>", rank = 2)]
-#[tracing::instrument(skip(db, code), err)]
-pub async fn send_code(
-    db: MainDatabase,
+/// Consume an authorized code, checking it was issued to this client and redirect URI.
+pub async fn redeem(
+    db: &MainDatabase,
+    code: String,
     client_id: String,
     redirect_uri: String,
-    code: String,
     code_verifier: String,
-) -> Result<Json<Me>> {
+) -> Result<models::IndieauthCode> {
     db.run(move |c| {
         use schema::indieauth_codes::dsl;
-        let _iac: Result<models::IndieauthCode> = dsl::indieauth_codes
+        let iac: models::IndieauthCode = dsl::indieauth_codes
             .find(&code)
             .get_result(c)
-            .map_err(Error::Database);
-        let iac = match _iac {
-            Err(e) => return Err(e),
-            Ok(_c) => _c,
-        };
-        if !iac.authorized {
+            .map_err(Error::Database)?;
+        if !iac.authorized || iac.client_id != client_id || iac.redirect_uri != redirect_uri {
             return Err(Error::NotFound);
         }
         if !pkce::verify(&code_verifier, &iac.code_challenge) {
@@ -138,12 +279,24 @@ pub async fn send_code(
         }
         diesel::delete(dsl::indieauth_codes.filter(dsl::code.eq(code)))
             .execute(c)
-            .map_err(Error::Database)
-            .map(|_| ())
+            .map_err(Error::Database)?;
+        Ok(iac)
     })
-    .await?;
+    .await
+}
+
+#[rocket::post("/auth?<code>&<redirect_uri>&<client_id>&<code_verifier>", rank = 2)]
+#[tracing::instrument(skip(db, code), err)]
+pub async fn send_code(
+    db: MainDatabase,
+    client_id: String,
+    redirect_uri: String,
+    code: String,
+    code_verifier: String,
+) -> Result<Json<Me>> {
+    let iac = redeem(&db, code, client_id, redirect_uri, code_verifier).await?;
     Ok(Json(Me {
-        me: "https://5ht2.me".to_string(),
+        me: iac.me.unwrap_or_else(|| "https://5ht2.me".to_string()),
         access_token: None,
         scope: None,
     }))
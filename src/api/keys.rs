@@ -0,0 +1,29 @@
+use rocket::{post, State};
+use tracing::instrument;
+
+use crate::{paseto, MainDatabase};
+
+use super::{Error, Result};
+
+/// Generate a new signing key for the version tokens are signed as, and start signing
+/// with it.
+///
+/// The previous key is retired, but still accepted for verification, so tokens
+/// already handed out stay valid. Other instances pick the new key up when they next
+/// reread the stored keys.
+#[post("/keys/rotate")]
+#[instrument(skip(conn, ring), err)]
+pub async fn rotate(
+    conn: MainDatabase,
+    tok: paseto::Token,
+    ring: &State<paseto::KeyRing>,
+) -> Result<String> {
+    if !tok.is_admin() {
+        return Err(Error::Forbidden("only admins can rotate keys".into()));
+    }
+    let kp = paseto::Keypair::generate(ring.version())?;
+    let kid = kp.kid().to_string();
+    ring.promote(&conn, kp).await?;
+    tracing::info!("promoted signing key {kid}");
+    Ok(kid)
+}
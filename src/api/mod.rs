@@ -1,4 +1,10 @@
+pub mod clients;
+pub mod device;
 mod error;
 pub mod indieauth;
+pub mod keys;
+pub mod oidc;
+pub mod par;
+mod redirect;
 pub mod token;
 pub use error::{Error, Result};
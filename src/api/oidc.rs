@@ -0,0 +1,137 @@
+//! OpenID Connect on top of the authorization code flow.
+//!
+//! Clients asking for the `openid` scope get an ID token alongside their access
+//! token, signed as an EdDSA JWS with the active signing key. Relying parties find
+//! the key at `/.well-known/jwks.json`.
+
+use chrono::{DateTime, Duration, Utc};
+use diesel::prelude::*;
+use rocket::{
+    get,
+    serde::{json::Json, Serialize},
+};
+use serde_json::json;
+
+use crate::{jws, models, paseto, schema, session, MainDatabase};
+
+use super::{Error, Result};
+
+pub const OPENID_SCOPE: &str = "openid";
+pub const PROFILE_SCOPE: &str = "profile";
+pub const EMAIL_SCOPE: &str = "email";
+
+/// How long ID tokens are good for, unless the client was registered with another
+/// lifetime. Relying parties only look at them once.
+pub const ID_TOKEN_LIFETIME: i64 = 10 * 60;
+
+/// The `acr` for a login that used the methods in `amr`.
+fn acr(amr: &[&str]) -> &'static str {
+    if amr.contains(&session::AMR_MFA) {
+        "2"
+    } else {
+        "1"
+    }
+}
+
+/// Sign an ID token for a redeemed authorization code, about `sub` as the client
+/// gets to see them.
+pub fn id_token(
+    ring: &paseto::KeyRing,
+    issuer: &str,
+    sub: &str,
+    lifetime: Duration,
+    iac: &models::IndieauthCode,
+) -> Result<String> {
+    let now = Utc::now();
+    let amr: Vec<&str> = iac
+        .amr
+        .as_deref()
+        .unwrap_or_default()
+        .split_whitespace()
+        .collect();
+    let auth_time = iac
+        .auth_time
+        .as_deref()
+        .and_then(|t| DateTime::parse_from_rfc3339(t).ok())
+        .map_or(now.timestamp(), |t| t.timestamp());
+    let mut claims = json!({
+        "iss": issuer,
+        "sub": sub,
+        "aud": iac.client_id,
+        "iat": now.timestamp(),
+        "exp": (now + lifetime).timestamp(),
+        "auth_time": auth_time,
+        "acr": acr(&amr),
+        "amr": amr,
+    });
+    if let Some(nonce) = &iac.nonce {
+        claims["nonce"] = nonce.as_str().into();
+    }
+    jws::sign_eddsa(&ring.active(), &claims)
+}
+
+#[derive(Serialize, Debug)]
+#[serde(crate = "rocket::serde")]
+pub struct UserInfo {
+    sub: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    name: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    preferred_username: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    website: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    email: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    email_verified: Option<bool>,
+}
+
+/// The user record behind `tok`. Its own subject may be the pairwise one its client
+/// sees, so the one it was issued for is looked up with it.
+async fn user_for(db: &MainDatabase, tok: &paseto::Token) -> Result<Option<models::User>> {
+    let (jti, sub) = (tok.jti.clone(), tok.sub.clone());
+    Ok(db
+        .run(move |c| {
+            let user_sub = schema::tokens::table
+                .find(&jti)
+                .select(schema::tokens::user_sub)
+                .first::<Option<String>>(c)
+                .optional()?
+                .flatten();
+            schema::users::table
+                .find(user_sub.unwrap_or(sub))
+                .get_result(c)
+                .optional()
+        })
+        .await?)
+}
+
+/// Claims about the user behind an access token with the `openid` scope, from what
+/// their last login said about them: `profile` claims with the `profile` scope, and
+/// `email` claims with the `email` scope.
+#[get("/userinfo")]
+pub async fn userinfo(db: MainDatabase, tok: paseto::Token) -> Result<Json<UserInfo>> {
+    if !tok.has_scope(OPENID_SCOPE) {
+        return Err(Error::Forbidden(format!("{OPENID_SCOPE} scope required")));
+    }
+    let user = user_for(&db, &tok).await?;
+    let profile = tok.has_scope(PROFILE_SCOPE);
+    let email = tok.has_scope(EMAIL_SCOPE);
+    let (name, website, address, verified) = match user {
+        Some(user) => (
+            Some(user.name),
+            user.website,
+            user.email,
+            user.email_verified,
+        ),
+        None => (None, None, None, false),
+    };
+    Ok(Json(UserInfo {
+        name: name.clone().filter(|_| profile),
+        preferred_username: name.filter(|_| profile),
+        website: website.or(tok.me).filter(|_| profile),
+        email_verified: address.as_ref().map(|_| verified).filter(|_| email),
+        email: address.filter(|_| email),
+        sub: tok.sub,
+    }))
+}
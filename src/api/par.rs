@@ -0,0 +1,163 @@
+//! Pushed authorization requests, per RFC 9126.
+//!
+//! Clients POST what they'd otherwise put in the `/auth` query string, and send the
+//! browser off with just their `client_id` and the `request_uri` they get back.
+
+use chrono::{DateTime, Duration, Utc};
+use diesel::prelude::*;
+use rocket::{
+    fairing::AdHoc,
+    form::Form,
+    http::Status,
+    post,
+    serde::{json::Json, Deserialize, Serialize},
+    FromForm,
+};
+use tracing::instrument;
+
+use crate::{models, schema, MainDatabase};
+
+use super::{
+    clients::{self, random_string},
+    indieauth::AuthorizationRequest,
+    Error, Result,
+};
+
+const REQUEST_URI_PREFIX: &str = "urn:ietf:params:oauth:request_uri:";
+
+/// How long a `request_uri` can be used for, in seconds.
+const REQUEST_LIFETIME: i64 = 60;
+
+/// The `par` section of Rocket.toml.
+#[derive(Debug, Default, Deserialize)]
+#[serde(crate = "rocket::serde")]
+pub struct Config {
+    /// Whether registered clients have to push their authorization requests.
+    #[serde(default)]
+    pub required: bool,
+}
+
+pub fn fairing() -> AdHoc {
+    AdHoc::on_ignite("Pushed authorization requests", |rocket| async {
+        let config = rocket
+            .figment()
+            .extract_inner::<Config>("par")
+            .unwrap_or_default();
+        rocket.manage(config)
+    })
+}
+
+/// Consume a pushed request, checking it was pushed by `client_id`. Someone else
+/// presenting the `request_uri` doesn't use it up.
+pub async fn take(
+    db: &MainDatabase,
+    request_uri: String,
+    client_id: &str,
+) -> Result<AuthorizationRequest> {
+    let client_id = client_id.to_string();
+    let pushed = db
+        .run(move |c| {
+            use schema::pushed_authorization_requests::dsl;
+            c.transaction(|c| {
+                let pushed: Option<models::PushedAuthorizationRequest> =
+                    dsl::pushed_authorization_requests
+                        .find(&request_uri)
+                        .filter(dsl::client_id.eq(&client_id))
+                        .get_result(c)
+                        .optional()?;
+                if pushed.is_some() {
+                    diesel::delete(dsl::pushed_authorization_requests.find(&request_uri))
+                        .execute(c)?;
+                }
+                Ok::<_, Error>(pushed)
+            })
+        })
+        .await?
+        .ok_or(Error::Grant("invalid_request_uri"))?;
+    let expired =
+        DateTime::parse_from_rfc3339(&pushed.expires_at).map_or(true, |exp| exp < Utc::now());
+    if expired {
+        return Err(Error::Grant("invalid_request_uri"));
+    }
+    Ok(serde_json::from_str(&pushed.request)?)
+}
+
+/// An authorization request as it's pushed, along with the client's credentials.
+#[derive(FromForm, Debug)]
+pub struct PushedRequest {
+    client_id: Option<String>,
+    client_secret: Option<String>,
+    client_assertion_type: Option<String>,
+    client_assertion: Option<String>,
+    me: Option<String>,
+    redirect_uri: String,
+    state: String,
+    response_type: String,
+    code_challenge: String,
+    code_challenge_method: String,
+    scope: Option<String>,
+    nonce: Option<String>,
+    custom_claims: Option<String>,
+}
+
+impl PushedRequest {
+    fn split(self) -> (clients::Credentials, AuthorizationRequest) {
+        let creds = clients::Credentials {
+            client_id: self.client_id,
+            client_secret: self.client_secret,
+            client_assertion_type: self.client_assertion_type,
+            client_assertion: self.client_assertion,
+        };
+        let req = AuthorizationRequest {
+            me: self.me,
+            redirect_uri: self.redirect_uri,
+            state: self.state,
+            response_type: self.response_type,
+            code_challenge: self.code_challenge,
+            code_challenge_method: self.code_challenge_method,
+            scope: self.scope,
+            nonce: self.nonce,
+            custom_claims: self.custom_claims,
+        };
+        (creds, req)
+    }
+}
+
+#[derive(Serialize, Debug)]
+#[serde(crate = "rocket::serde")]
+pub struct Pushed {
+    request_uri: String,
+    expires_in: i64,
+}
+
+/// Registered clients have to authenticate here; IndieAuth clients can't.
+#[post("/par", data = "<req>")]
+#[instrument(skip(conn, auth, req), err)]
+pub async fn push(
+    conn: MainDatabase,
+    auth: clients::ClientAuth,
+    req: Form<PushedRequest>,
+) -> Result<(Status, Json<Pushed>)> {
+    let (creds, request) = req.into_inner().split();
+    let client_id = clients::identify(&conn, &auth, creds).await?;
+    let pushed = models::PushedAuthorizationRequest {
+        request_uri: format!("{REQUEST_URI_PREFIX}{}", random_string(24)?),
+        client_id,
+        request: serde_json::to_string(&request)?,
+        expires_at: (Utc::now() + Duration::seconds(REQUEST_LIFETIME)).to_rfc3339(),
+    };
+    let request_uri = pushed.request_uri.clone();
+    conn.run(move |c| {
+        diesel::insert_into(schema::pushed_authorization_requests::table)
+            .values(&pushed)
+            .execute(c)
+    })
+    .await?;
+    Ok((
+        Status::Created,
+        Json(Pushed {
+            request_uri,
+            expires_in: REQUEST_LIFETIME,
+        }),
+    ))
+}
@@ -0,0 +1,119 @@
+//! Which redirect URIs a client may use, including those of native apps per
+//! RFC 8252.
+
+use rocket::serde::Deserialize;
+use url::{Host, Url};
+
+use crate::fetch::Fetcher;
+
+use super::{Error, Result};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Kind {
+    Web,
+    /// `http://127.0.0.1:{port}/...` or `http://[::1]:{port}/...`, section 7.3.
+    Loopback,
+    /// A private-use scheme like `com.example.app:/callback`, section 7.1.
+    PrivateUse,
+}
+
+impl Kind {
+    pub fn of(url: &Url) -> Self {
+        let loopback = match url.host() {
+            Some(Host::Ipv4(ip)) => ip.is_loopback(),
+            Some(Host::Ipv6(ip)) => ip.is_loopback(),
+            _ => false,
+        };
+        match url.scheme() {
+            "http" if loopback => Kind::Loopback,
+            "http" | "https" => Kind::Web,
+            _ => Kind::PrivateUse,
+        }
+    }
+
+    /// Native apps can't keep a secret, and another app on the same device could
+    /// be listening on their redirect.
+    pub fn is_native(self) -> bool {
+        self != Kind::Web
+    }
+}
+
+/// Check a redirect URI before it's registered.
+pub fn check(uri: &str) -> Result<Kind> {
+    let url = Url::parse(uri).map_err(|_| Error::Grant("invalid_redirect_uri"))?;
+    if url.fragment().is_some() {
+        return Err(Error::Grant("invalid_redirect_uri"));
+    }
+    let kind = Kind::of(&url);
+    // private-use schemes have to be reverse domain names, section 7.1
+    if kind == Kind::PrivateUse && !url.scheme().contains('.') {
+        return Err(Error::Grant("invalid_redirect_uri"));
+    }
+    Ok(kind)
+}
+
+/// Whether `requested` is the `registered` redirect URI. Loopback redirects match on
+/// any port, as native apps pick one when they start listening.
+pub fn matches(registered: &str, requested: &str) -> bool {
+    if registered == requested {
+        return true;
+    }
+    let (registered, requested) = match (Url::parse(registered), Url::parse(requested)) {
+        (Ok(registered), Ok(requested)) => (registered, requested),
+        _ => return false,
+    };
+    Kind::of(&registered) == Kind::Loopback
+        && Kind::of(&requested) == Kind::Loopback
+        && registered.host() == requested.host()
+        && registered.path() == requested.path()
+        && registered.query() == requested.query()
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(crate = "rocket::serde")]
+struct ClientMetadata {
+    #[serde(default)]
+    redirect_uris: Vec<String>,
+}
+
+/// The redirect URIs an IndieAuth client declares in the JSON metadata document
+/// served at its `client_id`, leaving out any that couldn't be registered either.
+/// Clients without one declare nothing.
+async fn declared(fetcher: &Fetcher, client_id: &Url) -> Vec<String> {
+    let metadata = fetcher.json::<ClientMetadata>(client_id).await;
+    let mut uris = metadata.map(|m| m.redirect_uris).unwrap_or_default();
+    uris.retain(|uri| check(uri).is_ok());
+    uris
+}
+
+/// Check the redirect URI of an IndieAuth client, identified by its URL.
+///
+/// On the client's own origin, anything goes. Anywhere else, including loopback and
+/// private-use redirects, it has to be declared in the client's metadata.
+pub async fn check_indieauth(
+    fetcher: &Fetcher,
+    client_id: &str,
+    redirect_uri: &str,
+) -> Result<Kind> {
+    let client = Url::parse(client_id).map_err(|_| Error::Grant("invalid_client"))?;
+    let redirect = Url::parse(redirect_uri).map_err(|_| Error::Grant("invalid_request"))?;
+    let kind = Kind::of(&redirect);
+    let same_origin = client.scheme() == redirect.scheme()
+        && client.host() == redirect.host()
+        && client.port_or_known_default() == redirect.port_or_known_default();
+    // a loopback client_id is someone testing locally; treat it like any loopback
+    let loopback_client = Kind::of(&client) == Kind::Loopback
+        && kind == Kind::Loopback
+        && client.host() == redirect.host();
+    if (same_origin || loopback_client) && kind != Kind::PrivateUse {
+        return Ok(kind);
+    }
+    if declared(fetcher, &client)
+        .await
+        .iter()
+        .any(|registered| matches(registered, redirect_uri))
+    {
+        return Ok(kind);
+    }
+    Err(Error::Grant("invalid_request"))
+}
@@ -1,38 +1,133 @@
-use ::paseto::PasetoBuilder;
-use chrono::Utc;
+use chrono::{DateTime, Duration, Utc};
 use diesel::prelude::*;
-use rocket::{get, post, serde::json::Json, State};
+use rocket::{
+    form::Form,
+    get, post,
+    serde::{json::Json, Serialize},
+    FromForm, State,
+};
 use rusty_ulid::generate_ulid_string;
+use serde_json::{Map, Value};
 use tracing::instrument;
 
-use crate::{models, paseto, schema, MainDatabase, APPLICATION_NAME};
+use crate::{
+    dpop, models, paseto, schema, subject::SubjectKey, MainDatabase, ResolvedBaseUrl,
+    APPLICATION_NAME,
+};
 
-use super::{Error, Result};
+use super::{clients, device, indieauth, oidc, Error, Result};
 
-#[get("/token/info")]
-pub async fn info(tok: paseto::Token) -> Json<paseto::Token> {
-    Json(tok)
+/// How long access tokens from the token endpoint last.
+const ACCESS_TOKEN_LIFETIME: i64 = 24 * 60 * 60;
+
+pub const TOKEN_EXCHANGE_GRANT_TYPE: &str = "urn:ietf:params:oauth:grant-type:token-exchange";
+const ACCESS_TOKEN_TYPE: &str = "urn:ietf:params:oauth:token-type:access_token";
+
+/// Everything needed to mint a token.
+#[derive(Debug, Clone, Default)]
+pub struct Grant {
+    pub sub: String,
+    pub aud: String,
+    pub scopes: Vec<String>,
+    pub exp: Option<DateTime<Utc>>,
+    /// The jti of the token this one was derived from, if any.
+    pub parent: Option<String>,
+    /// The subject behind `sub`, for user tokens, where `sub` may be the pairwise one
+    /// the client sees. Derived tokens inherit it.
+    pub user_sub: Option<String>,
+    /// Registered claims set by the server itself, e.g. `client_id`.
+    pub registered: Map<String, Value>,
+    /// Namespaced custom claims, see [`paseto::check_custom_claim`].
+    pub extra: Map<String, Value>,
 }
 
-#[post("/token/mint?<aud>&<sub>")]
-#[instrument(skip(kp, conn), err)]
-pub async fn mint(
-    conn: MainDatabase,
-    tok: paseto::Token,
-    kp: &State<paseto::Keypair>,
-    aud: String,
-    sub: String,
-) -> Result<String> {
-    let kp = kp.inner().ed25519_keypair();
+impl Grant {
+    /// Derive a grant from `parent`, enforcing the minting rules.
+    ///
+    /// Admins may mint anything. Everyone else may only mint tokens for their own
+    /// subject, with a subset of their own scopes, expiring no later than they do.
+    pub fn derive(
+        parent: &paseto::Token,
+        aud: String,
+        sub: Option<String>,
+        scopes: Option<Vec<String>>,
+        lifetime: Option<Duration>,
+        extra: Map<String, Value>,
+    ) -> Result<Self> {
+        for name in extra.keys() {
+            paseto::check_custom_claim(name)?;
+        }
+        let now = Utc::now();
+        let requested_exp = lifetime.map(|l| now + l);
+        let grant = Self {
+            sub: sub.unwrap_or_else(|| parent.sub.clone()),
+            aud,
+            scopes: scopes.unwrap_or_else(|| {
+                parent
+                    .scopes()
+                    .iter()
+                    .filter(|s| *s != paseto::ADMIN_SCOPE)
+                    .cloned()
+                    .collect()
+            }),
+            exp: requested_exp,
+            parent: Some(parent.jti.clone()),
+            extra,
+            ..Default::default()
+        };
+        if parent.is_admin() {
+            return Ok(grant);
+        }
+        if grant.sub != parent.sub {
+            return Err(Error::Forbidden(format!(
+                "can't mint tokens for {}",
+                grant.sub
+            )));
+        }
+        if let Some(scope) = grant.scopes.iter().find(|s| !parent.has_scope(s)) {
+            return Err(Error::Forbidden(format!("scope {scope} not held")));
+        }
+        let exp = match (parent.expires_at(), requested_exp) {
+            (Some(max), Some(exp)) if exp > max => {
+                return Err(Error::Forbidden(
+                    "lifetime exceeds that of the parent token".into(),
+                ))
+            }
+            (Some(max), None) => Some(max),
+            (_, exp) => exp,
+        };
+        Ok(Self { exp, ..grant })
+    }
+}
+
+/// Record a token in the database and sign it.
+pub async fn issue(conn: &MainDatabase, ring: &paseto::KeyRing, grant: Grant) -> Result<String> {
     let now = Utc::now();
+    let user_sub = match (grant.user_sub.clone(), grant.parent.clone()) {
+        (None, Some(parent)) => conn
+            .run(move |c| {
+                use schema::tokens::dsl;
+                dsl::tokens
+                    .find(&parent)
+                    .select(dsl::user_sub)
+                    .first::<Option<String>>(c)
+                    .optional()
+            })
+            .await?
+            .flatten(),
+        (user_sub, _) => user_sub,
+    };
     let tok = models::Token {
         id: generate_ulid_string(),
-        sub: sub.clone(),
-        aud: aud.clone(),
+        sub: grant.sub.clone(),
+        aud: grant.aud.clone(),
         iat: now.to_rfc3339(),
         iss: APPLICATION_NAME.into(),
-        exp: None,
-        valid: None,
+        exp: grant.exp.map(|exp| exp.timestamp()),
+        valid: Some(1),
+        scopes: Some(grant.scopes.join(" ")),
+        parent: grant.parent.clone(),
+        user_sub,
     };
     let clone = tok.clone();
     conn.run(move |c| {
@@ -42,16 +137,454 @@ pub async fn mint(
             .map_err(Error::Database)
     })
     .await?;
-    PasetoBuilder::new()
-        .set_ed25519_key(&kp)
-        .set_issued_at(Some(now))
-        .set_issuer(&format!("api call from {}", clone.sub))
-        .set_audience(&aud)
-        .set_jti(&clone.id)
-        .set_subject(&sub)
-        .build()
-        .map_err(|why| {
-            tracing::error!("can't make paseto: {why}");
-            Error::PasetoCreationError(format!("{why}"))
-        })
+    // registered claims go in last, so nothing can shadow them
+    let mut claims = grant.extra;
+    claims.extend(grant.registered);
+    claims.insert("iss".into(), format!("api call from {}", clone.sub).into());
+    claims.insert("sub".into(), grant.sub.into());
+    claims.insert("aud".into(), grant.aud.into());
+    claims.insert("jti".into(), clone.id.into());
+    claims.insert("iat".into(), clone.iat.into());
+    claims.insert("scopes".into(), grant.scopes.into());
+    if let Some(exp) = grant.exp {
+        claims.insert("exp".into(), exp.to_rfc3339().into());
+    }
+    if let Some(parent) = grant.parent {
+        claims.insert("parent".into(), parent.into());
+    }
+    ring.sign(&claims)
+}
+
+#[get("/token/info")]
+pub async fn info(tok: paseto::Token) -> Json<paseto::Token> {
+    Json(tok)
+}
+
+/// Mint a token derived from the caller's. Custom claims can be passed as a JSON
+/// object in the body.
+#[post("/token/mint?<aud>&<sub>&<scope>&<lifetime>", data = "<claims>")]
+#[instrument(skip(ring, conn), err)]
+pub async fn mint(
+    conn: MainDatabase,
+    tok: paseto::Token,
+    ring: &State<paseto::KeyRing>,
+    aud: String,
+    sub: Option<String>,
+    scope: Option<String>,
+    lifetime: Option<i64>,
+    claims: Option<Json<Map<String, Value>>>,
+) -> Result<String> {
+    let scopes = scope.map(|s| s.split_whitespace().map(String::from).collect());
+    let grant = Grant::derive(
+        &tok,
+        aud,
+        sub,
+        scopes,
+        lifetime.map(Duration::seconds),
+        claims.map(|c| c.into_inner()).unwrap_or_default(),
+    )?;
+    issue(&conn, ring.inner(), grant).await
+}
+
+#[derive(FromForm, Debug)]
+pub struct TokenRequest {
+    grant_type: String,
+    code: Option<String>,
+    client_id: Option<String>,
+    client_secret: Option<String>,
+    client_assertion_type: Option<String>,
+    client_assertion: Option<String>,
+    redirect_uri: Option<String>,
+    code_verifier: Option<String>,
+    scope: Option<String>,
+    audience: Option<String>,
+    device_code: Option<String>,
+    subject_token: Option<String>,
+    subject_token_type: Option<String>,
+    requested_token_type: Option<String>,
+    /// Namespaced claims to add to the token, as a JSON object. Only for grants where
+    /// the client speaks for itself; with a code, they go in the authorization request.
+    custom_claims: Option<String>,
+}
+
+impl TokenRequest {
+    fn credentials(&mut self) -> clients::Credentials {
+        clients::Credentials {
+            client_id: self.client_id.take(),
+            client_secret: self.client_secret.take(),
+            client_assertion_type: self.client_assertion_type.take(),
+            client_assertion: self.client_assertion.take(),
+        }
+    }
+}
+
+#[derive(Serialize, Debug)]
+#[serde(crate = "rocket::serde")]
+pub struct TokenResponse {
+    access_token: String,
+    token_type: &'static str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    expires_in: Option<i64>,
+    scope: String,
+    /// Only when the `openid` scope was granted.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    id_token: Option<String>,
+    /// Only for token exchange.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    issued_token_type: Option<&'static str>,
+    /// Only for IndieAuth clients.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    me: Option<String>,
+}
+
+fn missing(name: &str) -> Error {
+    Error::OAuth2(format!("missing {name}"))
+}
+
+/// Parse namespaced custom claims, as a JSON object, checking none of them is
+/// reserved.
+pub(crate) fn custom_claims(raw: Option<&str>) -> Result<Map<String, Value>> {
+    let claims: Map<String, Value> = match raw {
+        Some(raw) => serde_json::from_str(raw).map_err(|_| Error::Grant("invalid_request"))?,
+        None => Map::new(),
+    };
+    for name in claims.keys() {
+        paseto::check_custom_claim(name)?;
+    }
+    Ok(claims)
+}
+
+fn split_scopes(scope: Option<&str>) -> Vec<String> {
+    scope
+        .unwrap_or_default()
+        .split_whitespace()
+        .map(String::from)
+        .collect()
+}
+
+/// The subject a user's token for `client_id` is about, as the client gets to see
+/// them, and how long the token lasts. Registered clients set their own lifetime.
+async fn user_token_for(
+    conn: &MainDatabase,
+    subjects: &SubjectKey,
+    client_id: &str,
+    sub: String,
+) -> Result<(String, Duration)> {
+    Ok(match clients::find(conn, client_id).await? {
+        Some(client) => (
+            subjects.for_client(&client, &sub),
+            Duration::seconds(client.token_lifetime.into()),
+        ),
+        None => (sub, Duration::seconds(ACCESS_TOKEN_LIFETIME)),
+    })
+}
+
+/// Also returns the redeemed code, which an ID token is made from.
+///
+/// The scopes are checked again, in case the client was registered, changed or
+/// removed since the code was issued.
+async fn authorization_code(
+    conn: &MainDatabase,
+    subjects: &SubjectKey,
+    unregistered: &clients::Unregistered,
+    auth: &clients::ClientAuth,
+    mut req: TokenRequest,
+) -> Result<(Grant, models::IndieauthCode)> {
+    if req.custom_claims.is_some() {
+        return Err(Error::Grant("invalid_request"));
+    }
+    let code = req.code.take().ok_or_else(|| missing("code"))?;
+    let redirect_uri = req
+        .redirect_uri
+        .take()
+        .ok_or_else(|| missing("redirect_uri"))?;
+    let code_verifier = req
+        .code_verifier
+        .take()
+        .ok_or_else(|| missing("code_verifier"))?;
+    let client_id = clients::identify(conn, auth, req.credentials()).await?;
+    let iac = indieauth::redeem(conn, code, client_id, redirect_uri, code_verifier).await?;
+    let me = iac
+        .me
+        .clone()
+        .unwrap_or_else(|| "https://5ht2.me".to_string());
+    let mut registered = Map::new();
+    registered.insert("client_id".into(), iac.client_id.clone().into());
+    registered.insert("me".into(), me.into());
+    let user_sub = iac.sub.clone().ok_or(Error::NotFound)?;
+    let (sub, lifetime) = user_token_for(conn, subjects, &iac.client_id, user_sub.clone()).await?;
+    let client = clients::find(conn, &iac.client_id).await?;
+    let scopes = clients::permitted_scopes(
+        client.as_ref(),
+        unregistered,
+        iac.scope.as_deref().unwrap_or_default(),
+    );
+    let grant = Grant {
+        sub,
+        aud: iac.client_id.clone(),
+        scopes,
+        exp: Some(Utc::now() + lifetime),
+        registered,
+        extra: custom_claims(iac.custom_claims.as_deref())?,
+        user_sub: Some(user_sub),
+        ..Default::default()
+    };
+    Ok((grant, iac))
+}
+
+/// Machine-to-machine tokens, limited to what the client was registered with.
+async fn client_credentials(
+    conn: &MainDatabase,
+    auth: &clients::ClientAuth,
+    mut req: TokenRequest,
+) -> Result<Grant> {
+    let client = clients::authenticate(conn, auth, req.credentials()).await?;
+    let allowed = client.scopes();
+    let scopes = match req.scope.as_deref() {
+        Some(_) => split_scopes(req.scope.as_deref()),
+        None => allowed.iter().map(|s| s.to_string()).collect(),
+    };
+    if scopes.iter().any(|s| !allowed.contains(&s.as_str())) {
+        return Err(Error::Grant("invalid_scope"));
+    }
+    let aud = match req.audience {
+        Some(aud) if client.audiences().contains(&aud.as_str()) => aud,
+        Some(_) => return Err(Error::Grant("invalid_target")),
+        None => client
+            .audiences()
+            .first()
+            .map(|aud| aud.to_string())
+            .ok_or_else(|| missing("audience"))?,
+    };
+    let mut registered = Map::new();
+    registered.insert("client_id".into(), client.id.clone().into());
+    Ok(Grant {
+        sub: client.id.clone(),
+        aud,
+        scopes,
+        exp: Some(Utc::now() + Duration::seconds(client.token_lifetime.into())),
+        registered,
+        extra: custom_claims(req.custom_claims.as_deref())?,
+        ..Default::default()
+    })
+}
+
+/// Tokens for a device the user approved at `/device`, with only the scopes the
+/// client still has.
+async fn device_code(
+    conn: &MainDatabase,
+    subjects: &SubjectKey,
+    auth: &clients::ClientAuth,
+    mut req: TokenRequest,
+) -> Result<Grant> {
+    if req.custom_claims.is_some() {
+        return Err(Error::Grant("invalid_request"));
+    }
+    let device_code = req
+        .device_code
+        .take()
+        .ok_or_else(|| missing("device_code"))?;
+    let client_id = clients::identify(conn, auth, req.credentials()).await?;
+    let client = clients::find(conn, &client_id)
+        .await?
+        .ok_or(Error::InvalidClient)?;
+    let approved = device::poll(conn, device_code, client_id).await?;
+    let mut registered = Map::new();
+    registered.insert("client_id".into(), approved.client_id.clone().into());
+    let user_sub = approved.sub.ok_or(Error::Grant("invalid_grant"))?;
+    let (sub, lifetime) =
+        user_token_for(conn, subjects, &approved.client_id, user_sub.clone()).await?;
+    let allowed = client.scopes();
+    Ok(Grant {
+        sub,
+        aud: approved.client_id,
+        scopes: split_scopes(approved.scope.as_deref())
+            .into_iter()
+            .filter(|s| allowed.contains(&s.as_str()))
+            .collect(),
+        exp: Some(Utc::now() + lifetime),
+        registered,
+        user_sub: Some(user_sub),
+        ..Default::default()
+    })
+}
+
+/// Exchange a token we issued for a narrower one, per RFC 8693.
+///
+/// The authenticated client is the actor: it gets a token for the same subject, for
+/// one of its registered audiences, with at most the subject token's scopes and
+/// lifetime. Revoking the subject token revokes the exchanged one too.
+///
+/// The subject token has to be addressed to the client, unless it's registered for
+/// delegation. A DPoP-bound one only goes with a proof from the key it's bound to.
+async fn token_exchange(
+    conn: &MainDatabase,
+    ring: &paseto::KeyRing,
+    auth: &clients::ClientAuth,
+    jkt: Option<&str>,
+    mut req: TokenRequest,
+) -> Result<Grant> {
+    let client = clients::authenticate(conn, auth, req.credentials()).await?;
+    if req.subject_token_type.as_deref() != Some(ACCESS_TOKEN_TYPE) {
+        return Err(Error::Grant("invalid_request"));
+    }
+    if !matches!(
+        req.requested_token_type.as_deref(),
+        None | Some(ACCESS_TOKEN_TYPE)
+    ) {
+        return Err(Error::Grant("invalid_request"));
+    }
+    let raw = req.subject_token.ok_or_else(|| missing("subject_token"))?;
+    let subject = match paseto::verify_token(conn, ring, &raw).await {
+        Ok(subject) => subject,
+        Err(Error::Database(why)) => return Err(Error::Database(why)),
+        Err(_) => return Err(Error::Grant("invalid_grant")),
+    };
+    if subject.aud != client.id && !client.delegation {
+        return Err(Error::Grant("invalid_grant"));
+    }
+    if let Some(cnf) = &subject.cnf {
+        if jkt != Some(cnf.jkt.as_str()) {
+            return Err(Error::Grant("invalid_grant"));
+        }
+    }
+    let aud = req.audience.ok_or_else(|| missing("audience"))?;
+    if !client.audiences().contains(&aud.as_str()) {
+        return Err(Error::Grant("invalid_target"));
+    }
+    let scopes = match req.scope.as_deref() {
+        Some(_) => split_scopes(req.scope.as_deref()),
+        None => subject
+            .scopes()
+            .iter()
+            .filter(|s| *s != paseto::ADMIN_SCOPE)
+            .cloned()
+            .collect(),
+    };
+    if scopes.iter().any(|s| !subject.has_scope(s)) {
+        return Err(Error::Grant("invalid_scope"));
+    }
+    let exp = Utc::now() + Duration::seconds(client.token_lifetime.into());
+    let exp = match subject.expires_at() {
+        Some(max) if max < exp => max,
+        _ => exp,
+    };
+    let act = paseto::Actor {
+        sub: client.id.clone(),
+        act: subject.act.map(Box::new),
+    };
+    let mut registered = Map::new();
+    registered.insert("client_id".into(), client.id.into());
+    registered.insert("act".into(), serde_json::to_value(act)?);
+    Ok(Grant {
+        sub: subject.sub,
+        aud,
+        scopes,
+        exp: Some(exp),
+        parent: Some(subject.jti),
+        registered,
+        extra: custom_claims(req.custom_claims.as_deref())?,
+        ..Default::default()
+    })
+}
+
+/// The OAuth 2.0 / IndieAuth token endpoint.
+///
+/// Clients sending a `DPoP` proof get a token bound to the proof's key.
+#[post("/token", data = "<req>")]
+#[instrument(skip(conn, ring, subjects, unregistered, base, auth, req), err)]
+#[allow(clippy::too_many_arguments)]
+pub async fn token(
+    conn: MainDatabase,
+    ring: &State<paseto::KeyRing>,
+    subjects: &State<SubjectKey>,
+    unregistered: &State<clients::Unregistered>,
+    base: ResolvedBaseUrl,
+    proof: dpop::Proof,
+    auth: clients::ClientAuth,
+    req: Form<TokenRequest>,
+) -> Result<Json<TokenResponse>> {
+    let req = req.into_inner();
+    let mut issued_token_type = None;
+    let mut id_token = None;
+    let (mut grant, me) = match req.grant_type.as_str() {
+        "authorization_code" => {
+            let (grant, iac) =
+                authorization_code(&conn, subjects, unregistered, &auth, req).await?;
+            if grant.scopes.iter().any(|s| s == oidc::OPENID_SCOPE) {
+                let lifetime = clients::find(&conn, &iac.client_id)
+                    .await?
+                    .map_or(oidc::ID_TOKEN_LIFETIME, |c| c.id_token_lifetime.into());
+                id_token = Some(oidc::id_token(
+                    ring.inner(),
+                    &base.0,
+                    &grant.sub,
+                    Duration::seconds(lifetime),
+                    &iac,
+                )?);
+            }
+            (grant, iac.me)
+        }
+        "client_credentials" => (client_credentials(&conn, &auth, req).await?, None),
+        device::GRANT_TYPE => (device_code(&conn, subjects, &auth, req).await?, None),
+        TOKEN_EXCHANGE_GRANT_TYPE => {
+            issued_token_type = Some(ACCESS_TOKEN_TYPE);
+            let grant = token_exchange(&conn, ring.inner(), &auth, proof.jkt(), req).await?;
+            (grant, None)
+        }
+        other => return Err(Error::UnsupportedGrantType(other.to_string())),
+    };
+    if let Some(jkt) = proof.jkt() {
+        grant
+            .registered
+            .insert("cnf".into(), serde_json::json!({ "jkt": jkt }));
+    }
+    let expires_in = grant.exp.map(|exp| (exp - Utc::now()).num_seconds());
+    let scope = grant.scopes.join(" ");
+    let access_token = issue(&conn, ring.inner(), grant).await?;
+    Ok(Json(TokenResponse {
+        access_token,
+        token_type: if proof.jkt().is_some() {
+            "DPoP"
+        } else {
+            "Bearer"
+        },
+        expires_in,
+        scope,
+        id_token,
+        issued_token_type,
+        me,
+    }))
+}
+
+#[post("/token/revoke?<jti>")]
+#[instrument(skip(conn), err)]
+pub async fn revoke(conn: MainDatabase, tok: paseto::Token, jti: String) -> Result<()> {
+    conn.run(move |c| {
+        use schema::tokens::dsl;
+        let target: models::Token = dsl::tokens
+            .find(&jti)
+            .get_result(c)
+            .optional()?
+            .ok_or(Error::NotFound)?;
+        if !tok.is_admin() && target.sub != tok.sub {
+            return Err(Error::Forbidden(format!(
+                "can't revoke tokens for {}",
+                target.sub
+            )));
+        }
+        // walk down the lineage, revoking one generation at a time
+        let mut generation = vec![target.id];
+        while !generation.is_empty() {
+            diesel::update(dsl::tokens.filter(dsl::id.eq_any(&generation)))
+                .set(&models::UpdateTokenValid { valid: Some(0) })
+                .execute(c)?;
+            generation = dsl::tokens
+                .filter(dsl::parent.eq_any(&generation))
+                .select(dsl::id)
+                .load(c)?;
+        }
+        Ok(())
+    })
+    .await
 }
@@ -0,0 +1,40 @@
+use color_eyre::eyre::{eyre, Result};
+use diesel::prelude::*;
+use indieauth::{
+    paseto::{self, Keypair, StorageKey, Version},
+    APPLICATION_NAME,
+};
+use tracing::info;
+
+pub fn establish_connection() -> Result<SqliteConnection> {
+    let database_url = std::env::var("DATABASE_URL").unwrap_or("./file.db".to_string());
+    SqliteConnection::establish(&database_url)
+        .map_err(|why| eyre!("can't connect to {database_url}: {why}"))
+}
+
+/// Generate a new signing key for `paseto.version` and make it the active one. The
+/// previous key is retired, but still accepted, so tokens already handed out stay
+/// valid.
+///
+/// Running servers reread the stored keys every 30 seconds, and start signing with the
+/// new one then, no restart needed.
+fn main() -> Result<()> {
+    color_eyre::install()?;
+    tracing_subscriber::fmt::init();
+    info!("{APPLICATION_NAME} key rotation starting up");
+    let figment = rocket::Config::figment();
+    let storage = StorageKey::from_figment(&figment)
+        .map_err(|why| eyre!("invalid paseto.storage_key: {why}"))?;
+    let version = figment
+        .extract_inner::<Version>("paseto.version")
+        .unwrap_or_default();
+    let mut connection = establish_connection()?;
+    let kp = Keypair::generate(version).map_err(|why| eyre!("can't generate a key: {why}"))?;
+    paseto::store(&mut connection, &kp, storage.as_ref())
+        .map_err(|why| eyre!("can't store the new key: {why}"))?;
+    info!(
+        "promoted {version:?} signing key {}, servers sign with it within a minute",
+        kp.kid()
+    );
+    Ok(())
+}
@@ -0,0 +1,201 @@
+//! Sender-constrained tokens, per RFC 9449.
+//!
+//! A client opts in by sending a `DPoP` proof to the token endpoint. The token it
+//! gets back is bound to the proof's key with a `cnf.jkt` claim, and from then on is
+//! only accepted alongside a fresh proof signed by that key.
+
+use std::{collections::HashMap, sync::Mutex};
+
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD as BASE64_URL_SAFE_NO_PAD, Engine};
+use chrono::Utc;
+use ring::{
+    digest::{digest, SHA256},
+    signature::{UnparsedPublicKey, ECDSA_P256_SHA256_FIXED, ED25519},
+};
+use rocket::{
+    fairing::AdHoc,
+    http::Status,
+    request::{self, FromRequest},
+    Request,
+};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    api::{Error, Result},
+    BaseUrl,
+};
+
+/// How far a proof's `iat` may be from our clock, in seconds.
+const MAX_SKEW: i64 = 60;
+
+fn invalid(why: impl Into<String>) -> Error {
+    Error::InvalidDpopProof(why.into())
+}
+
+fn b64(input: &str) -> Result<Vec<u8>> {
+    BASE64_URL_SAFE_NO_PAD
+        .decode(input)
+        .map_err(|_| invalid("malformed base64"))
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(tag = "kty")]
+enum Jwk {
+    #[serde(rename = "OKP")]
+    Okp { crv: String, x: String },
+    #[serde(rename = "EC")]
+    Ec { crv: String, x: String, y: String },
+}
+
+impl Jwk {
+    /// The RFC 7638 thumbprint: the required members, sorted, without whitespace.
+    fn thumbprint(&self) -> String {
+        let canonical = match self {
+            Jwk::Okp { crv, x } => format!(r#"{{"crv":"{crv}","kty":"OKP","x":"{x}"}}"#),
+            Jwk::Ec { crv, x, y } => {
+                format!(r#"{{"crv":"{crv}","kty":"EC","x":"{x}","y":"{y}"}}"#)
+            }
+        };
+        BASE64_URL_SAFE_NO_PAD.encode(digest(&SHA256, canonical.as_bytes()))
+    }
+
+    fn verify(&self, alg: &str, message: &[u8], sig: &[u8]) -> Result {
+        match (alg, self) {
+            ("EdDSA", Jwk::Okp { crv, x }) if crv == "Ed25519" => {
+                UnparsedPublicKey::new(&ED25519, b64(x)?).verify(message, sig)
+            }
+            ("ES256", Jwk::Ec { crv, x, y }) if crv == "P-256" => {
+                let mut point = vec![4];
+                point.extend(b64(x)?);
+                point.extend(b64(y)?);
+                UnparsedPublicKey::new(&ECDSA_P256_SHA256_FIXED, point).verify(message, sig)
+            }
+            _ => return Err(invalid(format!("unsupported alg {alg} for this key"))),
+        }
+        .map_err(|_| invalid("bad signature"))
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct Header {
+    typ: String,
+    alg: String,
+    jwk: Jwk,
+}
+
+#[derive(Debug, Deserialize)]
+struct Claims {
+    jti: String,
+    htm: String,
+    htu: String,
+    iat: i64,
+    ath: Option<String>,
+}
+
+/// The `cnf` claim of a bound token.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct Confirmation {
+    pub jkt: String,
+}
+
+/// The proofs we've seen recently, to catch replays.
+pub struct Dpop {
+    seen: Mutex<HashMap<String, i64>>,
+}
+
+pub fn fairing() -> AdHoc {
+    AdHoc::on_ignite("DPoP", |rocket| async {
+        rocket.manage(Dpop {
+            seen: Mutex::new(HashMap::new()),
+        })
+    })
+}
+
+impl Dpop {
+    /// Check a proof for `request`, returning the thumbprint of the key that signed it.
+    ///
+    /// `access_token` must be passed when the proof accompanies a bound token.
+    pub fn verify(
+        &self,
+        request: &Request<'_>,
+        proof: &str,
+        access_token: Option<&str>,
+    ) -> Result<String> {
+        let mut parts = proof.split('.');
+        let (header_b64, claims_b64, sig_b64) = match (parts.next(), parts.next(), parts.next()) {
+            (Some(h), Some(c), Some(s)) if parts.next().is_none() => (h, c, s),
+            _ => return Err(invalid("not a JWS")),
+        };
+        let header: Header =
+            serde_json::from_slice(&b64(header_b64)?).map_err(|_| invalid("malformed header"))?;
+        if header.typ != "dpop+jwt" {
+            return Err(invalid("wrong typ"));
+        }
+        header.jwk.verify(
+            &header.alg,
+            format!("{header_b64}.{claims_b64}").as_bytes(),
+            &b64(sig_b64)?,
+        )?;
+        let claims: Claims =
+            serde_json::from_slice(&b64(claims_b64)?).map_err(|_| invalid("malformed claims"))?;
+        if claims.htm != request.method().as_str() {
+            return Err(invalid("htm mismatch"));
+        }
+        let htu = claims.htu.split(['?', '#']).next().unwrap_or_default();
+        let base_url = request
+            .rocket()
+            .state::<BaseUrl>()
+            .expect("base URL fairing attached");
+        if htu != base_url.url_of(request) {
+            return Err(invalid("htu mismatch"));
+        }
+        let now = Utc::now().timestamp();
+        if (claims.iat - now).abs() > MAX_SKEW {
+            return Err(invalid("iat too far from now"));
+        }
+        if let Some(access_token) = access_token {
+            let ath = BASE64_URL_SAFE_NO_PAD.encode(digest(&SHA256, access_token.as_bytes()));
+            if claims.ath.as_deref() != Some(ath.as_str()) {
+                return Err(invalid("ath mismatch"));
+            }
+        }
+        let mut seen = self.seen.lock().unwrap();
+        seen.retain(|_, iat| now - *iat <= MAX_SKEW);
+        if seen.insert(claims.jti, claims.iat).is_some() {
+            return Err(invalid("proof replayed"));
+        }
+        Ok(header.jwk.thumbprint())
+    }
+}
+
+/// The `DPoP` header of a request, if any, checked as a proof without an access token.
+#[derive(Debug)]
+pub struct Proof(Option<String>);
+
+impl Proof {
+    /// The thumbprint of the proof's key, for the `cnf.jkt` claim.
+    pub fn jkt(&self) -> Option<&str> {
+        self.0.as_deref()
+    }
+}
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for Proof {
+    type Error = Error;
+
+    async fn from_request(request: &'r Request<'_>) -> request::Outcome<Self, Self::Error> {
+        let proofs = request.headers().get("DPoP").collect::<Vec<_>>();
+        let dpop = request
+            .rocket()
+            .state::<Dpop>()
+            .expect("DPoP fairing attached");
+        match proofs.as_slice() {
+            [] => request::Outcome::Success(Proof(None)),
+            [proof] => match dpop.verify(request, proof, None) {
+                Ok(jkt) => request::Outcome::Success(Proof(Some(jkt))),
+                Err(why) => request::Outcome::Failure((Status::BadRequest, why)),
+            },
+            _ => request::Outcome::Failure((Status::BadRequest, invalid("multiple proofs"))),
+        }
+    }
+}
@@ -0,0 +1,189 @@
+//! Fetching documents from URLs someone other than an admin picked: client metadata,
+//! sector identifiers, home pages. Those can point anywhere, so requests time out,
+//! bodies are capped, and private and loopback addresses are off limits, even when
+//! reached through DNS or a redirect.
+
+use std::{
+    net::{IpAddr, SocketAddr},
+    sync::Arc,
+    time::Duration,
+};
+
+use reqwest::{
+    dns::{Addrs, Name, Resolve, Resolving},
+    redirect, Method, RequestBuilder, Response,
+};
+use rocket::{fairing::AdHoc, serde::de::DeserializeOwned};
+use url::{Host, Url};
+
+use crate::{
+    api::{Error, Result},
+    APPLICATION_NAME,
+};
+
+/// The largest body we bother reading.
+pub const MAX_BODY: usize = 1 << 20;
+
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+const MAX_REDIRECTS: usize = 5;
+
+/// The HTTP client for untrusted URLs.
+pub struct Fetcher {
+    client: reqwest::Client,
+    allow_private: bool,
+}
+
+/// Reads `fetch.allow_private`, for tests against servers on localhost.
+pub fn fairing() -> AdHoc {
+    AdHoc::try_on_ignite("Fetcher", |rocket| async {
+        let allow_private = rocket
+            .figment()
+            .extract_inner::<bool>("fetch.allow_private")
+            .unwrap_or(false);
+        match Fetcher::new(allow_private) {
+            Ok(fetcher) => Ok(rocket.manage(fetcher)),
+            Err(why) => {
+                tracing::error!("failed to build the HTTP client: {why}");
+                Err(rocket)
+            }
+        }
+    })
+}
+
+impl Fetcher {
+    pub fn new(allow_private: bool) -> reqwest::Result<Self> {
+        let policy = redirect::Policy::custom(move |attempt| {
+            if attempt.previous().len() >= MAX_REDIRECTS {
+                attempt.error("too many redirects")
+            } else if !allow_private && !allowed(attempt.url()) {
+                attempt.error("redirected to a private address")
+            } else {
+                attempt.follow()
+            }
+        });
+        let mut builder = reqwest::Client::builder()
+            .timeout(TIMEOUT)
+            .redirect(policy)
+            .user_agent(APPLICATION_NAME);
+        if !allow_private {
+            builder = builder.dns_resolver(Arc::new(PublicOnly));
+        }
+        Ok(Fetcher {
+            client: builder.build()?,
+            allow_private,
+        })
+    }
+
+    /// Start a request to `url`, unless it's somewhere we don't go.
+    pub fn request(&self, method: Method, url: &Url) -> Result<RequestBuilder> {
+        if !self.allow_private && !allowed(url) {
+            return Err(Error::OAuth2(format!("{url} is not a public address")));
+        }
+        Ok(self.client.request(method, url.clone()))
+    }
+
+    /// GET `url`, failing unless it answers with a success.
+    pub async fn get(&self, url: &Url, accept: &str) -> Result<Response> {
+        let res = self
+            .request(Method::GET, url)?
+            .header("Accept", accept)
+            .send()
+            .await
+            .map_err(|why| Error::OAuth2(format!("{why}")))?;
+        if !res.status().is_success() {
+            return Err(Error::OAuth2(format!(
+                "{url} answered with {}",
+                res.status()
+            )));
+        }
+        Ok(res)
+    }
+
+    /// GET `url` as JSON.
+    pub async fn json<T: DeserializeOwned>(&self, url: &Url) -> Result<T> {
+        let res = self.get(url, "application/json").await?;
+        Ok(serde_json::from_slice(&read(res).await?)?)
+    }
+}
+
+/// The body of `res`, up to [`MAX_BODY`] bytes. Whatever comes after is dropped.
+pub async fn read(mut res: Response) -> Result<Vec<u8>> {
+    let mut body = vec![];
+    while let Some(chunk) = res
+        .chunk()
+        .await
+        .map_err(|why| Error::OAuth2(format!("{why}")))?
+    {
+        let room = MAX_BODY - body.len();
+        body.extend_from_slice(&chunk[..chunk.len().min(room)]);
+        if body.len() == MAX_BODY {
+            break;
+        }
+    }
+    Ok(body)
+}
+
+/// http or https, and not an IP address we shouldn't be talking to. Host names are
+/// checked when they're resolved.
+fn allowed(url: &Url) -> bool {
+    if !matches!(url.scheme(), "http" | "https") {
+        return false;
+    }
+    match url.host() {
+        Some(Host::Ipv4(ip)) => is_public(IpAddr::V4(ip)),
+        Some(Host::Ipv6(ip)) => is_public(IpAddr::V6(ip)),
+        Some(Host::Domain(domain)) => !domain.eq_ignore_ascii_case("localhost"),
+        None => false,
+    }
+}
+
+fn is_public(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => {
+            let [a, b, ..] = ip.octets();
+            !(ip.is_private()
+                || ip.is_loopback()
+                || ip.is_link_local()
+                || ip.is_broadcast()
+                || ip.is_documentation()
+                || ip.is_unspecified()
+                || ip.is_multicast()
+                // shared address space, RFC 6598
+                || (a == 100 && (64..128).contains(&b))
+                || a == 0)
+        }
+        IpAddr::V6(ip) => {
+            if let Some(ip) = ip.to_ipv4_mapped() {
+                return is_public(IpAddr::V4(ip));
+            }
+            let first = ip.segments()[0];
+            !(ip.is_loopback()
+                || ip.is_unspecified()
+                || ip.is_multicast()
+                // unique local, fc00::/7
+                || (first & 0xfe00) == 0xfc00
+                // link-local, fe80::/10
+                || (first & 0xffc0) == 0xfe80)
+        }
+    }
+}
+
+/// Resolves host names to their public addresses only.
+struct PublicOnly;
+
+impl Resolve for PublicOnly {
+    fn resolve(&self, name: Name) -> Resolving {
+        Box::pin(async move {
+            let addrs: Vec<SocketAddr> = tokio::net::lookup_host((name.as_str(), 0))
+                .await?
+                .filter(|addr| is_public(addr.ip()))
+                .collect();
+            if addrs.is_empty() {
+                return Err(format!("{} has no public address", name.as_str()).into());
+            }
+            let addrs: Addrs = Box::new(addrs.into_iter());
+            Ok(addrs)
+        })
+    }
+}
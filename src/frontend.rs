@@ -16,11 +16,6 @@ async fn frontend() -> App {
     }
 }
 
-#[get("/login")]
-async fn login() -> App {
-    frontend().await
-}
-
 #[get("/sw.js")]
 async fn sw() -> (ContentType, &'static str) {
     (ContentType::JavaScript, include_str!("../static/sw.js"))
@@ -44,7 +39,7 @@ async fn not_found(req: &Request<'_>) -> NotFound {
 pub fn fairing() -> AdHoc {
     async fn f(r: rocket::Rocket<rocket::Build>) -> rocket::Rocket<rocket::Build> {
         r.register("/", catchers![not_found])
-            .mount("/", routes![frontend, login, sw])
+            .mount("/", routes![frontend, sw])
     }
     AdHoc::on_ignite("frontend integration", f)
 }
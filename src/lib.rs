@@ -1,13 +1,26 @@
 pub mod api;
+pub mod dpop;
+pub mod fetch;
 pub mod frontend;
-pub mod gitlab;
+pub mod jws;
 pub mod models;
 pub mod oauth;
 pub mod paseto;
+pub mod passkey;
 pub mod rocket_trace;
 pub mod schema;
+pub mod session;
+pub mod subject;
+pub mod totp;
+pub mod upstream;
 pub mod wellknown;
 
+use rocket::{
+    fairing::AdHoc,
+    figment::Figment,
+    request::{self, FromRequest},
+    Build, Request, Rocket,
+};
 use rocket_sync_db_pools::{
     database,
     diesel::{prelude::*, SqliteConnection},
@@ -23,7 +36,140 @@ pub const APPLICATION_NAME: &str = concat!(
 #[database("main_data")]
 pub struct MainDatabase(SqliteConnection);
 
-pub struct GitLab;
+/// Where this server is reachable from outside, from `base_url` in Rocket.toml.
+///
+/// It's required: assertion audiences, DPoP proofs and passkeys are checked against
+/// it, and the `Host` header is up to whoever sends the request.
+pub struct BaseUrl(String);
+
+impl BaseUrl {
+    pub fn fairing() -> AdHoc {
+        AdHoc::try_on_ignite("Base URL", |rocket| async {
+            match rocket.figment().extract_inner::<String>("base_url") {
+                Ok(url) if !url.is_empty() => {
+                    let base_url = BaseUrl(url.trim_end_matches('/').to_string());
+                    Ok(rocket.manage(base_url))
+                }
+                _ => {
+                    tracing::error!("base_url must be set to where this server is reachable");
+                    Err(rocket)
+                }
+            }
+        })
+    }
+
+    pub fn get(&self) -> &str {
+        &self.0
+    }
+
+    /// The absolute URL of `request`, without its query.
+    pub fn url_of(&self, request: &Request<'_>) -> String {
+        format!("{}{}", self.0, request.uri().path())
+    }
+}
+
+/// [`BaseUrl`] resolved for the current request, for building absolute links.
+pub struct ResolvedBaseUrl(pub String);
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for ResolvedBaseUrl {
+    type Error = ();
+
+    async fn from_request(request: &'r Request<'_>) -> request::Outcome<Self, Self::Error> {
+        match request.rocket().state::<BaseUrl>() {
+            Some(base) => request::Outcome::Success(ResolvedBaseUrl(base.get().to_string())),
+            None => request::Outcome::Failure((rocket::http::Status::InternalServerError, ())),
+        }
+    }
+}
+
+/// The whole server, configured from `figment`, minus CORS, which is up to where it's
+/// deployed.
+pub fn build(figment: Figment) -> Rocket<Build> {
+    rocket::custom(figment)
+        .attach(rocket_trace::static_files())
+        .attach(frontend::fairing())
+        .attach(MainDatabase::fairing())
+        .attach(rocket_trace::RequestId {})
+        .attach(paseto::key_ring())
+        .attach(session::fairing())
+        .attach(subject::fairing())
+        .attach(BaseUrl::fairing())
+        .attach(fetch::fairing())
+        .attach(dpop::fairing())
+        .attach(api::par::fairing())
+        .attach(api::clients::fairing())
+        .attach(upstream::fairing())
+        .mount(
+            "/login",
+            rocket::routes![
+                upstream::index,
+                upstream::login,
+                upstream::callback,
+                upstream::indieauth::start,
+                upstream::indieauth::callback,
+                upstream::relme::start,
+                upstream::mastodon::start,
+                upstream::mastodon::callback,
+                upstream::email::start,
+                upstream::email::confirm,
+                upstream::email::callback,
+                upstream::signature::login_page,
+                upstream::signature::login,
+                passkey::login_page,
+                passkey::login_options,
+                passkey::login,
+                totp::prompt,
+                totp::verify,
+            ],
+        )
+        .mount(
+            "/",
+            rocket::routes![
+                wellknown::botinfo,
+                wellknown::robots,
+                wellknown::security,
+                wellknown::paseto_keys,
+                wellknown::openid_configuration,
+                wellknown::jwks,
+                session::logout,
+                passkey::manage,
+                passkey::registration_options,
+                passkey::register,
+                passkey::remove,
+                totp::manage,
+                totp::enroll,
+                totp::disable,
+                upstream::signature::manage,
+                upstream::signature::add,
+                upstream::signature::remove,
+                api::device::verify_page,
+                api::device::verify,
+            ],
+        )
+        .mount(
+            "/api",
+            rocket::routes![
+                api::indieauth::auth,
+                api::indieauth::authorized,
+                api::indieauth::send_code,
+                api::token::info,
+                api::token::mint,
+                api::token::revoke,
+                api::token::token,
+                api::keys::rotate,
+                api::clients::register,
+                api::clients::list,
+                api::clients::show,
+                api::clients::update,
+                api::clients::remove,
+                api::clients::register_dynamic,
+                api::device::authorize,
+                api::par::push,
+                api::oidc::userinfo,
+            ],
+        )
+}
 
 pub fn establish_connection() -> SqliteConnection {
     let database_url = std::env::var("DATABASE_URL").expect("DATABASE_URL must be set");
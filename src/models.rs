@@ -10,8 +10,97 @@ pub struct Token {
     pub aud: String,
     pub iss: String,
     pub iat: String,
-    pub exp: Option<i32>,
+    pub exp: Option<i64>,
     pub valid: Option<i32>,
+    pub scopes: Option<String>,
+    pub parent: Option<String>,
+    /// The subject the token was issued for, when `sub` is pairwise.
+    pub user_sub: Option<String>,
+}
+
+#[derive(AsChangeset)]
+#[diesel(table_name = tokens)]
+pub struct UpdateTokenValid {
+    pub valid: Option<i32>,
+}
+
+#[derive(Queryable, Debug, Clone, Insertable)]
+#[diesel(table_name = clients)]
+pub struct Client {
+    pub id: String,
+    pub name: String,
+    pub secret_hash: Option<String>,
+    pub public_key: Option<String>,
+    pub audiences: String,
+    pub scopes: String,
+    pub token_lifetime: i32,
+    pub created_at: String,
+    /// `public` or `pairwise`, see [`crate::subject`].
+    pub subject_type: String,
+    pub sector_identifier: Option<String>,
+    pub redirect_uris: String,
+    /// `confidential` or `public`.
+    pub client_type: String,
+    /// Trusted first-party clients skip the consent screen.
+    pub first_party: bool,
+    pub id_token_lifetime: i32,
+    /// Whether users need a second factor before consenting.
+    pub require_mfa: bool,
+    /// Whether the client may exchange tokens issued to someone else.
+    pub delegation: bool,
+}
+
+#[derive(AsChangeset, Default, PartialEq)]
+#[diesel(table_name = clients)]
+pub struct UpdateClient {
+    pub name: Option<String>,
+    pub audiences: Option<String>,
+    pub scopes: Option<String>,
+    pub token_lifetime: Option<i32>,
+    pub subject_type: Option<String>,
+    pub sector_identifier: Option<String>,
+    pub redirect_uris: Option<String>,
+    pub first_party: Option<bool>,
+    pub id_token_lifetime: Option<i32>,
+    pub require_mfa: Option<bool>,
+    pub delegation: Option<bool>,
+}
+
+#[derive(Queryable, Debug, Clone, Insertable)]
+#[diesel(table_name = pushed_authorization_requests)]
+pub struct PushedAuthorizationRequest {
+    pub request_uri: String,
+    pub client_id: String,
+    pub request: String,
+    pub expires_at: String,
+}
+
+#[derive(Queryable, Debug, Clone, Insertable)]
+#[diesel(table_name = device_codes)]
+pub struct DeviceCode {
+    pub device_code: String,
+    pub user_code: String,
+    pub client_id: String,
+    pub scope: Option<String>,
+    pub status: String,
+    pub sub: Option<String>,
+    pub expires_at: String,
+    pub interval: i32,
+    pub last_polled_at: Option<String>,
+}
+
+#[derive(AsChangeset)]
+#[diesel(table_name = device_codes)]
+pub struct UpdateDeviceCodePolled {
+    pub interval: i32,
+    pub last_polled_at: Option<String>,
+}
+
+#[derive(AsChangeset)]
+#[diesel(table_name = device_codes)]
+pub struct UpdateDeviceCodeStatus {
+    pub status: String,
+    pub sub: Option<String>,
 }
 
 #[derive(Queryable, Debug, Clone, Insertable)]
@@ -24,19 +113,166 @@ pub struct IndieauthCode {
     pub response_type: String,
     pub code_challenge: String,
     pub authorized: bool,
+    pub me: Option<String>,
+    pub scope: Option<String>,
+    pub sub: Option<String>,
+    pub nonce: Option<String>,
+    pub auth_time: Option<String>,
+    pub amr: Option<String>,
+    /// Namespaced claims the client asked to add to its tokens, as a JSON object.
+    pub custom_claims: Option<String>,
 }
 
 #[derive(AsChangeset)]
 #[diesel(table_name = indieauth_codes)]
 pub struct UpdateIndieauthCodeAuthorized {
     pub authorized: bool,
+    pub sub: Option<String>,
+    pub auth_time: Option<String>,
+    pub amr: Option<String>,
 }
 
 #[derive(Queryable, Debug, Clone, Insertable)]
-#[diesel(table_name = gitlab_tokens)]
-pub struct GitlabToken {
+#[diesel(table_name = upstream_tokens)]
+pub struct UpstreamToken {
     pub id: String,
-    pub user_id: i32,
+    /// The name of the provider's `oauth.<name>` section.
+    pub provider: String,
+    pub user_id: String,
     pub access_token: String,
-    pub refresh_token: String,
+    pub refresh_token: Option<String>,
+}
+
+#[derive(Queryable, Debug, Clone, Insertable)]
+#[diesel(table_name = sessions)]
+pub struct Session {
+    pub id: String,
+    pub user_id: String,
+    pub sub: String,
+    pub auth_time: String,
+    pub amr: String,
+    pub expires_at: String,
+}
+
+#[derive(Queryable, Debug, Clone, Insertable)]
+#[diesel(table_name = signing_keys)]
+pub struct SigningKey {
+    pub kid: String,
+    pub public: String,
+    pub private: String,
+    pub created_at: String,
+    pub retired_at: Option<String>,
+    pub active: bool,
+    /// The PASETO version the key signs, `v2` or `v4`. No key signs both.
+    pub version: String,
+}
+
+#[derive(AsChangeset)]
+#[diesel(table_name = signing_keys)]
+pub struct RetireSigningKey {
+    pub retired_at: Option<String>,
+    pub active: bool,
+}
+
+/// The OAuth app registered on a Mastodon instance the first time someone logged in
+/// from there.
+#[derive(Queryable, Debug, Clone, Insertable)]
+#[diesel(table_name = mastodon_apps)]
+pub struct MastodonApp {
+    pub instance: String,
+    pub client_id: String,
+    pub client_secret: String,
+    pub redirect_uri: String,
+    pub created_at: String,
+}
+
+/// A WebAuthn credential, registered by a logged in owner to log in with later.
+#[derive(Queryable, Debug, Clone, Insertable)]
+#[diesel(table_name = passkeys)]
+pub struct Passkey {
+    /// The base64url credential id.
+    pub id: String,
+    pub user_id: String,
+    pub sub: String,
+    pub name: String,
+    /// The base64url DER SubjectPublicKeyInfo.
+    pub public_key: String,
+    /// The COSE algorithm identifier.
+    pub alg: i32,
+    pub sign_count: i64,
+    pub created_at: String,
+    pub last_used_at: Option<String>,
+}
+
+#[derive(AsChangeset)]
+#[diesel(table_name = passkeys)]
+pub struct UpdatePasskeyUse {
+    pub sign_count: i64,
+    pub last_used_at: Option<String>,
+}
+
+/// A user's TOTP secret. It only counts once confirmed with a first code.
+#[derive(Queryable, Debug, Clone, Insertable)]
+#[diesel(table_name = totp_secrets)]
+pub struct TotpSecret {
+    /// The subject it's for, however they log in.
+    pub sub: String,
+    /// Base32, as authenticator apps take it.
+    pub secret: String,
+    pub confirmed: bool,
+    /// The last time step a code was used for, so none works twice.
+    pub last_step: Option<i64>,
+    /// Wrong codes in a row. Too many, and only recovery codes work.
+    pub failures: i32,
+    pub created_at: String,
+    /// Wrong recovery codes in a row, and when the last one was.
+    pub recovery_failures: i32,
+    pub recovery_failed_at: Option<String>,
+}
+
+#[derive(Queryable, Debug, Clone, Insertable)]
+#[diesel(table_name = recovery_codes)]
+pub struct RecoveryCode {
+    /// Hex-encoded SHA-256 of the code.
+    pub hash: String,
+    pub sub: String,
+    pub created_at: String,
+    pub used_at: Option<String>,
+}
+
+/// A login link sent by email, good for one use.
+#[derive(Queryable, Debug, Clone, Insertable)]
+#[diesel(table_name = email_logins)]
+pub struct EmailLogin {
+    pub id: String,
+    pub email: String,
+    pub created_at: String,
+    pub expires_at: String,
+    pub used_at: Option<String>,
+}
+
+/// An SSH or OpenPGP public key an owner logs in with by signing a challenge.
+#[derive(Queryable, Debug, Clone, Insertable)]
+#[diesel(table_name = login_keys)]
+pub struct LoginKey {
+    pub id: String,
+    pub user_id: String,
+    pub sub: String,
+    pub name: String,
+    /// In OpenSSH format, or an armored OpenPGP key.
+    pub public_key: String,
+    pub created_at: String,
+    pub last_used_at: Option<String>,
+}
+
+/// What the last login said about someone, for OpenID Connect clients asking.
+#[derive(Queryable, Debug, Clone, Insertable)]
+#[diesel(table_name = users)]
+pub struct User {
+    pub sub: String,
+    pub name: String,
+    pub website: Option<String>,
+    pub email: Option<String>,
+    pub email_verified: bool,
+    pub updated_at: String,
 }
@@ -64,12 +64,12 @@ impl Adapter {
             .into_owned())
     }
 
-    pub async fn exchange_code<K>(
+    pub async fn exchange_code(
         &self,
         config: &mut OAuthConfig,
         token: TokenRequest,
         state: &str,
-    ) -> Result<TokenResponse<K>> {
+    ) -> Result<TokenResponse> {
         let rb = self
             .client
             .post(config.provider().token_uri())
@@ -113,7 +113,7 @@ impl Adapter {
             return Err(Error::ExchangeError(resp.status().as_u16()));
         }
         let body = resp.bytes().await.map_err(|_| Error::ExchangeFailure)?;
-        let tr: TokenResponse<K> = serde_json::from_slice(&body).map_err(Error::Json)?;
+        let tr: TokenResponse = serde_json::from_slice(&body).map_err(Error::Json)?;
         Ok(tr)
     }
 }
@@ -13,7 +13,7 @@ pub struct OAuthConfig {
 }
 
 impl OAuthConfig {
-    fn new(
+    pub fn new(
         provider: Provider,
         client_id: String,
         client_secret: Option<String>,
@@ -28,19 +28,34 @@ impl OAuthConfig {
         }
     }
 
-    pub fn from_figment(figment: &Figment, name: &str) -> std::result::Result<Self, Error> {
+    /// Read `oauth.<name>`. Providers with well-known endpoints pass them as
+    /// `defaults`, so they can be left out of the configuration.
+    pub fn from_figment(
+        figment: &Figment,
+        name: &str,
+        defaults: Option<Provider>,
+    ) -> std::result::Result<Self, Error> {
         #[derive(rocket::serde::Deserialize)]
+        #[serde(crate = "rocket::serde")]
         struct Config {
-            auth_uri: String,
-            token_uri: String,
+            auth_uri: Option<String>,
+            token_uri: Option<String>,
             client_id: String,
             client_secret: Option<String>,
             redirect_uri: Option<String>,
         }
         let conf: Config = figment.extract_inner(&format!("oauth.{name}"))?;
-        let provider = Provider {
-            auth_uri: conf.auth_uri.into(),
-            token_uri: conf.token_uri.into(),
+        let provider = match (conf.auth_uri, conf.token_uri, defaults) {
+            (Some(auth_uri), Some(token_uri), _) => Provider::new(auth_uri, token_uri),
+            (auth_uri, token_uri, Some(defaults)) => Provider {
+                auth_uri: auth_uri.map_or(defaults.auth_uri, Cow::from),
+                token_uri: token_uri.map_or(defaults.token_uri, Cow::from),
+            },
+            _ => {
+                return Err(Error::from(format!(
+                    "oauth.{name} needs auth_uri and token_uri"
+                )))
+            }
         };
         Ok(Self::new(
             provider,
@@ -95,6 +110,16 @@ pub struct Provider {
 }
 
 impl Provider {
+    pub fn new(
+        auth_uri: impl Into<Cow<'static, str>>,
+        token_uri: impl Into<Cow<'static, str>>,
+    ) -> Self {
+        Self {
+            auth_uri: auth_uri.into(),
+            token_uri: token_uri.into(),
+        }
+    }
+
     pub fn auth_uri(&self) -> &str {
         &self.auth_uri
     }
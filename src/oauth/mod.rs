@@ -1,24 +1,24 @@
-use std::{fmt, marker::PhantomData, sync::Arc};
+use std::fmt;
 
 use crate::api::{Error, Result};
 use base64::{engine::general_purpose::URL_SAFE_NO_PAD as BASE64_URL_SAFE_NO_PAD, Engine};
 use rocket::{
-    fairing::{AdHoc, Fairing},
-    form::{Form, FromForm},
-    http::{Cookie, CookieJar, SameSite, Status},
-    request::{self, FromRequest},
+    http::{Cookie, CookieJar, SameSite},
     response::Redirect,
-    Build, Ignite, Request, Rocket, Sentinel,
 };
 use serde::Deserialize;
 use tokio::sync::RwLock;
 
-use self::config::OAuthConfig;
+pub use self::config::{OAuthConfig, Provider};
 
 mod adapter;
 mod config;
 pub mod pkce;
 
+/// Holds the provider a login was started with and the state sent along, so the
+/// callback of one provider can't finish a login started with another.
+const STATE_COOKIE: &str = "oauth2_state";
+
 fn generate_state(rng: &mut impl rand::RngCore) -> Result<String> {
     let mut buf = [0; 128]; // 1024 bits
     rng.try_fill_bytes(&mut buf)
@@ -33,204 +33,117 @@ pub enum TokenRequest {
 }
 
 #[derive(Clone, Debug, PartialEq, Deserialize)]
-pub struct TokenResponse<K> {
-    data: TokenResponseData,
-    #[serde(skip)]
-    _k: PhantomData<fn() -> K>,
-}
-
-#[derive(Clone, Debug, PartialEq, Deserialize)]
-struct TokenResponseData {
+pub struct TokenResponse {
     access_token: String,
     token_type: String,
     expires_in: Option<u64>,
     refresh_token: Option<String>,
     created_at: Option<u64>,
     scope: Option<String>,
+    /// Only from OpenID Connect providers.
+    id_token: Option<String>,
 }
 
-impl<K> TokenResponse<K> {
+impl TokenResponse {
     pub fn access_token(&self) -> &str {
-        &self.data.access_token
+        &self.access_token
     }
 
     pub fn refresh_token(&self) -> Option<&str> {
-        self.data.refresh_token.as_deref()
-    }
-}
-
-struct Shared<K> {
-    adapter: adapter::Adapter,
-    config: config::OAuthConfig,
-    _k: PhantomData<fn() -> TokenResponse<K>>,
-}
-
-pub struct OAuth2<K>(Arc<RwLock<Shared<K>>>);
-
-#[rocket::async_trait]
-impl<'r, K: 'static> FromRequest<'r> for TokenResponse<K> {
-    type Error = Error;
-
-    async fn from_request(request: &'r Request<'_>) -> request::Outcome<Self, Self::Error> {
-        let oauth2 = request
-            .rocket()
-            .state::<Arc<RwLock<Shared<K>>>>()
-            .expect("OAuth2 fairing was not attached for this key type!");
-        let query = match request.uri().query() {
-            Some(q) => q,
-            None => {
-                return request::Outcome::Failure((
-                    Status::BadRequest,
-                    Error::OAuth2("missing query string in request".into()),
-                ));
-            }
-        };
-
-        #[derive(FromForm)]
-        struct CallbackQuery {
-            code: String,
-            state: String,
-            scope: Option<String>,
-        }
-        let params = match Form::<CallbackQuery>::parse_encoded(&query) {
-            Ok(p) => p,
-            Err(e) => {
-                tracing::warn!("Failed to parse OAuth2 query string: {e:?}");
-                return request::Outcome::Failure((
-                    Status::BadRequest,
-                    Error::OAuth2(format!("{e:?}")),
-                ));
-            }
-        };
-        {
-            let cookies = request
-                .guard::<&CookieJar<'_>>()
-                .await
-                .expect("request cookies");
-            match cookies.get_private("oauth2_state") {
-                Some(ref cookie) if cookie.value() == params.state => {
-                    cookies.remove(cookie.clone());
-                }
-                other => {
-                    if other.is_some() {
-                        tracing::warn!("the OAuth2 state returned from the server did not match the stored state.");
-                    } else {
-                        tracing::error!("The OAuth2 state cookie was missing. It may have been blocked by the client?");
-                    }
-                    return request::Outcome::Failure((Status::BadRequest, Error::OAuth2("The OAuth2 state returned from the server did not match the stored state".into())));
-                }
-            }
-        }
-        let mut oauth2 = oauth2.write().await;
-        match oauth2
-            .adapter
-            .clone()
-            .exchange_code(
-                &mut oauth2.config,
-                TokenRequest::AuthorizationCode(params.code),
-                &params.state,
-            )
-            .await
-        {
-            Ok(mut token) => {
-                if token.data.scope.is_none() && params.scope.is_some() {
-                    token.data.scope = params.scope;
-                }
-                request::Outcome::Success(token)
-            }
-            Err(e) => {
-                tracing::warn!("OAuth2 token exchange failed: {e}");
-                request::Outcome::Failure((Status::BadRequest, e))
-            }
-        }
+        self.refresh_token.as_deref()
     }
-}
 
-fn sentinel_abort<K: 'static>(rocket: &Rocket<Ignite>, wrapper: &str) -> bool {
-    if rocket.state::<Arc<Shared<K>>>().is_some() {
-        return false;
+    pub fn id_token(&self) -> Option<&str> {
+        self.id_token.as_deref()
     }
-    let type_name = std::any::type_name::<K>();
-    tracing::error!(
-        "{wrapper}<{type_name}> was used in a mounted route without attaching a matching fairing"
-    );
-    tracing::info!(
-        "attach either OAuth2::<{type_name}>::fairing() or OAuth2::<{type_name}>::custom()"
-    );
-    true
 }
 
-impl<K: 'static> Sentinel for TokenResponse<K> {
-    fn abort(rocket: &Rocket<Ignite>) -> bool {
-        sentinel_abort::<K>(rocket, "TokenResponse")
-    }
+/// An OAuth 2.0 client for one upstream provider.
+pub struct OAuth2 {
+    /// Which provider this is, e.g. its configured name, or a Mastodon instance.
+    name: String,
+    adapter: adapter::Adapter,
+    config: RwLock<OAuthConfig>,
 }
 
-impl<K: 'static> OAuth2<K> {
-    pub fn fairing(config_name: impl AsRef<str> + Send + 'static) -> impl Fairing {
-        AdHoc::try_on_ignite("oauth2::fairing", |rocket| async move {
-            let config =
-                match config::OAuthConfig::from_figment(rocket.figment(), config_name.as_ref()) {
-                    Ok(c) => c,
-                    Err(e) => {
-                        tracing::error!("invalid configuration: {e:?}");
-                        return Err(rocket);
-                    }
-                };
-            Ok(Self::_init(rocket, adapter::Adapter::default(), config))
-        })
-    }
-
-    fn _init(
-        rocket: Rocket<Build>,
-        adapter: adapter::Adapter,
-        config: config::OAuthConfig,
-    ) -> Rocket<Build> {
-        rocket.manage(Arc::new(Shared::<K> {
-            adapter,
-            config,
-            _k: PhantomData,
-        }))
-    }
-
-    pub fn custom(adapter: adapter::Adapter, config: OAuthConfig) -> impl Fairing {
-        AdHoc::on_ignite("oauth2::custom", |rocket| async {
-            Self::_init(rocket, adapter, config)
-        })
+impl OAuth2 {
+    pub fn new(name: impl Into<String>, config: OAuthConfig) -> Self {
+        Self {
+            name: name.into(),
+            adapter: adapter::Adapter::default(),
+            config: RwLock::new(config),
+        }
     }
 
     pub async fn get_redirect(&self, cookies: &CookieJar<'_>, scopes: &[&str]) -> Result<Redirect> {
         self.get_redirect_extras(cookies, scopes, &[]).await
     }
 
-    async fn get_redirect_extras(
+    pub async fn get_redirect_extras(
         &self,
         cookies: &CookieJar<'_>,
         scopes: &[&str],
         extras: &[(&str, &str)],
     ) -> Result<Redirect> {
         let state = generate_state(&mut rand::thread_rng())?;
-        let mut oauth2 = self.0.write().await;
-        let uri =
-            oauth2
-                .adapter
-                .clone()
-                .authorization_url(&mut oauth2.config, &state, scopes, extras)?;
+        let mut config = self.config.write().await;
+        let uri = self
+            .adapter
+            .authorization_url(&mut config, &state, scopes, extras)?;
         cookies.add_private(
-            Cookie::build("oauth2_state", state)
+            Cookie::build(STATE_COOKIE, format!("{} {state}", self.name))
                 .same_site(SameSite::Lax)
                 .finish(),
         );
         Ok(Redirect::to(uri))
     }
 
-    pub async fn refresh(&self, refresh_token: &str, state: &str) -> Result<TokenResponse<K>> {
-        let mut oauth2 = self.0.write().await;
-        oauth2
-            .adapter
-            .clone()
+    /// Check the state the provider sent back against our cookie, then exchange the
+    /// code for a token.
+    pub async fn exchange(
+        &self,
+        cookies: &CookieJar<'_>,
+        code: String,
+        state: &str,
+    ) -> Result<TokenResponse> {
+        match cookies.get_private(STATE_COOKIE) {
+            Some(cookie)
+                if cookie.value().rsplit_once(' ') == Some((self.name.as_str(), state)) =>
+            {
+                cookies.remove_private(cookie)
+            }
+            Some(_) => {
+                tracing::warn!(
+                    "the OAuth2 state returned from {} did not match the stored state.",
+                    self.name
+                );
+                return Err(Error::OAuth2(
+                    "The OAuth2 state returned from the server did not match the stored state"
+                        .into(),
+                ));
+            }
+            None => {
+                tracing::error!(
+                    "The OAuth2 state cookie was missing. It may have been blocked by the client?"
+                );
+                return Err(Error::OAuth2("missing OAuth2 state cookie".into()));
+            }
+        }
+        let mut config = self.config.write().await;
+        self.adapter
+            .exchange_code(&mut config, TokenRequest::AuthorizationCode(code), state)
+            .await
+            .map_err(|e| {
+                tracing::warn!("OAuth2 token exchange failed: {e}");
+                e
+            })
+    }
+
+    pub async fn refresh(&self, refresh_token: &str, state: &str) -> Result<TokenResponse> {
+        let mut config = self.config.write().await;
+        self.adapter
             .exchange_code(
-                &mut oauth2.config,
+                &mut config,
                 TokenRequest::RefreshToken(refresh_token.to_string()),
                 state,
             )
@@ -238,32 +151,11 @@ impl<K: 'static> OAuth2<K> {
     }
 }
 
-#[rocket::async_trait]
-impl<'r, K: 'static> FromRequest<'r> for OAuth2<K> {
-    type Error = ();
-
-    async fn from_request(request: &'r Request<'_>) -> request::Outcome<Self, Self::Error> {
-        request::Outcome::Success(OAuth2(
-            request
-                .rocket()
-                .state::<Arc<RwLock<Shared<K>>>>()
-                .expect("OAuth2 fairing was not attached for this key type!")
-                .clone(),
-        ))
-    }
-}
-
-impl<K: 'static> Sentinel for OAuth2<K> {
-    fn abort(rocket: &Rocket<Ignite>) -> bool {
-        sentinel_abort::<K>(rocket, "OAuth2")
-    }
-}
-
-impl<C: fmt::Debug> fmt::Debug for OAuth2<C> {
+impl fmt::Debug for OAuth2 {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         f.debug_struct("OAuth2")
             .field("adapter", &(..))
-            .field("config", &self.0.blocking_read().config)
+            .field("config", &(..))
             .finish()
     }
 }
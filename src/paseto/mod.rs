@@ -0,0 +1,938 @@
+use std::{
+    fmt,
+    sync::{Arc, RwLock},
+    time::{Duration, Instant},
+};
+
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD as BASE64_URL_SAFE_NO_PAD, Engine};
+use blake2::{
+    digest::{Update, VariableOutput},
+    Blake2bVar,
+};
+use chrono::{DateTime, Utc};
+use diesel::{prelude::*, SqliteConnection};
+use paseto::{tokens::PasetoPublicKey, validate_public_token, PasetoBuilder};
+use ring::{
+    aead::{Aad, LessSafeKey, Nonce, UnboundKey, AES_256_GCM, NONCE_LEN},
+    digest::{digest, SHA256},
+    rand::{SecureRandom, SystemRandom},
+    signature::{Ed25519KeyPair, KeyPair},
+};
+use rocket::{
+    fairing::AdHoc,
+    http::Status,
+    request::{self, FromRequest},
+    Build, Request, Rocket, State,
+};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use serde_json::{Map, Value};
+
+use crate::{
+    api::{Error, Result},
+    dpop, models, schema, MainDatabase,
+};
+
+pub(crate) mod v4;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Version {
+    #[default]
+    V2,
+    V4,
+}
+
+impl Version {
+    fn header(self) -> &'static str {
+        match self {
+            Version::V2 => "v2.public.",
+            Version::V4 => "v4.public.",
+        }
+    }
+
+    fn paserk_prefix(self) -> &'static str {
+        match self {
+            Version::V2 => "k2",
+            Version::V4 => "k4",
+        }
+    }
+
+    /// How the version is stored, e.g. `v4`.
+    fn name(self) -> &'static str {
+        match self {
+            Version::V2 => "v2",
+            Version::V4 => "v4",
+        }
+    }
+
+    fn from_name(name: &str) -> Option<Self> {
+        [Version::V2, Version::V4]
+            .into_iter()
+            .find(|v| v.name() == name)
+    }
+}
+
+#[derive(Debug, Default, Deserialize)]
+struct Config {
+    #[serde(default)]
+    public: String,
+    #[serde(default)]
+    private: String,
+    /// Version newly minted tokens use.
+    #[serde(default)]
+    version: Version,
+    /// Versions accepted when validating; defaults to just `version`. List both while
+    /// migrating from one to the other.
+    accept: Option<Vec<Version>>,
+    /// Mixed into v4 signatures; verifiers need to know it too.
+    #[serde(default)]
+    implicit_assertion: String,
+    /// Encrypts private keys in `signing_keys` (32 hex-encoded bytes).
+    #[serde(default)]
+    storage_key: String,
+}
+
+/// What sealed private keys in `signing_keys` start with. Anything else is a bare hex
+/// key, from before there was a storage key.
+const SEALED_PREFIX: &str = "aes256gcm:";
+
+/// Encrypts private signing keys before they're stored, from `paseto.storage_key`.
+/// The key id is authenticated along with them, so rows can't be swapped around.
+#[derive(Clone)]
+pub struct StorageKey([u8; 32]);
+
+impl fmt::Debug for StorageKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("StorageKey(..)")
+    }
+}
+
+impl StorageKey {
+    pub fn from_hex(key: &str) -> Result<Self> {
+        let key = hex::decode(key).map_err(|why| Error::InvalidSigningKey(format!("{why}")))?;
+        let key = key
+            .try_into()
+            .map_err(|_| Error::InvalidSigningKey("storage key must be 32 bytes".into()))?;
+        Ok(Self(key))
+    }
+
+    /// `paseto.storage_key`, if it's set.
+    pub fn from_figment(figment: &rocket::figment::Figment) -> Result<Option<Self>> {
+        match figment.extract_inner::<String>("paseto.storage_key") {
+            Ok(key) if !key.is_empty() => Self::from_hex(&key).map(Some),
+            _ => Ok(None),
+        }
+    }
+
+    fn key(&self) -> LessSafeKey {
+        LessSafeKey::new(UnboundKey::new(&AES_256_GCM, &self.0).unwrap())
+    }
+
+    fn seal(&self, kid: &str, private: &[u8]) -> Result<String> {
+        let mut nonce = [0; NONCE_LEN];
+        SystemRandom::new()
+            .fill(&mut nonce)
+            .map_err(|_| Error::InvalidSigningKey("failed to generate random data".into()))?;
+        let mut sealed = private.to_vec();
+        self.key()
+            .seal_in_place_append_tag(
+                Nonce::assume_unique_for_key(nonce),
+                Aad::from(kid.as_bytes()),
+                &mut sealed,
+            )
+            .map_err(|_| Error::InvalidSigningKey("failed to seal private key".into()))?;
+        Ok(format!(
+            "{SEALED_PREFIX}{}{}",
+            hex::encode(nonce),
+            hex::encode(sealed)
+        ))
+    }
+
+    fn open(&self, kid: &str, stored: &str) -> Result<Vec<u8>> {
+        let invalid = || Error::InvalidSigningKey(format!("can't open private key {kid}"));
+        let sealed = stored
+            .strip_prefix(SEALED_PREFIX)
+            .and_then(|sealed| hex::decode(sealed).ok())
+            .filter(|sealed| sealed.len() > NONCE_LEN)
+            .ok_or_else(invalid)?;
+        let (nonce, sealed) = sealed.split_at(NONCE_LEN);
+        let nonce = Nonce::try_assume_unique_for_key(nonce).map_err(|_| invalid())?;
+        let mut sealed = sealed.to_vec();
+        let private = self
+            .key()
+            .open_in_place(nonce, Aad::from(kid.as_bytes()), &mut sealed)
+            .map_err(|_| invalid())?;
+        Ok(private.to_vec())
+    }
+}
+
+/// Loads the signing key ring from the database.
+///
+/// Every accepted version has a key pair of its own. If `paseto.version` has no
+/// active key yet, the key pair in `paseto.public`/`paseto.private` is imported, or a
+/// fresh one is generated if that isn't configured either; other versions always get
+/// a fresh one.
+pub fn key_ring() -> AdHoc {
+    async fn fairing(rocket: Rocket<Build>) -> std::result::Result<Rocket<Build>, Rocket<Build>> {
+        let conn = match MainDatabase::get_one(&rocket).await {
+            Some(conn) => conn,
+            None => {
+                tracing::error!("the database fairing must be attached before the key ring");
+                return Err(rocket);
+            }
+        };
+        let config: Config = match rocket.figment().find_value("paseto") {
+            Ok(_) => match rocket.figment().extract_inner("paseto") {
+                Ok(config) => config,
+                Err(why) => {
+                    tracing::error!("invalid paseto configuration: {why}");
+                    return Err(rocket);
+                }
+            },
+            Err(_) => Config::default(),
+        };
+        let storage = match StorageKey::from_figment(rocket.figment()) {
+            Ok(storage) => storage,
+            Err(why) => {
+                tracing::error!("invalid paseto.storage_key: {why}");
+                return Err(rocket);
+            }
+        };
+        if storage.is_none() {
+            tracing::warn!("no paseto.storage_key configured, private keys are stored as is");
+        }
+        let configured = if !config.public.is_empty() && !config.private.is_empty() {
+            match Keypair::from_hex(config.version, &config.public, &config.private) {
+                Ok(kp) => Some(kp),
+                Err(why) => {
+                    tracing::error!("invalid paseto key pair in configuration: {why}");
+                    return Err(rocket);
+                }
+            }
+        } else {
+            None
+        };
+        match KeyRing::load(&conn, configured, config, storage).await {
+            Ok(ring) => Ok(rocket
+                .manage(ring)
+                .attach(AdHoc::on_liftoff("Paseto reload", |rocket| {
+                    Box::pin(reload_every(rocket, RELOAD_INTERVAL))
+                }))),
+            Err(why) => {
+                tracing::error!("can't load signing keys: {why}");
+                Err(rocket)
+            }
+        }
+    }
+    AdHoc::try_on_ignite("Paseto", fairing)
+}
+
+/// An Ed25519 key pair, which only ever signs tokens of one PASETO version.
+#[derive(Debug, Clone)]
+pub struct Keypair {
+    kid: String,
+    version: Version,
+    public: Vec<u8>,
+    private: Vec<u8>,
+}
+
+impl Keypair {
+    fn new(version: Version, public: Vec<u8>, private: Vec<u8>) -> Result<Self> {
+        Ed25519KeyPair::from_seed_and_public_key(&private, &public)
+            .map_err(|why| Error::InvalidSigningKey(format!("{why}")))?;
+        let kid = BASE64_URL_SAFE_NO_PAD.encode(&digest(&SHA256, &public).as_ref()[..16]);
+        Ok(Self {
+            kid,
+            version,
+            public,
+            private,
+        })
+    }
+
+    /// The key pair stored in `row`, opening its private key with `storage` if it's
+    /// sealed.
+    fn from_row(row: &models::SigningKey, storage: Option<&StorageKey>) -> Result<Self> {
+        let version = Version::from_name(&row.version).ok_or_else(|| {
+            Error::InvalidSigningKey(format!(
+                "key {} has unknown version {}",
+                row.kid, row.version
+            ))
+        })?;
+        let public =
+            hex::decode(&row.public).map_err(|why| Error::InvalidSigningKey(format!("{why}")))?;
+        let private = if row.private.starts_with(SEALED_PREFIX) {
+            storage
+                .ok_or_else(|| {
+                    Error::InvalidSigningKey(format!(
+                        "key {} is sealed, but there's no paseto.storage_key",
+                        row.kid
+                    ))
+                })?
+                .open(&row.kid, &row.private)?
+        } else {
+            hex::decode(&row.private).map_err(|why| Error::InvalidSigningKey(format!("{why}")))?
+        };
+        Self::new(version, public, private)
+    }
+
+    pub fn from_hex(version: Version, public: &str, private: &str) -> Result<Self> {
+        let public =
+            hex::decode(public).map_err(|why| Error::InvalidSigningKey(format!("{why}")))?;
+        let private =
+            hex::decode(private).map_err(|why| Error::InvalidSigningKey(format!("{why}")))?;
+        Self::new(version, public, private)
+    }
+
+    pub fn generate(version: Version) -> Result<Self> {
+        let mut seed = [0; 32];
+        SystemRandom::new()
+            .fill(&mut seed)
+            .map_err(|_| Error::InvalidSigningKey("failed to generate random data".into()))?;
+        let kp = Ed25519KeyPair::from_seed_unchecked(&seed)
+            .map_err(|why| Error::InvalidSigningKey(format!("{why}")))?;
+        Self::new(version, kp.public_key().as_ref().to_vec(), seed.to_vec())
+    }
+
+    /// The database id of this key.
+    pub fn kid(&self) -> &str {
+        &self.kid
+    }
+
+    /// The version of the tokens this key signs.
+    pub fn version(&self) -> Version {
+        self.version
+    }
+
+    pub fn public(&self) -> &[u8] {
+        &self.public
+    }
+
+    pub fn ed25519_keypair(&self) -> Ed25519KeyPair {
+        Ed25519KeyPair::from_seed_and_public_key(&self.private, &self.public).unwrap()
+    }
+
+    /// The public key serialized as a PASERK, e.g. `k4.public.<key>`.
+    pub fn paserk(&self) -> String {
+        format!(
+            "{}.public.{}",
+            self.version.paserk_prefix(),
+            BASE64_URL_SAFE_NO_PAD.encode(&self.public)
+        )
+    }
+
+    /// The PASERK identifier of the public key, e.g. `k4.pid.<hash>`.
+    pub fn pid(&self) -> String {
+        let header = format!("{}.pid.", self.version.paserk_prefix());
+        let mut hasher = Blake2bVar::new(33).unwrap();
+        hasher.update(header.as_bytes());
+        hasher.update(self.paserk().as_bytes());
+        let mut hash = [0; 33];
+        hasher.finalize_variable(&mut hash).unwrap();
+        format!("{header}{}", BASE64_URL_SAFE_NO_PAD.encode(hash))
+    }
+
+    fn matches(&self, kid: &str) -> bool {
+        self.kid == kid || self.pid() == kid
+    }
+
+    /// The footer attached to every token signed with this key.
+    fn footer(&self) -> String {
+        serde_json::to_string(&Footer { kid: self.pid() }).unwrap()
+    }
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+struct Footer {
+    kid: String,
+}
+
+/// How often the stored keys are reread, so a key promoted by another instance, or by
+/// `rotate_signing_key`, is picked up without a restart.
+const RELOAD_INTERVAL: Duration = Duration::from_secs(30);
+
+/// How often a token naming a key we don't know can make us reread them early.
+const RELOAD_ON_UNKNOWN_KEY: Duration = Duration::from_secs(1);
+
+/// Reread the stored keys every `interval`, for as long as the server runs.
+async fn reload_every(rocket: &Rocket<rocket::Orbit>, interval: Duration) {
+    let (Some(ring), Some(pool)) = (rocket.state::<KeyRing>(), MainDatabase::pool(rocket)) else {
+        return;
+    };
+    let (shared, storage, pool) = (ring.ring.clone(), ring.storage.clone(), pool.clone());
+    tokio::spawn(async move {
+        loop {
+            tokio::time::sleep(interval).await;
+            let Some(conn) = pool.get().await else {
+                continue;
+            };
+            let reloaded = conn
+                .run(stored_keys)
+                .await
+                .and_then(|rows| replace(&shared, rows, storage.as_ref()));
+            if let Err(why) = reloaded {
+                tracing::warn!("can't reload signing keys: {why}");
+            }
+        }
+    });
+}
+
+/// Every stored key, newest first.
+fn stored_keys(c: &mut SqliteConnection) -> Result<Vec<models::SigningKey>> {
+    use schema::signing_keys::dsl;
+    Ok(dsl::signing_keys.order(dsl::created_at.desc()).load(c)?)
+}
+
+/// Stored keys, sorted out.
+struct Stored {
+    /// At most one per version.
+    active: Vec<Keypair>,
+    retired: Vec<Keypair>,
+    /// Those with private keys stored as is.
+    unsealed: Vec<Keypair>,
+}
+
+impl Stored {
+    fn from_rows(rows: Vec<models::SigningKey>, storage: Option<&StorageKey>) -> Result<Self> {
+        let mut stored = Stored {
+            active: vec![],
+            retired: Vec::with_capacity(rows.len()),
+            unsealed: vec![],
+        };
+        for row in rows {
+            let kp = Keypair::from_row(&row, storage)?;
+            if !row.private.starts_with(SEALED_PREFIX) {
+                stored.unsealed.push(kp.clone());
+            }
+            if row.active && !stored.active.iter().any(|a| a.version == kp.version) {
+                stored.active.push(kp);
+            } else {
+                stored.retired.push(kp);
+            }
+        }
+        Ok(stored)
+    }
+}
+
+/// Swap in the keys just read from `rows`.
+fn replace(
+    ring: &RwLock<Ring>,
+    rows: Vec<models::SigningKey>,
+    storage: Option<&StorageKey>,
+) -> Result {
+    let Stored {
+        mut active,
+        retired,
+        ..
+    } = Stored::from_rows(rows, storage)?;
+    let mut ring = ring.write().unwrap();
+    // every version in use keeps an active key, even if its row went missing
+    for kp in &ring.active {
+        if !active.iter().any(|a| a.version == kp.version) {
+            active.push(kp.clone());
+        }
+    }
+    *ring = Ring {
+        active,
+        retired,
+        loaded_at: Instant::now(),
+    };
+    Ok(())
+}
+
+/// One active signing key per version, plus the retired keys that tokens may still be
+/// signed with. Every instance rereads them from the database now and then, see
+/// [`RELOAD_INTERVAL`].
+#[derive(Debug)]
+pub struct KeyRing {
+    ring: Arc<RwLock<Ring>>,
+    version: Version,
+    accept: Vec<Version>,
+    implicit_assertion: Vec<u8>,
+    storage: Option<StorageKey>,
+}
+
+#[derive(Debug)]
+struct Ring {
+    /// At most one per version.
+    active: Vec<Keypair>,
+    retired: Vec<Keypair>,
+    loaded_at: Instant,
+}
+
+impl KeyRing {
+    async fn load(
+        conn: &MainDatabase,
+        configured: Option<Keypair>,
+        config: Config,
+        storage: Option<StorageKey>,
+    ) -> Result<Self> {
+        let rows = conn.run(stored_keys).await?;
+        let Stored {
+            mut active,
+            retired,
+            unsealed,
+        } = Stored::from_rows(rows, storage.as_ref())?;
+        let accept = config.accept.unwrap_or_else(|| vec![config.version]);
+        let mut versions = accept.clone();
+        versions.push(config.version);
+        let mut configured = configured;
+        for version in versions {
+            if active.iter().any(|kp| kp.version == version) {
+                continue;
+            }
+            let kp = match configured.take().filter(|kp| kp.version == version) {
+                Some(kp) => kp,
+                None => Keypair::generate(version)?,
+            };
+            persist(conn, &kp, storage.clone()).await?;
+            active.push(kp);
+        }
+        // keys stored before there was a storage key get sealed as soon as there is one
+        if let Some(storage) = storage.clone().filter(|_| !unsealed.is_empty()) {
+            tracing::info!("sealing {} stored private key(s)", unsealed.len());
+            conn.run(move |c| {
+                use schema::signing_keys::dsl;
+                for kp in unsealed {
+                    let sealed = storage.seal(&kp.kid, &kp.private)?;
+                    diesel::update(dsl::signing_keys.find(&kp.kid))
+                        .set(dsl::private.eq(sealed))
+                        .execute(c)?;
+                }
+                Ok::<_, Error>(())
+            })
+            .await?;
+        }
+        for kp in &active {
+            tracing::info!("{:?} key {}", kp.version, kp.pid());
+        }
+        tracing::info!(
+            "signing {:?} tokens, {} retired key(s)",
+            config.version,
+            retired.len()
+        );
+        Ok(Self {
+            ring: Arc::new(RwLock::new(Ring {
+                active,
+                retired,
+                loaded_at: Instant::now(),
+            })),
+            version: config.version,
+            accept,
+            implicit_assertion: config.implicit_assertion.into_bytes(),
+            storage,
+        })
+    }
+
+    /// The version newly minted tokens use.
+    pub fn version(&self) -> Version {
+        self.version
+    }
+
+    /// The versions accepted when validating.
+    pub fn accepted(&self) -> &[Version] {
+        &self.accept
+    }
+
+    /// The key newly minted tokens are signed with.
+    pub fn active(&self) -> Keypair {
+        self.ring
+            .read()
+            .unwrap()
+            .active
+            .iter()
+            .find(|kp| kp.version == self.version)
+            .cloned()
+            .expect("every version in use has an active key")
+    }
+
+    /// The active key of every version in use.
+    pub fn active_keys(&self) -> Vec<Keypair> {
+        self.ring.read().unwrap().active.clone()
+    }
+
+    pub fn retired(&self) -> Vec<Keypair> {
+        self.ring.read().unwrap().retired.clone()
+    }
+
+    /// Look up a verification key by its key id or PASERK identifier.
+    pub fn get(&self, kid: &str) -> Option<Keypair> {
+        let ring = self.ring.read().unwrap();
+        ring.active
+            .iter()
+            .chain(ring.retired.iter())
+            .find(|kp| kp.matches(kid))
+            .cloned()
+    }
+
+    /// Reread the stored keys, unless they were read less than `age` ago.
+    pub async fn reload(&self, conn: &MainDatabase, age: Duration) -> Result {
+        if self.ring.read().unwrap().loaded_at.elapsed() < age {
+            return Ok(());
+        }
+        let rows = conn.run(stored_keys).await?;
+        replace(&self.ring, rows, self.storage.as_ref())
+    }
+
+    /// Verify a token like [`KeyRing::verify`], rereading the stored keys first if it
+    /// names one we don't know, in case another instance just promoted it.
+    pub async fn verify_fresh(&self, conn: &MainDatabase, tok: &str) -> Result<Value> {
+        let unknown = footer_kid(tok).map_or(false, |kid| self.get(&kid).is_none());
+        if unknown {
+            self.reload(conn, RELOAD_ON_UNKNOWN_KEY).await?;
+        }
+        self.verify(tok)
+    }
+
+    /// Make `kp` the active key for its version, keeping the previous one around for
+    /// verification. Other instances pick it up when they next reread the stored keys.
+    pub async fn promote(&self, conn: &MainDatabase, kp: Keypair) -> Result {
+        persist(conn, &kp, self.storage.clone()).await?;
+        let mut ring = self.ring.write().unwrap();
+        match ring.active.iter().position(|a| a.version == kp.version) {
+            Some(i) => {
+                let previous = std::mem::replace(&mut ring.active[i], kp);
+                ring.retired.insert(0, previous);
+            }
+            None => ring.active.push(kp),
+        }
+        Ok(())
+    }
+
+    /// Sign `claims` with the active key, as the configured version.
+    pub fn sign(&self, claims: &Map<String, Value>) -> Result<String> {
+        let signing_key = self.active();
+        let kp = signing_key.ed25519_keypair();
+        let footer = signing_key.footer();
+        match self.version {
+            Version::V2 => {
+                let mut base = PasetoBuilder::new();
+                let mut builder = base.set_ed25519_key(&kp).set_footer(&footer);
+                for (key, value) in claims {
+                    builder = builder.set_claim(key, value.clone());
+                }
+                builder.build().map_err(|why| {
+                    tracing::error!("can't make paseto: {why}");
+                    Error::PasetoCreationError(format!("{why}"))
+                })
+            }
+            Version::V4 => {
+                let message = serde_json::to_vec(claims)?;
+                Ok(v4::sign(
+                    &kp,
+                    &message,
+                    footer.as_bytes(),
+                    &self.implicit_assertion,
+                ))
+            }
+        }
+    }
+
+    /// Verify a token, picking the key named in its footer.
+    pub fn verify(&self, tok: &str) -> Result<Value> {
+        let version = [Version::V2, Version::V4]
+            .into_iter()
+            .find(|v| tok.starts_with(v.header()))
+            .ok_or_else(|| Error::PasetoValidationError("unknown token type".into()))?;
+        if !self.accept.contains(&version) {
+            return Err(Error::PasetoValidationError(format!(
+                "{version:?} tokens are not accepted"
+            )));
+        }
+        let footer = footer(tok);
+        let validate = |kp: &Keypair, footer: Option<&str>| match version {
+            Version::V2 => validate_public_token(
+                tok,
+                footer,
+                &PasetoPublicKey::ED25519PublicKey(kp.public()),
+                &paseto::TimeBackend::Chrono,
+            )
+            .map_err(|why| Error::PasetoValidationError(why.to_string())),
+            Version::V4 => v4::verify(
+                kp.public(),
+                tok,
+                footer.unwrap_or_default().as_bytes(),
+                &self.implicit_assertion,
+            ),
+        };
+        match footer {
+            Some(footer) => {
+                let Footer { kid } = serde_json::from_str(&footer)
+                    .map_err(|_| Error::PasetoValidationError("malformed footer".into()))?;
+                let kp = self
+                    .get(&kid)
+                    .filter(|kp| kp.version == version)
+                    .ok_or_else(|| Error::PasetoValidationError(format!("unknown key id {kid}")))?;
+                validate(&kp, Some(&footer))
+            }
+            // tokens minted before key ids were introduced
+            None => {
+                let ring = self.ring.read().unwrap();
+                ring.active
+                    .iter()
+                    .chain(ring.retired.iter())
+                    .filter(|kp| kp.version == version)
+                    .find_map(|kp| validate(kp, None).ok())
+                    .ok_or_else(|| {
+                        Error::PasetoValidationError("no key could verify this token".into())
+                    })
+            }
+        }
+    }
+}
+
+/// The footer of `tok`, if it has one.
+fn footer(tok: &str) -> Option<String> {
+    tok.splitn(4, '.')
+        .nth(3)
+        .and_then(|f| BASE64_URL_SAFE_NO_PAD.decode(f).ok())
+        .and_then(|f| String::from_utf8(f).ok())
+}
+
+/// The key id in the footer of `tok`, if it names one.
+fn footer_kid(tok: &str) -> Option<String> {
+    let Footer { kid } = serde_json::from_str(&footer(tok)?).ok()?;
+    Some(kid)
+}
+
+/// Store `kp` as the active signing key for its version, retiring the current one.
+/// Its private key is sealed with `storage`, if there is one.
+///
+/// This is also what the `rotate_signing_key` command uses. Running servers start
+/// signing with the new key when they next reread the stored keys.
+pub fn store(c: &mut SqliteConnection, kp: &Keypair, storage: Option<&StorageKey>) -> Result {
+    let now = Utc::now().to_rfc3339();
+    let private = match storage {
+        Some(storage) => storage.seal(&kp.kid, &kp.private)?,
+        None => hex::encode(&kp.private),
+    };
+    let row = models::SigningKey {
+        kid: kp.kid.clone(),
+        public: hex::encode(&kp.public),
+        private,
+        created_at: now.clone(),
+        retired_at: None,
+        active: true,
+        version: kp.version.name().to_string(),
+    };
+    use schema::signing_keys::dsl;
+    c.transaction(|c| {
+        diesel::update(
+            dsl::signing_keys
+                .filter(dsl::active.eq(true))
+                .filter(dsl::version.eq(&row.version)),
+        )
+        .set(&models::RetireSigningKey {
+            retired_at: Some(now),
+            active: false,
+        })
+        .execute(c)?;
+        diesel::insert_into(dsl::signing_keys)
+            .values(&row)
+            .execute(c)
+    })?;
+    Ok(())
+}
+
+async fn persist(conn: &MainDatabase, kp: &Keypair, storage: Option<StorageKey>) -> Result {
+    let kp = kp.clone();
+    conn.run(move |c| store(c, &kp, storage.as_ref())).await
+}
+
+/// Scope granting unrestricted access to the token API.
+pub const ADMIN_SCOPE: &str = "admin";
+
+/// Claims only the server may set. Anything else has to be namespaced.
+pub const RESERVED_CLAIMS: &[&str] = &[
+    "iss",
+    "sub",
+    "aud",
+    "exp",
+    "nbf",
+    "iat",
+    "jti",
+    "scopes",
+    "parent",
+    "client_id",
+    "me",
+    "cnf",
+    "act",
+];
+
+/// Check that `name` can be used for a custom claim: it has to look like
+/// `namespace:name` (a URL will do) and not shadow a registered claim.
+pub fn check_custom_claim(name: &str) -> Result {
+    if RESERVED_CLAIMS.contains(&name) {
+        return Err(Error::Forbidden(format!("claim {name} is reserved")));
+    }
+    match name.split_once(':') {
+        Some((ns, claim)) if !ns.is_empty() && !claim.is_empty() => Ok(()),
+        _ => Err(Error::Forbidden(format!(
+            "custom claim {name} must be namespaced"
+        ))),
+    }
+}
+
+/// Who is acting on behalf of the subject of an exchanged token, per RFC 8693
+/// section 4.1. Nested when a token is exchanged more than once.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct Actor {
+    pub sub: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub act: Option<Box<Actor>>,
+}
+
+#[derive(Debug, Deserialize, Serialize, Default)]
+pub struct Token {
+    pub jti: String,
+    pub sub: String,
+    pub aud: String,
+    pub iss: String,
+    pub iat: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub exp: Option<String>,
+    pub scopes: Option<Vec<String>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub parent: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub client_id: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub me: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub cnf: Option<dpop::Confirmation>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub act: Option<Actor>,
+    /// Custom claims, see [`Token::claim`].
+    #[serde(flatten)]
+    pub extra: Map<String, Value>,
+}
+
+impl Token {
+    /// Get a custom claim, if it's present and has the expected shape.
+    pub fn claim<T: DeserializeOwned>(&self, name: &str) -> Result<Option<T>> {
+        self.extra
+            .get(name)
+            .map(|val| T::deserialize(val).map_err(Error::Json))
+            .transpose()
+    }
+
+    pub fn scopes(&self) -> &[String] {
+        self.scopes.as_deref().unwrap_or_default()
+    }
+
+    pub fn has_scope(&self, scope: &str) -> bool {
+        self.scopes().iter().any(|s| s == scope)
+    }
+
+    pub fn is_admin(&self) -> bool {
+        self.has_scope(ADMIN_SCOPE)
+    }
+
+    pub fn expires_at(&self) -> Option<DateTime<Utc>> {
+        self.exp
+            .as_deref()
+            .and_then(|exp| DateTime::parse_from_rfc3339(exp).ok())
+            .map(|exp| exp.with_timezone(&Utc))
+    }
+}
+
+/// Check that a bound token came with a proof from its key, and that an unbound one
+/// wasn't presented as `DPoP`.
+fn check_binding(request: &Request<'_>, raw: &str, tok: &Token, dpop_scheme: bool) -> Result {
+    match (&tok.cnf, dpop_scheme) {
+        (Some(cnf), true) => {
+            let proofs = request.headers().get("DPoP").collect::<Vec<_>>();
+            let proof = match proofs.as_slice() {
+                [proof] => proof,
+                _ => return Err(Error::InvalidDpopProof("expected exactly one proof".into())),
+            };
+            let dpop = request
+                .rocket()
+                .state::<dpop::Dpop>()
+                .expect("DPoP fairing attached");
+            if dpop.verify(request, proof, Some(raw))? != cnf.jkt {
+                return Err(Error::InvalidDpopProof(
+                    "proof is for a different key".into(),
+                ));
+            }
+            Ok(())
+        }
+        (Some(_), false) => Err(Error::InvalidDpopProof("token is DPoP-bound".into())),
+        (None, true) => Err(Error::InvalidDpopProof("token is not DPoP-bound".into())),
+        (None, false) => Ok(()),
+    }
+}
+
+async fn validate(request: &Request<'_>, authorization: &str) -> request::Outcome<Token, Error> {
+    let (raw, dpop_scheme) = match authorization.split_once(' ') {
+        Some(("Bearer", raw)) => (raw, false),
+        Some(("DPoP", raw)) => (raw, true),
+        _ => (authorization, false),
+    };
+    let ring = request.guard::<&State<KeyRing>>().await.unwrap();
+    let db = match request.guard::<MainDatabase>().await {
+        request::Outcome::Success(db) => db,
+        _ => {
+            return request::Outcome::Failure((
+                Status::ServiceUnavailable,
+                Error::OAuth2("database unavailable".into()),
+            ))
+        }
+    };
+    let tok: Token = match ring.verify_fresh(&db, raw).await {
+        Ok(val) => match serde_json::from_value(val) {
+            Ok(tok) => tok,
+            Err(why) => return request::Outcome::Failure((Status::Unauthorized, Error::Json(why))),
+        },
+        Err(why) => return request::Outcome::Failure((Status::Unauthorized, why)),
+    };
+    if let Err(why) = check_binding(request, raw, &tok, dpop_scheme) {
+        return request::Outcome::Failure((Status::Unauthorized, why));
+    }
+    match check_valid(&db, tok.jti.clone()).await {
+        Ok(()) => request::Outcome::Success(tok),
+        Err(why @ Error::Database(_)) => {
+            request::Outcome::Failure((Status::InternalServerError, why))
+        }
+        Err(why) => request::Outcome::Failure((Status::Unauthorized, why)),
+    }
+}
+
+/// Check that a token we issued hasn't been revoked since.
+async fn check_valid(db: &MainDatabase, jti: String) -> Result {
+    let valid = db
+        .run(move |c| {
+            use schema::tokens::dsl;
+            dsl::tokens
+                .find(&jti)
+                .select(dsl::valid)
+                .first::<Option<i32>>(c)
+                .optional()
+        })
+        .await?;
+    match valid {
+        Some(Some(0)) | None => Err(Error::TokenRevoked),
+        Some(_) => Ok(()),
+    }
+}
+
+/// Verify a token passed around outside the `Authorization` header, e.g. as the
+/// subject of a token exchange. DPoP bindings are up to the caller, see `cnf`.
+pub async fn verify_token(db: &MainDatabase, ring: &KeyRing, raw: &str) -> Result<Token> {
+    let tok: Token = serde_json::from_value(ring.verify_fresh(db, raw).await?)?;
+    check_valid(db, tok.jti.clone()).await?;
+    Ok(tok)
+}
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for Token {
+    type Error = crate::api::Error;
+
+    async fn from_request(request: &'r Request<'_>) -> request::Outcome<Self, Self::Error> {
+        // browsers get a session cookie instead, see `crate::session`
+        let keys = request.headers().get("Authorization").collect::<Vec<_>>();
+        match keys.len() {
+            1 => validate(request, keys[0]).await,
+            _ => request::Outcome::Failure((Status::Unauthorized, Error::NoPasetoInRequest)),
+        }
+    }
+}
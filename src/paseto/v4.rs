@@ -0,0 +1,434 @@
+//! `v4.public` and `v4.local` tokens.
+//!
+//! The `paseto` crate stops at v2, but v4.public is the same Ed25519 construction
+//! with an implicit assertion mixed into the signature, and v4.local is XChaCha20
+//! with a keyed BLAKE2b MAC, so they're done by hand here.
+
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD as BASE64_URL_SAFE_NO_PAD, Engine};
+use blake2::{
+    digest::{
+        consts::{U32, U56},
+        Mac,
+    },
+    Blake2bMac,
+};
+use chacha20::{
+    cipher::{KeyIvInit, StreamCipher},
+    XChaCha20,
+};
+use chrono::{DateTime, Utc};
+use ring::{
+    rand::{SecureRandom, SystemRandom},
+    signature::{Ed25519KeyPair, UnparsedPublicKey, ED25519},
+};
+use serde_json::Value;
+
+use crate::api::{Error, Result};
+
+const HEADER: &str = "v4.public.";
+const LOCAL_HEADER: &str = "v4.local.";
+
+fn invalid(why: &str) -> Error {
+    Error::PasetoValidationError(why.to_string())
+}
+
+fn le64(n: u64) -> [u8; 8] {
+    (n & (u64::MAX >> 1)).to_le_bytes()
+}
+
+/// Pre-authentication encoding, as defined by the PASETO spec.
+fn pae(pieces: &[&[u8]]) -> Vec<u8> {
+    let mut out = le64(pieces.len() as u64).to_vec();
+    for piece in pieces {
+        out.extend_from_slice(&le64(piece.len() as u64));
+        out.extend_from_slice(piece);
+    }
+    out
+}
+
+pub fn sign(kp: &Ed25519KeyPair, message: &[u8], footer: &[u8], implicit: &[u8]) -> String {
+    let sig = kp.sign(&pae(&[HEADER.as_bytes(), message, footer, implicit]));
+    let mut body = message.to_vec();
+    body.extend_from_slice(sig.as_ref());
+    let mut token = format!("{HEADER}{}", BASE64_URL_SAFE_NO_PAD.encode(body));
+    if !footer.is_empty() {
+        token.push('.');
+        token.push_str(&BASE64_URL_SAFE_NO_PAD.encode(footer));
+    }
+    token
+}
+
+pub fn verify(public: &[u8], token: &str, footer: &[u8], implicit: &[u8]) -> Result<Value> {
+    claims(&verify_message(public, token, footer, implicit)?)
+}
+
+/// The message a v4.public token carries, once its signature checks out.
+fn verify_message(public: &[u8], token: &str, footer: &[u8], implicit: &[u8]) -> Result<Vec<u8>> {
+    let rest = token
+        .strip_prefix(HEADER)
+        .ok_or_else(|| invalid("not a v4.public token"))?;
+    let (body, token_footer) = match rest.split_once('.') {
+        Some((body, footer)) => (body, Some(footer)),
+        None => (rest, None),
+    };
+    let token_footer = token_footer
+        .map(|f| BASE64_URL_SAFE_NO_PAD.decode(f))
+        .transpose()
+        .map_err(|_| invalid("malformed footer"))?
+        .unwrap_or_default();
+    if token_footer != footer {
+        return Err(invalid("footer mismatch"));
+    }
+    let body = BASE64_URL_SAFE_NO_PAD
+        .decode(body)
+        .map_err(|_| invalid("malformed payload"))?;
+    if body.len() < 64 {
+        return Err(invalid("payload too short"));
+    }
+    let (message, sig) = body.split_at(body.len() - 64);
+    UnparsedPublicKey::new(&ED25519, public)
+        .verify(&pae(&[HEADER.as_bytes(), message, footer, implicit]), sig)
+        .map_err(|_| invalid("invalid signature"))?;
+    Ok(message.to_vec())
+}
+
+/// The encryption key and XChaCha20 nonce for the token with nonce `n`, and the MAC
+/// that authenticates it.
+fn split_key(key: &[u8], n: &[u8]) -> Result<(Vec<u8>, Vec<u8>, Blake2bMac<U32>)> {
+    let invalid_key = || Error::InvalidSigningKey("v4.local keys are 32 bytes".into());
+    if key.len() != 32 {
+        return Err(invalid_key());
+    }
+    let mut ek = Blake2bMac::<U56>::new_from_slice(key).map_err(|_| invalid_key())?;
+    ek.update(b"paseto-encryption-key");
+    ek.update(n);
+    let ek = ek.finalize().into_bytes();
+    let mut ak = Blake2bMac::<U32>::new_from_slice(key).map_err(|_| invalid_key())?;
+    ak.update(b"paseto-auth-key-for-aead");
+    ak.update(n);
+    let mac = Blake2bMac::<U32>::new_from_slice(&ak.finalize().into_bytes())
+        .map_err(|_| invalid_key())?;
+    let (ek, n2) = ek.split_at(32);
+    Ok((ek.to_vec(), n2.to_vec(), mac))
+}
+
+pub fn encrypt(key: &[u8], message: &[u8], footer: &[u8], implicit: &[u8]) -> Result<String> {
+    let mut n = [0; 32];
+    SystemRandom::new()
+        .fill(&mut n)
+        .map_err(|_| Error::PasetoCreationError("failed to generate random data".into()))?;
+    encrypt_with_nonce(key, &n, message, footer, implicit)
+}
+
+fn encrypt_with_nonce(
+    key: &[u8],
+    n: &[u8; 32],
+    message: &[u8],
+    footer: &[u8],
+    implicit: &[u8],
+) -> Result<String> {
+    let (ek, n2, mut ak) = split_key(key, n)?;
+    let mut c = message.to_vec();
+    XChaCha20::new_from_slices(&ek, &n2)
+        .unwrap()
+        .apply_keystream(&mut c);
+    ak.update(&pae(&[LOCAL_HEADER.as_bytes(), n, &c, footer, implicit]));
+    let mut body = n.to_vec();
+    body.extend_from_slice(&c);
+    body.extend_from_slice(&ak.finalize().into_bytes());
+    let mut token = format!("{LOCAL_HEADER}{}", BASE64_URL_SAFE_NO_PAD.encode(body));
+    if !footer.is_empty() {
+        token.push('.');
+        token.push_str(&BASE64_URL_SAFE_NO_PAD.encode(footer));
+    }
+    Ok(token)
+}
+
+pub fn decrypt(key: &[u8], token: &str, footer: &[u8], implicit: &[u8]) -> Result<Value> {
+    claims(&decrypt_message(key, token, footer, implicit)?)
+}
+
+/// The message a v4.local token carries, once its tag checks out.
+fn decrypt_message(key: &[u8], token: &str, footer: &[u8], implicit: &[u8]) -> Result<Vec<u8>> {
+    let rest = token
+        .strip_prefix(LOCAL_HEADER)
+        .ok_or_else(|| invalid("not a v4.local token"))?;
+    let (body, token_footer) = match rest.split_once('.') {
+        Some((body, footer)) => (body, Some(footer)),
+        None => (rest, None),
+    };
+    let token_footer = token_footer
+        .map(|f| BASE64_URL_SAFE_NO_PAD.decode(f))
+        .transpose()
+        .map_err(|_| invalid("malformed footer"))?
+        .unwrap_or_default();
+    if token_footer != footer {
+        return Err(invalid("footer mismatch"));
+    }
+    let body = BASE64_URL_SAFE_NO_PAD
+        .decode(body)
+        .map_err(|_| invalid("malformed payload"))?;
+    if body.len() < 64 {
+        return Err(invalid("payload too short"));
+    }
+    let (n, rest) = body.split_at(32);
+    let (c, t) = rest.split_at(rest.len() - 32);
+    let (ek, n2, mut ak) = split_key(key, n)?;
+    ak.update(&pae(&[LOCAL_HEADER.as_bytes(), n, c, footer, implicit]));
+    ak.verify_slice(t)
+        .map_err(|_| invalid("invalid authentication tag"))?;
+    let mut message = c.to_vec();
+    XChaCha20::new_from_slices(&ek, &n2)
+        .unwrap()
+        .apply_keystream(&mut message);
+    Ok(message)
+}
+
+fn claims(message: &[u8]) -> Result<Value> {
+    let claims: Value = serde_json::from_slice(message).map_err(|_| invalid("malformed claims"))?;
+    check_times(&claims)?;
+    Ok(claims)
+}
+
+/// The `paseto` crate does this for v2 tokens; v4 needs it done here.
+fn check_times(claims: &Value) -> Result {
+    let now = Utc::now();
+    let time = |name: &str| {
+        claims
+            .get(name)
+            .and_then(Value::as_str)
+            .map(|t| {
+                DateTime::parse_from_rfc3339(t).map_err(|_| invalid(&format!("malformed {name}")))
+            })
+            .transpose()
+    };
+    if matches!(time("exp")?, Some(exp) if exp < now) {
+        return Err(invalid("token has expired"));
+    }
+    if matches!(time("nbf")?, Some(nbf) if nbf > now) {
+        return Err(invalid("token is not valid yet"));
+    }
+    if matches!(time("iat")?, Some(iat) if iat > now) {
+        return Err(invalid("token was issued in the future"));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    //! The official vectors, from https://github.com/paseto-standard/test-vectors.
+    //! Their claims expired in 2022, so these check the messages, not the claims.
+
+    use super::*;
+
+    const LOCAL_KEY: &str = "707172737475767778797a7b7c7d7e7f808182838485868788898a8b8c8d8e8f";
+    const NONCE: &str = "26f7553354482a1d91d4784627854b8da6b8042a7966523c2b404e8dbbe7f7f2";
+    const SECRET_KEY: &str = "b4cbfb43df4ce210727d953e4a713307fa19bb7d9f85041438d9e11b942a3774\
+                              1eb9dbbbbc047c03fd70604e0071f0987e16b28b757225c11f00415d0e20b1a2";
+    const PUBLIC_KEY: &str = "1eb9dbbbbc047c03fd70604e0071f0987e16b28b757225c11f00415d0e20b1a2";
+
+    const SECRET: &str = r#"{"data":"this is a secret message","exp":"2022-01-01T00:00:00+00:00"}"#;
+    const HIDDEN: &str = r#"{"data":"this is a hidden message","exp":"2022-01-01T00:00:00+00:00"}"#;
+    const SIGNED: &str = r#"{"data":"this is a signed message","exp":"2022-01-01T00:00:00+00:00"}"#;
+    const KID: &str = r#"{"kid":"zVhMiPBP9fRf2snEcT7gFTioeA9COcNy9DfgL1W60haN"}"#;
+
+    /// `(name, nonce, payload, footer, implicit, token)`
+    const LOCAL: &[(&str, Option<&str>, &str, &str, &str, &str)] = &[
+        (
+            "4-E-1",
+            None,
+            SECRET,
+            "",
+            "",
+            "v4.local.AAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAQAr68PS4AXe7If_ZgesdkUMvSwscFlAl1pk5HC0e8kApeaqMfGo_7OpBnwJOAbY9V7WU6abu74MmcUE8YWAiaArVI8XJ5hOb_4v9RmDkneN0S92dx0OW4pgy7omxgf3S8c3LlQg",
+        ),
+        (
+            "4-E-2",
+            None,
+            HIDDEN,
+            "",
+            "",
+            "v4.local.AAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAQAr68PS4AXe7If_ZgesdkUMvS2csCgglvpk5HC0e8kApeaqMfGo_7OpBnwJOAbY9V7WU6abu74MmcUE8YWAiaArVI8XIemu9chy3WVKvRBfg6t8wwYHK0ArLxxfZP73W_vfwt5A",
+        ),
+        (
+            "4-E-3",
+            Some(NONCE),
+            SECRET,
+            "",
+            "",
+            "v4.local.JvdVM1RIKh2R1HhGJ4VLjaa4BCp5ZlI8K0BOjbvn9_L6qU34Aj806z9BHW68MiMIOL-WkS5pimduKSmcwEtx3ksEnMJnnMvZUScQKTvmZyxuKxT3L9IjiRh_2vdM-ac-tvG3LB6V6O_cKswZ1kK-vBsCO-WG6r5-xhqj0J73IogDuxnNWA",
+        ),
+        (
+            "4-E-4",
+            Some(NONCE),
+            HIDDEN,
+            "",
+            "",
+            "v4.local.JvdVM1RIKh2R1HhGJ4VLjaa4BCp5ZlI8K0BOjbvn9_L6qU34Aj806z9BHW68MiMIOL-WiiJunGd0KSmcwEtx3ksEnMJnnMvZUScQKTvmZyxuKxT3L9IjiRh_2vdM-ac-tvG3LB7Tel74ti0JFn6skilnLGyub72L5SFRUegCvR2efmjcuQ",
+        ),
+        (
+            "4-E-5",
+            Some(NONCE),
+            SECRET,
+            KID,
+            "",
+            "v4.local.JvdVM1RIKh2R1HhGJ4VLjaa4BCp5ZlI8K0BOjbvn9_L6qU34Aj806z9BHW68MiMIOL-WkS5pimduKSmcwEtx3ksEnMJnnMvZUScQKTvmZyxuKxT3L9IjiRh_2vdM-ac-tvG3LB4SjEkrlyKT9n2hlPhtLNi1CB9fsgS12-7n9paSy5-VNA.eyJraWQiOiJ6VmhNaVBCUDlmUmYyc25FY1Q3Z0ZUaW9lQTlDT2NOeTlEZmdMMVc2MGhhTiJ9",
+        ),
+        (
+            "4-E-6",
+            Some(NONCE),
+            HIDDEN,
+            KID,
+            "",
+            "v4.local.JvdVM1RIKh2R1HhGJ4VLjaa4BCp5ZlI8K0BOjbvn9_L6qU34Aj806z9BHW68MiMIOL-WiiJunGd0KSmcwEtx3ksEnMJnnMvZUScQKTvmZyxuKxT3L9IjiRh_2vdM-ac-tvG3LB5ozczLb9MElasx3uIJ8cWCoKb4fN5i_xeLm5gnDzBytQ.eyJraWQiOiJ6VmhNaVBCUDlmUmYyc25FY1Q3Z0ZUaW9lQTlDT2NOeTlEZmdMMVc2MGhhTiJ9",
+        ),
+        (
+            "4-E-7",
+            Some(NONCE),
+            SECRET,
+            KID,
+            r#"{"test-vector":"4-E-7"}"#,
+            "v4.local.JvdVM1RIKh2R1HhGJ4VLjaa4BCp5ZlI8K0BOjbvn9_L6qU34Aj806z9BHW68MiMIOL-WkS5pimduKSmcwEtx3ksEnMJnnMvZUScQKTvmZyxuKxT3L9IjiRh_2vdM-ac-tvG3LB64Pgn6u99CSqTpbb91bQLjP2PLVowa4BWIJ54bpxlIoQ.eyJraWQiOiJ6VmhNaVBCUDlmUmYyc25FY1Q3Z0ZUaW9lQTlDT2NOeTlEZmdMMVc2MGhhTiJ9",
+        ),
+        (
+            "4-E-8",
+            Some(NONCE),
+            HIDDEN,
+            KID,
+            r#"{"test-vector":"4-E-8"}"#,
+            "v4.local.JvdVM1RIKh2R1HhGJ4VLjaa4BCp5ZlI8K0BOjbvn9_L6qU34Aj806z9BHW68MiMIOL-WiiJunGd0KSmcwEtx3ksEnMJnnMvZUScQKTvmZyxuKxT3L9IjiRh_2vdM-ac-tvG3LB5u0uIyC1GjzGidJQE-sOJDoBPmaOC5JJoUBG142uj_rQ.eyJraWQiOiJ6VmhNaVBCUDlmUmYyc25FY1Q3Z0ZUaW9lQTlDT2NOeTlEZmdMMVc2MGhhTiJ9",
+        ),
+        (
+            "4-E-9",
+            Some(NONCE),
+            HIDDEN,
+            "arbitrary-string-that-isn't-json",
+            r#"{"test-vector":"4-E-9"}"#,
+            "v4.local.JvdVM1RIKh2R1HhGJ4VLjaa4BCp5ZlI8K0BOjbvn9_L6qU34Aj806z9BHW68MiMIOL-WiiJunGd0KSmcwEtx3ksEnMJnnMvZUScQKTvmZyxuKxT3L9IjiRh_2vdM-ac-tvG3LB6sb_p-PWZXBxTiDXqMtlpfbH5t4p0xFrWajPPCnKCHUw.YXJiaXRyYXJ5LXN0cmluZy10aGF0LWlzbid0LWpzb24",
+        ),
+    ];
+
+    /// `(name, footer, implicit, token)`, all signing [`SIGNED`].
+    const PUBLIC: &[(&str, &str, &str, &str)] = &[
+        (
+            "4-S-1",
+            "",
+            "",
+            "v4.public.eyJkYXRhIjoidGhpcyBpcyBhIHNpZ25lZCBtZXNzYWdlIiwiZXhwIjoiMjAyMi0wMS0wMVQwMDowMDowMCswMDowMCJ9bg_XBBzds8lTZShVlwwKSgeKpLT3yukTw6JUz3W4h_ExsQV-P0V54zemZDcAxFaSeef1QlXEFtkqxT1ciiQEDA",
+        ),
+        (
+            "4-S-2",
+            KID,
+            "",
+            "v4.public.eyJkYXRhIjoidGhpcyBpcyBhIHNpZ25lZCBtZXNzYWdlIiwiZXhwIjoiMjAyMi0wMS0wMVQwMDowMDowMCswMDowMCJ9v3Jt8mx_TdM2ceTGoqwrh4yDFn0XsHvvV_D0DtwQxVrJEBMl0F2caAdgnpKlt4p7xBnx1HcO-SPo8FPp214HDw.eyJraWQiOiJ6VmhNaVBCUDlmUmYyc25FY1Q3Z0ZUaW9lQTlDT2NOeTlEZmdMMVc2MGhhTiJ9",
+        ),
+        (
+            "4-S-3",
+            KID,
+            r#"{"test-vector":"4-S-3"}"#,
+            "v4.public.eyJkYXRhIjoidGhpcyBpcyBhIHNpZ25lZCBtZXNzYWdlIiwiZXhwIjoiMjAyMi0wMS0wMVQwMDowMDowMCswMDowMCJ9NPWciuD3d0o5eXJXG5pJy-DiVEoyPYWs1YSTwWHNJq6DZD3je5gf-0M4JR9ipdUSJbIovzmBECeaWmaqcaP0DQ.eyJraWQiOiJ6VmhNaVBCUDlmUmYyc25FY1Q3Z0ZUaW9lQTlDT2NOeTlEZmdMMVc2MGhhTiJ9",
+        ),
+    ];
+
+    fn nonce(hex: Option<&str>) -> [u8; 32] {
+        hex.map_or([0; 32], |n| hex::decode(n).unwrap().try_into().unwrap())
+    }
+
+    fn key_pair() -> Ed25519KeyPair {
+        let secret = hex::decode(SECRET_KEY).unwrap();
+        let (seed, public) = secret.split_at(32);
+        Ed25519KeyPair::from_seed_and_public_key(seed, public).unwrap()
+    }
+
+    #[test]
+    fn local_vectors() {
+        let key = hex::decode(LOCAL_KEY).unwrap();
+        for (name, n, payload, footer, implicit, token) in LOCAL {
+            let (footer, implicit) = (footer.as_bytes(), implicit.as_bytes());
+            let made = encrypt_with_nonce(&key, &nonce(*n), payload.as_bytes(), footer, implicit);
+            assert_eq!(made.unwrap(), *token, "{name}");
+            let message = decrypt_message(&key, token, footer, implicit).unwrap();
+            assert_eq!(message, payload.as_bytes(), "{name}");
+        }
+    }
+
+    #[test]
+    fn public_vectors() {
+        let public = hex::decode(PUBLIC_KEY).unwrap();
+        for (name, footer, implicit, token) in PUBLIC {
+            let (footer, implicit) = (footer.as_bytes(), implicit.as_bytes());
+            let made = sign(&key_pair(), SIGNED.as_bytes(), footer, implicit);
+            assert_eq!(made, *token, "{name}");
+            let message = verify_message(&public, token, footer, implicit).unwrap();
+            assert_eq!(message, SIGNED.as_bytes(), "{name}");
+        }
+    }
+
+    #[test]
+    fn wrong_purpose() {
+        let (_, _, _, _, _, local) = LOCAL[0];
+        let (_, _, _, public) = PUBLIC[0];
+        assert!(verify_message(&hex::decode(PUBLIC_KEY).unwrap(), local, b"", b"").is_err());
+        assert!(decrypt_message(&hex::decode(LOCAL_KEY).unwrap(), public, b"", b"").is_err());
+    }
+
+    #[test]
+    fn wrong_version() {
+        let (_, _, _, _, _, token) = LOCAL[0];
+        let token = token.replacen("v4.", "v3.", 1);
+        assert!(decrypt_message(&hex::decode(LOCAL_KEY).unwrap(), &token, b"", b"").is_err());
+    }
+
+    #[test]
+    fn wrong_key() {
+        let mut key = hex::decode(LOCAL_KEY).unwrap();
+        key[0] ^= 1;
+        let (_, _, _, _, _, token) = LOCAL[0];
+        assert!(decrypt_message(&key, token, b"", b"").is_err());
+
+        let mut public = hex::decode(PUBLIC_KEY).unwrap();
+        public[0] ^= 1;
+        let (_, _, _, token) = PUBLIC[0];
+        assert!(verify_message(&public, token, b"", b"").is_err());
+    }
+
+    #[test]
+    fn wrong_footer_or_implicit() {
+        let key = hex::decode(LOCAL_KEY).unwrap();
+        let public = hex::decode(PUBLIC_KEY).unwrap();
+        let (_, _, _, footer, implicit, token) = LOCAL[6];
+        let (footer, implicit) = (footer.as_bytes(), implicit.as_bytes());
+        assert!(decrypt_message(&key, token, b"", implicit).is_err());
+        assert!(decrypt_message(&key, token, footer, b"").is_err());
+        let (_, footer, implicit, token) = PUBLIC[2];
+        let (footer, implicit) = (footer.as_bytes(), implicit.as_bytes());
+        assert!(verify_message(&public, token, b"", implicit).is_err());
+        assert!(verify_message(&public, token, footer, b"").is_err());
+    }
+
+    /// `token` with the last byte of its tag or signature flipped.
+    fn tampered(token: &str) -> String {
+        let mut parts: Vec<String> = token.split('.').map(String::from).collect();
+        let mut body = BASE64_URL_SAFE_NO_PAD.decode(&parts[2]).unwrap();
+        *body.last_mut().unwrap() ^= 1;
+        parts[2] = BASE64_URL_SAFE_NO_PAD.encode(body);
+        parts.join(".")
+    }
+
+    #[test]
+    fn tampered_tokens() {
+        let (_, _, _, _, _, token) = LOCAL[4];
+        let key = hex::decode(LOCAL_KEY).unwrap();
+        assert!(decrypt_message(&key, &tampered(token), KID.as_bytes(), b"").is_err());
+        let (_, _, _, token) = PUBLIC[1];
+        let public = hex::decode(PUBLIC_KEY).unwrap();
+        assert!(verify_message(&public, &tampered(token), KID.as_bytes(), b"").is_err());
+    }
+
+    #[test]
+    fn expired_claims() {
+        let (_, _, _, token) = PUBLIC[0];
+        let public = hex::decode(PUBLIC_KEY).unwrap();
+        assert!(verify(&public, token, b"", b"").is_err());
+    }
+}
@@ -0,0 +1,470 @@
+//! Passkeys: WebAuthn credentials owners register while logged in, and log in with
+//! when no upstream provider is around.
+//!
+//! Only `none` attestation is asked for, so there's no CBOR to parse: browsers hand
+//! over the public key as a DER SubjectPublicKeyInfo through `getPublicKey()`, and
+//! everything else is in the raw authenticator data.
+
+use askama::Template;
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD as BASE64_URL_SAFE_NO_PAD, Engine};
+use chrono::Utc;
+use diesel::prelude::*;
+use ring::{
+    digest::{digest, SHA256},
+    signature::{self, UnparsedPublicKey, VerificationAlgorithm},
+};
+use rocket::{
+    delete, get,
+    http::{CookieJar, SameSite},
+    post,
+    serde::{json::Json, Deserialize, Serialize},
+    State,
+};
+use serde_json::{json, Value};
+use tracing::instrument;
+use url::Url;
+
+use crate::{
+    api::{clients::random_string, Error, Result},
+    models, schema,
+    session::{PendingCookie, Session, SessionKey, AMR_HWK, AMR_MFA},
+    upstream::{finish_login, Identity, LoggedIn},
+    MainDatabase, ResolvedBaseUrl,
+};
+
+/// How long a ceremony may take, in seconds.
+const CHALLENGE_LIFETIME: i64 = 5 * 60;
+
+const CHALLENGE: PendingCookie =
+    PendingCookie::new("webauthn_challenge", CHALLENGE_LIFETIME, SameSite::Strict);
+
+// COSE algorithm identifiers
+const ES256: i32 = -7;
+const EDDSA: i32 = -8;
+const RS256: i32 = -257;
+
+// authenticator data flags
+const USER_PRESENT: u8 = 0x01;
+const USER_VERIFIED: u8 = 0x04;
+const ATTESTED_CREDENTIAL_DATA: u8 = 0x40;
+
+fn invalid(why: &str) -> Error {
+    Error::Forbidden(format!("passkey: {why}"))
+}
+
+fn decode(data: &str) -> Result<Vec<u8>> {
+    BASE64_URL_SAFE_NO_PAD
+        .decode(data)
+        .map_err(|_| invalid("malformed base64"))
+}
+
+/// The relying party: this server's host, and the origin ceremonies run on.
+struct RelyingParty {
+    id: String,
+    origin: String,
+}
+
+impl RelyingParty {
+    fn new(base: &ResolvedBaseUrl) -> Result<Self> {
+        let url = Url::parse(&base.0).map_err(|_| Error::InvalidUri(base.0.clone()))?;
+        Ok(Self {
+            id: url
+                .host_str()
+                .ok_or_else(|| Error::InvalidUri(base.0.clone()))?
+                .to_string(),
+            origin: url.origin().ascii_serialization(),
+        })
+    }
+
+    /// Check `clientDataJSON` was made for this challenge, ceremony and origin.
+    fn check_client_data(&self, client_data: &[u8], kind: &str, challenge: &str) -> Result {
+        let client_data: Value =
+            serde_json::from_slice(client_data).map_err(|_| invalid("malformed client data"))?;
+        let field = |name: &str| client_data.get(name).and_then(Value::as_str);
+        if field("type") != Some(kind) {
+            return Err(invalid("wrong ceremony"));
+        }
+        if field("challenge") != Some(challenge) {
+            return Err(invalid("wrong challenge"));
+        }
+        if field("origin") != Some(self.origin.as_str()) {
+            return Err(invalid("wrong origin"));
+        }
+        if client_data.get("crossOrigin").and_then(Value::as_bool) == Some(true) {
+            return Err(invalid("cross-origin"));
+        }
+        Ok(())
+    }
+
+    /// Check authenticator data was made for us, with the user present, and return
+    /// its flags and signature counter.
+    fn check_authenticator_data(&self, data: &[u8]) -> Result<(u8, i64)> {
+        if data.len() < 37 {
+            return Err(invalid("short authenticator data"));
+        }
+        if data[..32] != *digest(&SHA256, self.id.as_bytes()).as_ref() {
+            return Err(invalid("wrong relying party"));
+        }
+        let flags = data[32];
+        if flags & USER_PRESENT == 0 {
+            return Err(invalid("user not present"));
+        }
+        let count = u32::from_be_bytes([data[33], data[34], data[35], data[36]]);
+        Ok((flags, count.into()))
+    }
+}
+
+/// The DER TLV at the start of `der`, as (tag, contents, rest).
+fn tlv(der: &[u8]) -> Option<(u8, &[u8], &[u8])> {
+    let (&tag, der) = der.split_first()?;
+    let (&len, mut der) = der.split_first()?;
+    let len = if len < 0x80 {
+        len as usize
+    } else {
+        let n = (len & 0x7f) as usize;
+        if n == 0 || n > 2 || der.len() < n {
+            return None;
+        }
+        let len = der[..n].iter().fold(0, |acc, &b| acc << 8 | b as usize);
+        der = &der[n..];
+        len
+    };
+    (der.len() >= len).then(|| (tag, &der[..len], &der[len..]))
+}
+
+/// The key inside a SubjectPublicKeyInfo, in the form ring wants it: an uncompressed
+/// point, raw Ed25519 bytes, or a DER RSAPublicKey.
+fn spki_key(der: &[u8]) -> Option<&[u8]> {
+    let (0x30, spki, _) = tlv(der)? else {
+        return None;
+    };
+    let (0x30, _, rest) = tlv(spki)? else {
+        return None;
+    };
+    let (0x03, bits, _) = tlv(rest)? else {
+        return None;
+    };
+    match bits.split_first()? {
+        (0, key) => Some(key),
+        _ => None,
+    }
+}
+
+fn verify_signature(alg: i32, spki: &[u8], message: &[u8], sig: &[u8]) -> Result {
+    let algorithm: &'static dyn VerificationAlgorithm = match alg {
+        ES256 => &signature::ECDSA_P256_SHA256_ASN1,
+        EDDSA => &signature::ED25519,
+        RS256 => &signature::RSA_PKCS1_2048_8192_SHA256,
+        _ => return Err(invalid("unsupported algorithm")),
+    };
+    let key = spki_key(spki).ok_or_else(|| invalid("malformed public key"))?;
+    UnparsedPublicKey::new(algorithm, key)
+        .verify(message, sig)
+        .map_err(|_| invalid("bad signature"))
+}
+
+/// The challenge of the ceremony in progress.
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(crate = "rocket::serde")]
+struct Challenge {
+    challenge: String,
+    /// `webauthn.create` or `webauthn.get`.
+    kind: String,
+}
+
+fn new_challenge(cookies: &CookieJar<'_>, kind: &str) -> Result<String> {
+    let challenge = random_string(32)?;
+    let pending = Challenge {
+        challenge: challenge.clone(),
+        kind: kind.into(),
+    };
+    CHALLENGE.put(cookies, pending)?;
+    Ok(challenge)
+}
+
+/// Take the challenge of a ceremony, which only works once.
+fn take_challenge(cookies: &CookieJar<'_>, kind: &str) -> Result<String> {
+    match CHALLENGE.take::<Challenge>(cookies) {
+        Some(pending) if pending.kind == kind => Ok(pending.challenge),
+        _ => Err(invalid("no ceremony in progress")),
+    }
+}
+
+/// A passkey as shown on the management page.
+pub struct Listed {
+    id: String,
+    name: String,
+    created_at: String,
+    last_used_at: String,
+}
+
+#[derive(Template)]
+#[template(path = "passkeys.html")]
+pub struct Passkeys {
+    sub: String,
+    passkeys: Vec<Listed>,
+}
+
+#[get("/passkeys")]
+#[instrument(skip(db), err)]
+pub async fn manage(db: MainDatabase, session: Session) -> Result<Passkeys> {
+    session.require_owner()?;
+    let uid = session.uid.clone();
+    let passkeys = db
+        .run(move |c| {
+            use schema::passkeys::dsl;
+            dsl::passkeys
+                .filter(dsl::user_id.eq(&uid))
+                .order(dsl::created_at.asc())
+                .load::<models::Passkey>(c)
+        })
+        .await?;
+    Ok(Passkeys {
+        sub: session.sub,
+        passkeys: passkeys
+            .into_iter()
+            .map(|p| Listed {
+                id: p.id,
+                name: p.name,
+                created_at: p.created_at,
+                last_used_at: p.last_used_at.unwrap_or_else(|| "never".into()),
+            })
+            .collect(),
+    })
+}
+
+#[post("/passkeys/options")]
+#[instrument(skip(db, cookies), err)]
+pub async fn registration_options(
+    db: MainDatabase,
+    session: Session,
+    base: ResolvedBaseUrl,
+    cookies: &CookieJar<'_>,
+) -> Result<Json<Value>> {
+    session.require_owner()?;
+    let rp = RelyingParty::new(&base)?;
+    let uid = session.uid.clone();
+    let existing = db
+        .run(move |c| {
+            use schema::passkeys::dsl;
+            dsl::passkeys
+                .filter(dsl::user_id.eq(&uid))
+                .select(dsl::id)
+                .load::<String>(c)
+        })
+        .await?;
+    let challenge = new_challenge(cookies, "webauthn.create")?;
+    Ok(Json(json!({
+        "challenge": challenge,
+        "rp": { "id": rp.id, "name": env!("CARGO_PKG_NAME") },
+        "user": {
+            "id": BASE64_URL_SAFE_NO_PAD.encode(session.uid.as_bytes()),
+            "name": session.sub,
+            "displayName": session.sub,
+        },
+        "pubKeyCredParams": [
+            { "type": "public-key", "alg": EDDSA },
+            { "type": "public-key", "alg": ES256 },
+            { "type": "public-key", "alg": RS256 },
+        ],
+        "excludeCredentials": existing
+            .iter()
+            .map(|id| json!({ "type": "public-key", "id": id }))
+            .collect::<Vec<_>>(),
+        "authenticatorSelection": {
+            "residentKey": "required",
+            "userVerification": "preferred",
+        },
+        "attestation": "none",
+        "timeout": CHALLENGE_LIFETIME * 1000,
+    })))
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(crate = "rocket::serde", rename_all = "camelCase")]
+pub struct Registration {
+    id: String,
+    #[serde(rename = "clientDataJSON")]
+    client_data_json: String,
+    authenticator_data: String,
+    public_key: String,
+    public_key_algorithm: i32,
+    name: Option<String>,
+}
+
+#[post("/passkeys", data = "<registration>")]
+#[instrument(skip(db, cookies, registration), err)]
+pub async fn register(
+    db: MainDatabase,
+    session: Session,
+    base: ResolvedBaseUrl,
+    cookies: &CookieJar<'_>,
+    registration: Json<Registration>,
+) -> Result<Json<Value>> {
+    session.require_owner()?;
+    let rp = RelyingParty::new(&base)?;
+    let challenge = take_challenge(cookies, "webauthn.create")?;
+    let registration = registration.into_inner();
+    rp.check_client_data(
+        &decode(&registration.client_data_json)?,
+        "webauthn.create",
+        &challenge,
+    )?;
+    let data = decode(&registration.authenticator_data)?;
+    let (flags, sign_count) = rp.check_authenticator_data(&data)?;
+    if flags & ATTESTED_CREDENTIAL_DATA == 0 {
+        return Err(invalid("no credential"));
+    }
+    // the attested credential data: a 16 byte AAGUID, then the length-prefixed id
+    let id = decode(&registration.id)?;
+    let id_len = data
+        .get(53..55)
+        .map(|len| u16::from_be_bytes([len[0], len[1]]) as usize);
+    if id_len != Some(id.len()) || data.get(55..55 + id.len()) != Some(&id[..]) {
+        return Err(invalid("credential id doesn't match"));
+    }
+    let public_key = decode(&registration.public_key)?;
+    if !matches!(registration.public_key_algorithm, ES256 | EDDSA | RS256)
+        || spki_key(&public_key).is_none()
+    {
+        return Err(invalid("unsupported public key"));
+    }
+    let row = models::Passkey {
+        id: registration.id,
+        user_id: session.uid,
+        sub: session.sub,
+        name: registration
+            .name
+            .filter(|name| !name.trim().is_empty())
+            .unwrap_or_else(|| "passkey".into()),
+        public_key: registration.public_key,
+        alg: registration.public_key_algorithm,
+        sign_count,
+        created_at: Utc::now().to_rfc3339(),
+        last_used_at: None,
+    };
+    let id = row.id.clone();
+    db.run(move |c| {
+        diesel::insert_into(schema::passkeys::table)
+            .values(&row)
+            .execute(c)
+    })
+    .await?;
+    Ok(Json(json!({ "id": id })))
+}
+
+#[delete("/passkeys/<id>")]
+#[instrument(skip(db), err)]
+pub async fn remove(db: MainDatabase, session: Session, id: String) -> Result<Json<Value>> {
+    session.require_owner()?;
+    let uid = session.uid;
+    let deleted = db
+        .run(move |c| {
+            use schema::passkeys::dsl;
+            diesel::delete(dsl::passkeys.find(&id).filter(dsl::user_id.eq(&uid))).execute(c)
+        })
+        .await?;
+    if deleted == 0 {
+        return Err(Error::NotFound);
+    }
+    Ok(Json(json!({ "deleted": deleted })))
+}
+
+#[derive(Template)]
+#[template(path = "passkey.html")]
+pub struct Login;
+
+#[get("/passkey")]
+pub fn login_page() -> Login {
+    Login
+}
+
+#[post("/passkey/options")]
+#[instrument(skip(cookies), err)]
+pub async fn login_options(base: ResolvedBaseUrl, cookies: &CookieJar<'_>) -> Result<Json<Value>> {
+    let rp = RelyingParty::new(&base)?;
+    let challenge = new_challenge(cookies, "webauthn.get")?;
+    Ok(Json(json!({
+        "challenge": challenge,
+        "rpId": rp.id,
+        "userVerification": "preferred",
+        "timeout": CHALLENGE_LIFETIME * 1000,
+    })))
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(crate = "rocket::serde", rename_all = "camelCase")]
+pub struct Assertion {
+    id: String,
+    #[serde(rename = "clientDataJSON")]
+    client_data_json: String,
+    authenticator_data: String,
+    signature: String,
+    user_handle: Option<String>,
+}
+
+#[post("/passkey", data = "<assertion>")]
+#[instrument(skip(db, session_key, cookies, assertion), err)]
+pub async fn login(
+    db: MainDatabase,
+    session_key: &State<SessionKey>,
+    base: ResolvedBaseUrl,
+    cookies: &CookieJar<'_>,
+    assertion: Json<Assertion>,
+) -> Result<LoggedIn> {
+    let rp = RelyingParty::new(&base)?;
+    let challenge = take_challenge(cookies, "webauthn.get")?;
+    let assertion = assertion.into_inner();
+    let id = assertion.id.clone();
+    let passkey = db
+        .run(move |c| {
+            schema::passkeys::table
+                .find(&id)
+                .first::<models::Passkey>(c)
+                .optional()
+        })
+        .await?
+        .ok_or_else(|| invalid("unknown credential"))?;
+    if let Some(handle) = &assertion.user_handle {
+        if decode(handle)? != passkey.user_id.as_bytes() {
+            return Err(invalid("wrong user"));
+        }
+    }
+    let client_data = decode(&assertion.client_data_json)?;
+    rp.check_client_data(&client_data, "webauthn.get", &challenge)?;
+    let data = decode(&assertion.authenticator_data)?;
+    let (flags, sign_count) = rp.check_authenticator_data(&data)?;
+    let mut message = data;
+    message.extend_from_slice(digest(&SHA256, &client_data).as_ref());
+    verify_signature(
+        passkey.alg,
+        &decode(&passkey.public_key)?,
+        &message,
+        &decode(&assertion.signature)?,
+    )?;
+    // authenticators that count at all count up; anything else is a clone
+    if (sign_count != 0 || passkey.sign_count != 0) && sign_count <= passkey.sign_count {
+        tracing::warn!(id = passkey.id, "passkey signature counter went backwards");
+        return Err(invalid("signature counter went backwards"));
+    }
+    let id = passkey.id.clone();
+    db.run(move |c| {
+        diesel::update(schema::passkeys::table.find(&id))
+            .set(&models::UpdatePasskeyUse {
+                sign_count,
+                last_used_at: Some(Utc::now().to_rfc3339()),
+            })
+            .execute(c)
+    })
+    .await?;
+    let amr: &[&str] = if flags & USER_VERIFIED != 0 {
+        &[AMR_HWK, AMR_MFA]
+    } else {
+        &[AMR_HWK]
+    };
+    let identity = Identity {
+        uid: passkey.user_id,
+        sub: passkey.sub,
+    };
+    finish_login(&db, session_key, cookies, identity, amr).await
+}
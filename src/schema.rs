@@ -1,11 +1,47 @@
 // @generated automatically by Diesel CLI.
 
 diesel::table! {
-    gitlab_tokens (id) {
+    clients (id) {
         id -> Text,
-        user_id -> Integer,
-        access_token -> Text,
-        refresh_token -> Text,
+        name -> Text,
+        secret_hash -> Nullable<Text>,
+        public_key -> Nullable<Text>,
+        audiences -> Text,
+        scopes -> Text,
+        token_lifetime -> Integer,
+        created_at -> Text,
+        subject_type -> Text,
+        sector_identifier -> Nullable<Text>,
+        redirect_uris -> Text,
+        client_type -> Text,
+        first_party -> Bool,
+        id_token_lifetime -> Integer,
+        require_mfa -> Bool,
+        delegation -> Bool,
+    }
+}
+
+diesel::table! {
+    device_codes (device_code) {
+        device_code -> Text,
+        user_code -> Text,
+        client_id -> Text,
+        scope -> Nullable<Text>,
+        status -> Text,
+        sub -> Nullable<Text>,
+        expires_at -> Text,
+        interval -> Integer,
+        last_polled_at -> Nullable<Text>,
+    }
+}
+
+diesel::table! {
+    email_logins (id) {
+        id -> Text,
+        email -> Text,
+        created_at -> Text,
+        expires_at -> Text,
+        used_at -> Nullable<Text>,
     }
 }
 
@@ -18,6 +54,90 @@ diesel::table! {
         response_type -> Text,
         code_challenge -> Text,
         authorized -> Bool,
+        me -> Nullable<Text>,
+        scope -> Nullable<Text>,
+        sub -> Nullable<Text>,
+        nonce -> Nullable<Text>,
+        auth_time -> Nullable<Text>,
+        amr -> Nullable<Text>,
+        custom_claims -> Nullable<Text>,
+    }
+}
+
+diesel::table! {
+    login_keys (id) {
+        id -> Text,
+        user_id -> Text,
+        sub -> Text,
+        name -> Text,
+        public_key -> Text,
+        created_at -> Text,
+        last_used_at -> Nullable<Text>,
+    }
+}
+
+diesel::table! {
+    mastodon_apps (instance) {
+        instance -> Text,
+        client_id -> Text,
+        client_secret -> Text,
+        redirect_uri -> Text,
+        created_at -> Text,
+    }
+}
+
+diesel::table! {
+    passkeys (id) {
+        id -> Text,
+        user_id -> Text,
+        sub -> Text,
+        name -> Text,
+        public_key -> Text,
+        alg -> Integer,
+        sign_count -> BigInt,
+        created_at -> Text,
+        last_used_at -> Nullable<Text>,
+    }
+}
+
+diesel::table! {
+    pushed_authorization_requests (request_uri) {
+        request_uri -> Text,
+        client_id -> Text,
+        request -> Text,
+        expires_at -> Text,
+    }
+}
+
+diesel::table! {
+    recovery_codes (hash) {
+        hash -> Text,
+        sub -> Text,
+        created_at -> Text,
+        used_at -> Nullable<Text>,
+    }
+}
+
+diesel::table! {
+    sessions (id) {
+        id -> Text,
+        user_id -> Text,
+        sub -> Text,
+        auth_time -> Text,
+        amr -> Text,
+        expires_at -> Text,
+    }
+}
+
+diesel::table! {
+    signing_keys (kid) {
+        kid -> Text,
+        public -> Text,
+        private -> Text,
+        created_at -> Text,
+        retired_at -> Nullable<Text>,
+        active -> Bool,
+        version -> Text,
     }
 }
 
@@ -28,9 +148,62 @@ diesel::table! {
         aud -> Text,
         iss -> Text,
         iat -> Text,
-        exp -> Nullable<Integer>,
+        exp -> Nullable<BigInt>,
         valid -> Nullable<Integer>,
+        scopes -> Nullable<Text>,
+        parent -> Nullable<Text>,
+        user_sub -> Nullable<Text>,
+    }
+}
+
+diesel::table! {
+    totp_secrets (sub) {
+        sub -> Text,
+        secret -> Text,
+        confirmed -> Bool,
+        last_step -> Nullable<BigInt>,
+        failures -> Integer,
+        created_at -> Text,
+        recovery_failures -> Integer,
+        recovery_failed_at -> Nullable<Text>,
+    }
+}
+
+diesel::table! {
+    upstream_tokens (id) {
+        id -> Text,
+        provider -> Text,
+        user_id -> Text,
+        access_token -> Text,
+        refresh_token -> Nullable<Text>,
+    }
+}
+
+diesel::table! {
+    users (sub) {
+        sub -> Text,
+        name -> Text,
+        website -> Nullable<Text>,
+        email -> Nullable<Text>,
+        email_verified -> Bool,
+        updated_at -> Text,
     }
 }
 
-diesel::allow_tables_to_appear_in_same_query!(gitlab_tokens, indieauth_codes, tokens,);
+diesel::allow_tables_to_appear_in_same_query!(
+    clients,
+    device_codes,
+    email_logins,
+    indieauth_codes,
+    login_keys,
+    mastodon_apps,
+    passkeys,
+    pushed_authorization_requests,
+    recovery_codes,
+    sessions,
+    signing_keys,
+    tokens,
+    totp_secrets,
+    upstream_tokens,
+    users,
+);
@@ -0,0 +1,378 @@
+use chrono::{DateTime, Duration, Utc};
+use diesel::prelude::*;
+use paseto::{validate_local_token, PasetoBuilder};
+use ring::rand::{SecureRandom, SystemRandom};
+use rocket::{
+    fairing::AdHoc,
+    http::{Cookie, CookieJar, SameSite, Status},
+    post,
+    request::{self, FromRequest},
+    response::Redirect,
+    Build, Request, Rocket, State,
+};
+use rusty_ulid::generate_ulid_string;
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use serde_json::{Map, Value};
+use tracing::instrument;
+
+use crate::{
+    api::{Error, Result},
+    models,
+    paseto::{v4, Version},
+    schema, MainDatabase,
+};
+
+pub const COOKIE_NAME: &str = "session";
+
+/// What [`Session::csrf_token`] tokens are sealed for.
+const CSRF_PURPOSE: &str = "csrf";
+
+/// Authentication method reference for logins through an upstream provider.
+pub const AMR_FEDERATED: &str = "fed";
+
+/// Authentication method reference for logins with a hardware-bound key, like a
+/// passkey.
+pub const AMR_HWK: &str = "hwk";
+
+/// Authentication method reference for logins with a key kept in software, like an
+/// SSH or OpenPGP key.
+pub const AMR_SWK: &str = "swk";
+
+/// Authentication method reference for logins with a one-time code.
+pub const AMR_OTP: &str = "otp";
+
+/// Authentication method reference for logins with more than one factor.
+pub const AMR_MFA: &str = "mfa";
+
+/// The symmetric key browser sessions are encrypted with.
+///
+/// This is deliberately separate from the signing keys: session tokens never leave
+/// the browser they were issued to.
+pub struct SessionKey {
+    key: Vec<u8>,
+    lifetime: Duration,
+    /// Whether tokens are `v2.local` or `v4.local`. Only one is accepted, as the key
+    /// isn't used with both.
+    version: Version,
+}
+
+impl SessionKey {
+    /// Encrypt `claims`, stamped with when they were issued and when they expire.
+    fn encrypt(&self, mut claims: Map<String, Value>, exp: DateTime<Utc>) -> Result<String> {
+        claims.insert("iat".into(), Utc::now().to_rfc3339().into());
+        claims.insert("exp".into(), exp.to_rfc3339().into());
+        match self.version {
+            Version::V2 => {
+                let mut base = PasetoBuilder::new();
+                let mut builder = base.set_encryption_key(&self.key);
+                for (key, value) in &claims {
+                    builder = builder.set_claim(key, value.clone());
+                }
+                builder
+                    .build()
+                    .map_err(|why| Error::PasetoCreationError(format!("{why}")))
+            }
+            Version::V4 => v4::encrypt(&self.key, &serde_json::to_vec(&claims)?, b"", b""),
+        }
+    }
+
+    /// The claims of a token from [`SessionKey::encrypt`], if it hasn't expired.
+    fn decrypt(&self, tok: &str) -> Result<Value> {
+        match self.version {
+            Version::V2 => validate_local_token(tok, None, &self.key, &paseto::TimeBackend::Chrono)
+                .map_err(|why| Error::PasetoValidationError(why.to_string())),
+            Version::V4 => v4::decrypt(&self.key, tok, b"", b""),
+        }
+    }
+
+    /// Seal `data` into a token only this server can open, for things that briefly
+    /// live outside the cookie, like login links sent by email. `purpose` keeps one
+    /// kind of token from passing for another.
+    pub(crate) fn seal(&self, purpose: &str, data: Value, exp: DateTime<Utc>) -> Result<String> {
+        let mut claims = Map::new();
+        claims.insert("purpose".into(), purpose.into());
+        claims.insert("data".into(), data);
+        self.encrypt(claims, exp)
+    }
+
+    /// Open a token from [`SessionKey::seal`], if it's for `purpose` and hasn't expired.
+    pub(crate) fn open(&self, purpose: &str, tok: &str) -> Result<Value> {
+        let mut claims = self.decrypt(tok)?;
+        if claims["purpose"] != purpose {
+            return Err(Error::PasetoValidationError("wrong purpose".into()));
+        }
+        Ok(claims["data"].take())
+    }
+}
+
+/// Reads `session.key` (32 hex-encoded bytes), `session.lifetime` (seconds) and
+/// `session.version` (`v2` or `v4`).
+///
+/// Without a configured key, a random one is generated, and sessions won't survive a
+/// restart. Neither do they when the version changes.
+pub fn fairing() -> AdHoc {
+    async fn fairing(rocket: Rocket<Build>) -> std::result::Result<Rocket<Build>, Rocket<Build>> {
+        let lifetime = rocket
+            .figment()
+            .extract_inner::<i64>("session.lifetime")
+            .unwrap_or(7 * 24 * 60 * 60);
+        let version = rocket
+            .figment()
+            .extract_inner::<Version>("session.version")
+            .unwrap_or_default();
+        let configured = rocket
+            .figment()
+            .extract_inner::<String>("session.key")
+            .ok()
+            .filter(|key| !key.is_empty());
+        let key = match configured {
+            Some(key) => match hex::decode(key) {
+                Ok(key) if key.len() == 32 => key,
+                _ => {
+                    tracing::error!("session.key must be 32 hex-encoded bytes");
+                    return Err(rocket);
+                }
+            },
+            None => {
+                tracing::warn!("no session.key configured, sessions won't survive a restart");
+                let mut key = vec![0; 32];
+                if SystemRandom::new().fill(&mut key).is_err() {
+                    tracing::error!("failed to generate a session key");
+                    return Err(rocket);
+                }
+                key
+            }
+        };
+        Ok(rocket.manage(SessionKey {
+            key,
+            lifetime: Duration::seconds(lifetime),
+            version,
+        }))
+    }
+    AdHoc::try_on_ignite("Sessions", fairing)
+}
+
+/// A logged in browser, carried in an encrypted `v2.local` or `v4.local` cookie.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct Session {
+    pub sid: String,
+    /// The upstream user id.
+    pub uid: String,
+    pub sub: String,
+    pub auth_time: String,
+    pub amr: Vec<String>,
+    /// Whether this is someone allowed to act as the site's `me`, rather than someone
+    /// who only proved who they are, e.g. with RelMeAuth.
+    #[serde(default)]
+    pub owner: bool,
+}
+
+impl Session {
+    /// Record a new session and set its cookie.
+    pub async fn start(
+        db: &MainDatabase,
+        key: &SessionKey,
+        cookies: &CookieJar<'_>,
+        uid: String,
+        sub: String,
+        amr: &[&str],
+        owner: bool,
+    ) -> Result<Session> {
+        let now = Utc::now();
+        let exp = now + key.lifetime;
+        let session = Session {
+            sid: generate_ulid_string(),
+            uid,
+            sub,
+            auth_time: now.to_rfc3339(),
+            amr: amr.iter().map(|s| s.to_string()).collect(),
+            owner,
+        };
+        let row = models::Session {
+            id: session.sid.clone(),
+            user_id: session.uid.clone(),
+            sub: session.sub.clone(),
+            auth_time: session.auth_time.clone(),
+            amr: session.amr.join(" "),
+            expires_at: exp.to_rfc3339(),
+        };
+        db.run(move |c| {
+            diesel::insert_into(schema::sessions::table)
+                .values(&row)
+                .execute(c)
+        })
+        .await?;
+        let claims = match serde_json::to_value(&session)? {
+            Value::Object(claims) => claims,
+            _ => unreachable!("sessions serialize to objects"),
+        };
+        let tok = key.encrypt(claims, exp)?;
+        cookies.add(
+            Cookie::build(COOKIE_NAME, tok)
+                .path("/")
+                .http_only(true)
+                .secure(true)
+                .same_site(SameSite::Lax)
+                .max_age(rocket::time::Duration::seconds(key.lifetime.num_seconds()))
+                .finish(),
+        );
+        Ok(session)
+    }
+
+    /// A token for forms that act with this session, like approving a client. Other
+    /// sites can't read it, so they can't submit those forms on someone's behalf.
+    pub fn csrf_token(&self, key: &SessionKey) -> Result<String> {
+        key.seal(
+            CSRF_PURPOSE,
+            serde_json::json!({ "sid": self.sid }),
+            Utc::now() + key.lifetime,
+        )
+    }
+
+    /// Check a token from [`Session::csrf_token`] was made for this session.
+    pub fn check_csrf(&self, key: &SessionKey, token: &str) -> Result {
+        let data = key
+            .open(CSRF_PURPOSE, token)
+            .map_err(|_| Error::Forbidden("invalid csrf token".into()))?;
+        if data["sid"] != self.sid.as_str() {
+            return Err(Error::Forbidden("csrf token is for another session".into()));
+        }
+        Ok(())
+    }
+
+    /// Only owners can approve anything on the site's behalf.
+    pub fn require_owner(&self) -> Result {
+        if self.owner {
+            Ok(())
+        } else {
+            Err(Error::Forbidden(format!("{} isn't the owner", self.sub)))
+        }
+    }
+}
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for Session {
+    type Error = Error;
+
+    async fn from_request(request: &'r Request<'_>) -> request::Outcome<Self, Self::Error> {
+        let tok = match request.cookies().get(COOKIE_NAME) {
+            Some(cookie) => cookie.value().to_string(),
+            None => {
+                return request::Outcome::Failure((Status::Unauthorized, Error::NoPasetoInRequest))
+            }
+        };
+        let key = request.guard::<&State<SessionKey>>().await.unwrap();
+        let session: Session = match key.decrypt(&tok) {
+            Ok(val) => match serde_json::from_value(val) {
+                Ok(session) => session,
+                Err(why) => {
+                    return request::Outcome::Failure((Status::Unauthorized, Error::Json(why)))
+                }
+            },
+            Err(why) => return request::Outcome::Failure((Status::Unauthorized, why)),
+        };
+        let db = match request.guard::<MainDatabase>().await {
+            request::Outcome::Success(db) => db,
+            _ => {
+                return request::Outcome::Failure((
+                    Status::ServiceUnavailable,
+                    Error::OAuth2("database unavailable".into()),
+                ))
+            }
+        };
+        let sid = session.sid.clone();
+        let exists = db
+            .run(move |c| {
+                use schema::sessions::dsl;
+                dsl::sessions
+                    .find(&sid)
+                    .select(dsl::id)
+                    .first::<String>(c)
+                    .optional()
+            })
+            .await;
+        match exists {
+            Ok(Some(_)) => request::Outcome::Success(session),
+            Ok(None) => request::Outcome::Failure((Status::Unauthorized, Error::TokenRevoked)),
+            Err(why) => {
+                request::Outcome::Failure((Status::InternalServerError, Error::Database(why)))
+            }
+        }
+    }
+}
+
+#[post("/logout")]
+#[instrument(skip(db, cookies), err)]
+pub async fn logout(
+    db: MainDatabase,
+    session: Session,
+    cookies: &CookieJar<'_>,
+) -> Result<Redirect> {
+    let sid = session.sid;
+    db.run(move |c| {
+        use schema::sessions::dsl;
+        diesel::delete(dsl::sessions.find(&sid)).execute(c)
+    })
+    .await?;
+    cookies.remove(Cookie::build(COOKIE_NAME, "").path("/").finish());
+    Ok(Redirect::to("/"))
+}
+
+/// Something a login holds on to between requests, like a challenge or where it's
+/// going next, in a private cookie of its own. It's only good in the browser the
+/// login started in, and only until it expires.
+pub struct PendingCookie {
+    name: &'static str,
+    /// In seconds.
+    lifetime: i64,
+    /// `Lax` for logins that come back from another site, `Strict` otherwise.
+    same_site: SameSite,
+}
+
+#[derive(Serialize, Deserialize)]
+struct Stored<T> {
+    value: T,
+    expires_at: i64,
+}
+
+impl PendingCookie {
+    pub const fn new(name: &'static str, lifetime: i64, same_site: SameSite) -> Self {
+        Self {
+            name,
+            lifetime,
+            same_site,
+        }
+    }
+
+    /// Hold on to `value`, replacing whatever was held before.
+    pub fn put<T: Serialize>(&self, cookies: &CookieJar<'_>, value: T) -> Result {
+        let stored = Stored {
+            value,
+            expires_at: (Utc::now() + Duration::seconds(self.lifetime)).timestamp(),
+        };
+        cookies.add_private(
+            Cookie::build(self.name, serde_json::to_string(&stored)?)
+                .same_site(self.same_site)
+                .finish(),
+        );
+        Ok(())
+    }
+
+    /// What's held, leaving it there.
+    pub fn peek<T: DeserializeOwned>(&self, cookies: &CookieJar<'_>) -> Option<T> {
+        let stored: Stored<T> =
+            serde_json::from_str(cookies.get_private(self.name)?.value()).ok()?;
+        (stored.expires_at > Utc::now().timestamp()).then_some(stored.value)
+    }
+
+    /// What's held, which only comes out once.
+    pub fn take<T: DeserializeOwned>(&self, cookies: &CookieJar<'_>) -> Option<T> {
+        let value = self.peek(cookies);
+        self.forget(cookies);
+        value
+    }
+
+    pub fn forget(&self, cookies: &CookieJar<'_>) {
+        cookies.remove_private(Cookie::named(self.name));
+    }
+}
@@ -0,0 +1,130 @@
+//! Pairwise pseudonymous subject identifiers, per OpenID Connect Core section 8.
+//!
+//! Registered clients with `subject_type = "pairwise"` see a different, stable `sub`
+//! for each user: an HMAC of the user's subject and the client's sector under a
+//! server secret. Clients in different sectors can't correlate users by it.
+
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD as BASE64_URL_SAFE_NO_PAD, Engine};
+use ring::{
+    hmac,
+    rand::{SecureRandom, SystemRandom},
+};
+use rocket::{fairing::AdHoc, Build, Rocket};
+use url::Url;
+
+use crate::{
+    api::{Error, Result},
+    fetch::Fetcher,
+    models,
+};
+
+pub const PUBLIC: &str = "public";
+pub const PAIRWISE: &str = "pairwise";
+
+/// The secret pairwise subjects are derived with.
+pub struct SubjectKey {
+    key: hmac::Key,
+}
+
+/// Reads `subject.secret` (32 hex-encoded bytes).
+///
+/// Without one, a random secret is generated, and pairwise subjects change on every
+/// restart.
+pub fn fairing() -> AdHoc {
+    async fn fairing(rocket: Rocket<Build>) -> std::result::Result<Rocket<Build>, Rocket<Build>> {
+        let configured = rocket
+            .figment()
+            .extract_inner::<String>("subject.secret")
+            .ok()
+            .filter(|secret| !secret.is_empty());
+        let secret = match configured {
+            Some(secret) => match hex::decode(secret) {
+                Ok(secret) if secret.len() == 32 => secret,
+                _ => {
+                    tracing::error!("subject.secret must be 32 hex-encoded bytes");
+                    return Err(rocket);
+                }
+            },
+            None => {
+                tracing::warn!(
+                    "no subject.secret configured, pairwise subjects won't survive a restart"
+                );
+                let mut secret = vec![0; 32];
+                if SystemRandom::new().fill(&mut secret).is_err() {
+                    tracing::error!("failed to generate a subject secret");
+                    return Err(rocket);
+                }
+                secret
+            }
+        };
+        Ok(rocket.manage(SubjectKey {
+            key: hmac::Key::new(hmac::HMAC_SHA256, &secret),
+        }))
+    }
+    AdHoc::try_on_ignite("Subjects", fairing)
+}
+
+impl SubjectKey {
+    /// The subject `client` gets to see for the user `sub`.
+    pub fn for_client(&self, client: &models::Client, sub: &str) -> String {
+        if client.subject_type != PAIRWISE {
+            return sub.to_string();
+        }
+        let sector = sector(client);
+        let mut ctx = hmac::Context::with_key(&self.key);
+        ctx.update(sector.as_bytes());
+        ctx.update(&[0]);
+        ctx.update(sub.as_bytes());
+        BASE64_URL_SAFE_NO_PAD.encode(ctx.sign())
+    }
+}
+
+/// The sector of a pairwise client, per section 8.1: the host of its
+/// `sector_identifier` URI if it has one, so several clients run by the same party can
+/// share subjects, and the host of its redirect URIs otherwise. Clients without
+/// redirect URIs are a sector of their own.
+fn sector(client: &models::Client) -> String {
+    let host = |uri: &str| Url::parse(uri).ok()?.host_str().map(str::to_string);
+    if let Some(identifier) = &client.sector_identifier {
+        return host(identifier).unwrap_or_else(|| identifier.clone());
+    }
+    client
+        .redirect_uris
+        .split_whitespace()
+        .next()
+        .and_then(host)
+        .unwrap_or_else(|| client.id.clone())
+}
+
+/// Check a pairwise client's sector before it's registered. Without a
+/// `sector_identifier`, all its redirect URIs have to be on one host. With one, it has
+/// to be an https URI serving a JSON array that lists every redirect URI.
+pub async fn check_sector(
+    fetcher: &Fetcher,
+    sector_identifier: Option<&str>,
+    redirect_uris: &[&str],
+) -> Result {
+    let invalid = || Error::Grant("invalid_client_metadata");
+    let Some(identifier) = sector_identifier else {
+        let mut hosts = redirect_uris
+            .iter()
+            .map(|uri| Url::parse(uri).ok().and_then(|url| url.host_str().map(str::to_string)));
+        let first = hosts.next().flatten();
+        return match hosts.all(|host| host == first) {
+            true => Ok(()),
+            false => Err(invalid()),
+        };
+    };
+    let url = Url::parse(identifier).map_err(|_| invalid())?;
+    if url.scheme() != "https" || url.host().is_none() {
+        return Err(invalid());
+    }
+    let listed: Vec<String> = fetcher.json(&url).await.map_err(|_| invalid())?;
+    match redirect_uris
+        .iter()
+        .all(|uri| listed.iter().any(|listed| listed == uri))
+    {
+        true => Ok(()),
+        false => Err(invalid()),
+    }
+}
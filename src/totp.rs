@@ -0,0 +1,578 @@
+//! TOTP (RFC 6238) as a second factor, with one-time recovery codes for when the
+//! authenticator app is gone.
+//!
+//! Owners enroll at `/mfa`. Once they have, logging in anywhere but with a verified
+//! passkey stops at `/login/mfa` until they enter a code.
+
+use askama::Template;
+use chrono::{DateTime, Duration, Utc};
+use diesel::prelude::*;
+use qrcode::{render::svg, QrCode};
+use ring::{
+    constant_time::verify_slices_are_equal,
+    digest::{digest, SHA256},
+    hmac,
+    rand::{SecureRandom, SystemRandom},
+};
+use rocket::{
+    form::Form,
+    get,
+    http::{CookieJar, SameSite},
+    post,
+    response::Redirect,
+    serde::{Deserialize, Serialize},
+    FromForm, State,
+};
+use tracing::instrument;
+use url::Url;
+
+use crate::{
+    api::{Error, Result},
+    models, schema,
+    session::{PendingCookie, Session, SessionKey, AMR_MFA, AMR_OTP},
+    upstream::{complete_login, Identity, Welcome},
+    MainDatabase, ResolvedBaseUrl,
+};
+
+/// Someone has five minutes to enter a code after the first factor.
+const PENDING: PendingCookie = PendingCookie::new("mfa_pending", 5 * 60, SameSite::Strict);
+
+const PERIOD: i64 = 30;
+const DIGITS: usize = 6;
+
+/// Steps either side of now that still count, for clocks that don't quite agree.
+const SKEW: i64 = 1;
+
+/// Wrong codes in a row before only recovery codes work.
+const MAX_FAILURES: i32 = 5;
+
+/// Wrong recovery codes in a row before they only get one try every
+/// [`RECOVERY_LOCKOUT`] seconds.
+const MAX_RECOVERY_FAILURES: i32 = 5;
+
+const RECOVERY_LOCKOUT: i64 = 15 * 60;
+
+const RECOVERY_CODES: usize = 10;
+
+const BASE32: &[u8; 32] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZ234567";
+
+fn base32_encode(data: &[u8]) -> String {
+    let mut out = String::new();
+    let (mut buffer, mut bits) = (0u32, 0);
+    for &byte in data {
+        buffer = buffer << 8 | byte as u32;
+        bits += 8;
+        while bits >= 5 {
+            bits -= 5;
+            out.push(BASE32[(buffer >> bits) as usize & 31] as char);
+        }
+    }
+    if bits > 0 {
+        out.push(BASE32[(buffer << (5 - bits)) as usize & 31] as char);
+    }
+    out
+}
+
+fn base32_decode(data: &str) -> Option<Vec<u8>> {
+    let mut out = vec![];
+    let (mut buffer, mut bits) = (0u32, 0);
+    for c in data.bytes() {
+        buffer = buffer << 5 | BASE32.iter().position(|&b| b == c)? as u32;
+        bits += 5;
+        if bits >= 8 {
+            bits -= 8;
+            out.push((buffer >> bits) as u8);
+        }
+    }
+    Some(out)
+}
+
+fn random_bytes(len: usize) -> Result<Vec<u8>> {
+    let mut buf = vec![0; len];
+    SystemRandom::new()
+        .fill(&mut buf)
+        .map_err(|_| Error::OAuth2("failed to generate random data".into()))?;
+    Ok(buf)
+}
+
+/// The code for a time step (RFC 4226 section 5.3).
+fn code_at(secret: &[u8], step: i64) -> String {
+    let key = hmac::Key::new(hmac::HMAC_SHA1_FOR_LEGACY_USE_ONLY, secret);
+    let tag = hmac::sign(&key, &(step as u64).to_be_bytes());
+    let tag = tag.as_ref();
+    let offset = (tag[tag.len() - 1] & 0x0f) as usize;
+    let bin = u32::from_be_bytes([
+        tag[offset] & 0x7f,
+        tag[offset + 1],
+        tag[offset + 2],
+        tag[offset + 3],
+    ]);
+    format!("{:0width$}", bin % 10u32.pow(DIGITS as u32), width = DIGITS)
+}
+
+/// Recovery codes are only kept hashed. They're random enough not to need more than
+/// SHA-256.
+fn hash_recovery_code(code: &str) -> String {
+    hex::encode(digest(&SHA256, code.as_bytes()))
+}
+
+/// Codes as typed: without spaces or dashes, and lowercase.
+fn normalize(code: &str) -> String {
+    code.chars()
+        .filter(|c| !c.is_whitespace() && *c != '-')
+        .flat_map(char::to_lowercase)
+        .collect()
+}
+
+async fn secret(db: &MainDatabase, sub: &str) -> Result<Option<models::TotpSecret>> {
+    let sub = sub.to_string();
+    Ok(db
+        .run(move |c| {
+            schema::totp_secrets::table
+                .find(&sub)
+                .first::<models::TotpSecret>(c)
+                .optional()
+        })
+        .await?)
+}
+
+/// Whether someone has to enter a code after the first factor.
+pub async fn enrolled(db: &MainDatabase, sub: &str) -> Result<bool> {
+    Ok(secret(db, sub).await?.map_or(false, |s| s.confirmed))
+}
+
+/// Check a TOTP code, using up its time step.
+async fn check_totp(db: &MainDatabase, secret: &models::TotpSecret, code: &str) -> Result {
+    if secret.failures >= MAX_FAILURES {
+        return Err(Error::Forbidden(
+            "too many wrong codes, use a recovery code".into(),
+        ));
+    }
+    let key =
+        base32_decode(&secret.secret).ok_or_else(|| Error::OAuth2("corrupt TOTP secret".into()))?;
+    let now = Utc::now().timestamp() / PERIOD;
+    let step = (now - SKEW..=now + SKEW).find(|&step| {
+        secret.last_step.map_or(true, |last| step > last)
+            && verify_slices_are_equal(code_at(&key, step).as_bytes(), code.as_bytes()).is_ok()
+    });
+    let sub = secret.sub.clone();
+    let used = db
+        .run(move |c| {
+            use schema::totp_secrets::dsl;
+            match step {
+                Some(step) => diesel::update(
+                    dsl::totp_secrets
+                        .find(&sub)
+                        .filter(dsl::last_step.is_null().or(dsl::last_step.lt(step))),
+                )
+                .set((dsl::last_step.eq(step), dsl::failures.eq(0)))
+                .execute(c),
+                None => diesel::update(dsl::totp_secrets.find(&sub))
+                    .set(dsl::failures.eq(dsl::failures + 1))
+                    .execute(c)
+                    .map(|_| 0),
+            }
+        })
+        .await?;
+    match used {
+        0 => Err(Error::Forbidden("wrong code".into())),
+        _ => Ok(()),
+    }
+}
+
+/// Use up a recovery code.
+async fn check_recovery_code(db: &MainDatabase, secret: &models::TotpSecret, code: &str) -> Result {
+    let last_failure = secret
+        .recovery_failed_at
+        .as_deref()
+        .and_then(|at| DateTime::parse_from_rfc3339(at).ok());
+    if secret.recovery_failures >= MAX_RECOVERY_FAILURES
+        && last_failure.map_or(false, |at| {
+            Utc::now() < at + Duration::seconds(RECOVERY_LOCKOUT)
+        })
+    {
+        return Err(Error::Forbidden(
+            "too many wrong recovery codes, try again later".into(),
+        ));
+    }
+    let hash = hash_recovery_code(code);
+    let sub = secret.sub.clone();
+    let used = db
+        .run(move |c| {
+            use schema::recovery_codes::dsl;
+            let used = diesel::update(
+                dsl::recovery_codes
+                    .find(&hash)
+                    .filter(dsl::sub.eq(&sub))
+                    .filter(dsl::used_at.is_null()),
+            )
+            .set(dsl::used_at.eq(Utc::now().to_rfc3339()))
+            .execute(c)?;
+            use schema::totp_secrets::dsl as totp;
+            match used {
+                0 => diesel::update(totp::totp_secrets.find(&sub))
+                    .set((
+                        totp::recovery_failures.eq(totp::recovery_failures + 1),
+                        totp::recovery_failed_at.eq(Utc::now().to_rfc3339()),
+                    ))
+                    .execute(c)?,
+                _ => diesel::update(totp::totp_secrets.find(&sub))
+                    .set((totp::failures.eq(0), totp::recovery_failures.eq(0)))
+                    .execute(c)?,
+            };
+            Ok::<_, diesel::result::Error>(used)
+        })
+        .await?;
+    match used {
+        0 => Err(Error::Forbidden("wrong code".into())),
+        _ => Ok(()),
+    }
+}
+
+/// Check a second factor: a TOTP code, or failing that, a recovery code.
+async fn check(db: &MainDatabase, sub: &str, code: &str) -> Result {
+    let code = normalize(code);
+    match secret(db, sub).await? {
+        Some(secret) if secret.confirmed && code.len() == DIGITS => {
+            check_totp(db, &secret, &code).await
+        }
+        Some(secret) if secret.confirmed => check_recovery_code(db, &secret, &code).await,
+        _ => Err(Error::Forbidden("no second factor enrolled".into())),
+    }
+}
+
+/// Make a fresh set of recovery codes, replacing any old ones, and return them.
+async fn new_recovery_codes(db: &MainDatabase, sub: &str) -> Result<Vec<String>> {
+    let mut codes = vec![];
+    for _ in 0..RECOVERY_CODES {
+        let code = hex::encode(random_bytes(5)?);
+        codes.push(format!("{}-{}", &code[..5], &code[5..]));
+    }
+    let now = Utc::now().to_rfc3339();
+    let rows: Vec<models::RecoveryCode> = codes
+        .iter()
+        .map(|code| models::RecoveryCode {
+            hash: hash_recovery_code(&normalize(code)),
+            sub: sub.to_string(),
+            created_at: now.clone(),
+            used_at: None,
+        })
+        .collect();
+    let sub = sub.to_string();
+    db.run(move |c| {
+        c.transaction(|c| {
+            use schema::recovery_codes::dsl;
+            diesel::delete(dsl::recovery_codes.filter(dsl::sub.eq(&sub))).execute(c)?;
+            diesel::insert_into(dsl::recovery_codes)
+                .values(&rows)
+                .execute(c)
+        })
+    })
+    .await?;
+    Ok(codes)
+}
+
+/// A login that passed its first factor, with how.
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(crate = "rocket::serde")]
+struct Pending {
+    uid: String,
+    sub: String,
+    amr: Vec<String>,
+}
+
+/// Hold on to a login that passed its first factor, until it passes the second.
+pub fn defer(cookies: &CookieJar<'_>, identity: Identity, amr: &[&str]) -> Result<Redirect> {
+    let pending = Pending {
+        uid: identity.uid,
+        sub: identity.sub,
+        amr: amr.iter().map(|s| s.to_string()).collect(),
+    };
+    PENDING.put(cookies, pending)?;
+    Ok(Redirect::to("/login/mfa"))
+}
+
+fn pending(cookies: &CookieJar<'_>) -> Option<Pending> {
+    PENDING.peek(cookies)
+}
+
+#[derive(FromForm, Debug)]
+pub struct Code {
+    code: String,
+}
+
+#[derive(Template)]
+#[template(path = "mfa_login.html")]
+pub struct Prompt {
+    message: Option<String>,
+}
+
+#[get("/mfa")]
+pub fn prompt(cookies: &CookieJar<'_>) -> std::result::Result<Prompt, Redirect> {
+    match pending(cookies) {
+        Some(_) => Ok(Prompt { message: None }),
+        None => Err(Redirect::to("/login")),
+    }
+}
+
+#[derive(rocket::Responder)]
+pub enum Verified {
+    LoggedIn(Welcome),
+    Retry(Prompt),
+}
+
+#[post("/mfa", data = "<code>")]
+#[instrument(skip(db, session_key, cookies, code), err)]
+pub async fn verify(
+    db: MainDatabase,
+    session_key: &State<SessionKey>,
+    cookies: &CookieJar<'_>,
+    code: Form<Code>,
+) -> Result<Verified> {
+    let pending = pending(cookies).ok_or_else(|| Error::Forbidden("log in again".into()))?;
+    if let Err(why) = check(&db, &pending.sub, &code.code).await {
+        return Ok(Verified::Retry(Prompt {
+            message: Some(format!("{why}")),
+        }));
+    }
+    PENDING.forget(cookies);
+    let mut amr: Vec<&str> = pending.amr.iter().map(String::as_str).collect();
+    for method in [AMR_OTP, AMR_MFA] {
+        if !amr.contains(&method) {
+            amr.push(method);
+        }
+    }
+    let identity = Identity {
+        uid: pending.uid,
+        sub: pending.sub,
+    };
+    complete_login(&db, session_key, cookies, identity, &amr)
+        .await
+        .map(Verified::LoggedIn)
+}
+
+#[derive(Template, Default)]
+#[template(path = "mfa.html")]
+pub struct Manage {
+    sub: String,
+    enrolled: bool,
+    /// An SVG to scan, while enrolling.
+    qr: Option<String>,
+    secret: Option<String>,
+    /// Only ever shown right after they're made.
+    recovery_codes: Vec<String>,
+    remaining: i64,
+    message: Option<String>,
+}
+
+/// The page for enrolling, or for someone who already has.
+async fn manage_page(
+    db: &MainDatabase,
+    base: &ResolvedBaseUrl,
+    session: &Session,
+    message: Option<String>,
+) -> Result<Manage> {
+    let existing = secret(db, &session.sub).await?;
+    if let Some(secret) = existing.as_ref().filter(|s| s.confirmed) {
+        let sub = secret.sub.clone();
+        let remaining = db
+            .run(move |c| {
+                use schema::recovery_codes::dsl;
+                dsl::recovery_codes
+                    .filter(dsl::sub.eq(&sub))
+                    .filter(dsl::used_at.is_null())
+                    .count()
+                    .get_result(c)
+            })
+            .await?;
+        return Ok(Manage {
+            sub: session.sub.clone(),
+            enrolled: true,
+            remaining,
+            message,
+            ..Default::default()
+        });
+    }
+    // keep showing the same secret until it's confirmed, in case it's scanned already
+    let secret = match existing {
+        Some(secret) => secret.secret,
+        None => {
+            let row = models::TotpSecret {
+                sub: session.sub.clone(),
+                secret: base32_encode(&random_bytes(20)?),
+                confirmed: false,
+                last_step: None,
+                failures: 0,
+                created_at: Utc::now().to_rfc3339(),
+                recovery_failures: 0,
+                recovery_failed_at: None,
+            };
+            let secret = row.secret.clone();
+            db.run(move |c| {
+                diesel::insert_into(schema::totp_secrets::table)
+                    .values(&row)
+                    .execute(c)
+            })
+            .await?;
+            secret
+        }
+    };
+    let issuer = Url::parse(&base.0)
+        .ok()
+        .and_then(|url| url.host_str().map(String::from))
+        .unwrap_or_else(|| env!("CARGO_PKG_NAME").into());
+    let mut uri = Url::parse("otpauth://totp/").map_err(|_| Error::InvalidUri(issuer.clone()))?;
+    uri.set_path(&format!("{issuer}:{}", session.sub));
+    uri.query_pairs_mut()
+        .append_pair("secret", &secret)
+        .append_pair("issuer", &issuer)
+        .append_pair("algorithm", "SHA1")
+        .append_pair("digits", &DIGITS.to_string())
+        .append_pair("period", &PERIOD.to_string());
+    let qr = QrCode::new(uri.as_str().as_bytes())
+        .map_err(|why| Error::OAuth2(format!("{why}")))?
+        .render::<svg::Color>()
+        .min_dimensions(200, 200)
+        .build();
+    Ok(Manage {
+        sub: session.sub.clone(),
+        qr: Some(qr),
+        secret: Some(secret),
+        message,
+        ..Default::default()
+    })
+}
+
+#[get("/mfa")]
+#[instrument(skip(db, base), err)]
+pub async fn manage(db: MainDatabase, base: ResolvedBaseUrl, session: Session) -> Result<Manage> {
+    session.require_owner()?;
+    manage_page(&db, &base, &session, None).await
+}
+
+/// Confirm enrollment with a first code, and hand out recovery codes.
+#[post("/mfa", data = "<code>")]
+#[instrument(skip(db, base, code), err)]
+pub async fn enroll(
+    db: MainDatabase,
+    base: ResolvedBaseUrl,
+    session: Session,
+    code: Form<Code>,
+) -> Result<Manage> {
+    session.require_owner()?;
+    let pending = match secret(&db, &session.sub).await? {
+        Some(secret) if !secret.confirmed => secret,
+        _ => return manage_page(&db, &base, &session, None).await,
+    };
+    if let Err(why) = check_totp(&db, &pending, &normalize(&code.code)).await {
+        return manage_page(&db, &base, &session, Some(format!("{why}"))).await;
+    }
+    let sub = session.sub.clone();
+    db.run(move |c| {
+        use schema::totp_secrets::dsl;
+        diesel::update(dsl::totp_secrets.find(&sub))
+            .set(dsl::confirmed.eq(true))
+            .execute(c)
+    })
+    .await?;
+    let recovery_codes = new_recovery_codes(&db, &session.sub).await?;
+    Ok(Manage {
+        sub: session.sub,
+        enrolled: true,
+        remaining: recovery_codes.len() as i64,
+        recovery_codes,
+        ..Default::default()
+    })
+}
+
+/// Turn the second factor off, which takes a code.
+#[post("/mfa/disable", data = "<code>")]
+#[instrument(skip(db, base, code), err)]
+pub async fn disable(
+    db: MainDatabase,
+    base: ResolvedBaseUrl,
+    session: Session,
+    code: Form<Code>,
+) -> Result<Manage> {
+    session.require_owner()?;
+    if let Err(why) = check(&db, &session.sub, &code.code).await {
+        return manage_page(&db, &base, &session, Some(format!("{why}"))).await;
+    }
+    let sub = session.sub.clone();
+    db.run(move |c| {
+        c.transaction(|c| {
+            diesel::delete(
+                schema::recovery_codes::table.filter(schema::recovery_codes::sub.eq(&sub)),
+            )
+            .execute(c)?;
+            diesel::delete(schema::totp_secrets::table.find(&sub)).execute(c)
+        })
+    })
+    .await?;
+    manage_page(
+        &db,
+        &base,
+        &session,
+        Some("Second factor turned off.".into()),
+    )
+    .await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// RFC 6238 Appendix B, for SHA-1: `(time, code)`. The RFC's codes have eight
+    /// digits; ours are the last six of them.
+    const RFC_6238: &[(i64, &str)] = &[
+        (59, "94287082"),
+        (1111111109, "07081804"),
+        (1111111111, "14050471"),
+        (1234567890, "89005924"),
+        (2000000000, "69279037"),
+        (20000000000, "65353130"),
+    ];
+
+    #[test]
+    fn rfc_6238_codes() {
+        for (time, code) in RFC_6238 {
+            assert_eq!(
+                code_at(b"12345678901234567890", time / PERIOD),
+                code[code.len() - DIGITS..],
+                "at {time}"
+            );
+        }
+    }
+
+    /// RFC 4648 section 10, without padding.
+    #[test]
+    fn base32_vectors() {
+        for (data, encoded) in [
+            ("", ""),
+            ("f", "MY"),
+            ("fo", "MZXQ"),
+            ("foo", "MZXW6"),
+            ("foob", "MZXW6YQ"),
+            ("fooba", "MZXW6YTB"),
+            ("foobar", "MZXW6YTBOI"),
+        ] {
+            assert_eq!(base32_encode(data.as_bytes()), encoded);
+            assert_eq!(base32_decode(encoded).unwrap(), data.as_bytes());
+        }
+    }
+
+    #[test]
+    fn base32_round_trip() {
+        for len in 0..=40 {
+            let data = random_bytes(len).unwrap();
+            assert_eq!(base32_decode(&base32_encode(&data)).unwrap(), data);
+        }
+    }
+
+    #[test]
+    fn base32_rejects_other_characters() {
+        assert!(base32_decode("MZXW6YT1").is_none());
+        assert!(base32_decode("mzxw6").is_none());
+        assert!(base32_decode("MZXW6===").is_none());
+    }
+}
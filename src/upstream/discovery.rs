@@ -0,0 +1,158 @@
+//! Finding `rel` links on someone's home page, from its `Link` headers and from the
+//! `<link>` and `<a>` elements of its HTML. There's no HTML parser here, just enough
+//! scanning for tags and attributes.
+
+use url::Url;
+
+use crate::{
+    api::{Error, Result},
+    fetch::{self, Fetcher},
+};
+
+/// The links on a fetched page. Relative ones are resolved against where the page
+/// ended up, after redirects.
+#[derive(Debug)]
+pub struct Page {
+    links: Vec<(String, Url)>,
+}
+
+impl Page {
+    /// Links with this `rel`, headers first, in order.
+    pub fn rel<'a>(&'a self, rel: &'a str) -> impl Iterator<Item = &'a Url> + 'a {
+        self.links
+            .iter()
+            .filter(move |(r, _)| r.eq_ignore_ascii_case(rel))
+            .map(|(_, url)| url)
+    }
+}
+
+/// Turn what someone typed into a profile URL: https unless said otherwise, with a
+/// path, and without a fragment.
+pub fn normalize(input: &str) -> Result<Url> {
+    let input = input.trim();
+    let with_scheme = if input.contains("://") {
+        input.to_string()
+    } else {
+        format!("https://{input}")
+    };
+    let mut url = Url::parse(&with_scheme).map_err(|_| Error::InvalidUri(input.to_string()))?;
+    if !matches!(url.scheme(), "https" | "http") || url.host().is_none() {
+        return Err(Error::InvalidUri(input.to_string()));
+    }
+    url.set_fragment(None);
+    Ok(url)
+}
+
+/// Fetch `url` and collect its links, from as much of the page as
+/// [`fetch::read`] lets through.
+pub async fn fetch(fetcher: &Fetcher, url: &Url) -> Result<Page> {
+    let res = fetcher.get(url, "text/html").await?;
+    let base = res.url().clone();
+    let mut links = vec![];
+    for header in res.headers().get_all(reqwest::header::LINK) {
+        if let Ok(header) = header.to_str() {
+            links.extend(link_header(&base, header));
+        }
+    }
+    let html = fetch::read(res).await?;
+    links.extend(link_elements(&base, &String::from_utf8_lossy(&html)));
+    Ok(Page { links })
+}
+
+/// `<https://example.com/auth>; rel="authorization_endpoint", <...>; rel="me"`
+fn link_header(base: &Url, header: &str) -> Vec<(String, Url)> {
+    let mut links = vec![];
+    for link in header.split(',') {
+        let mut parts = link.split(';');
+        let target = parts
+            .next()
+            .map(str::trim)
+            .and_then(|t| t.strip_prefix('<'))
+            .and_then(|t| t.strip_suffix('>'));
+        let Some(target) = target.and_then(|t| base.join(t).ok()) else {
+            continue;
+        };
+        for param in parts {
+            if let Some((key, value)) = param.split_once('=') {
+                if key.trim().eq_ignore_ascii_case("rel") {
+                    for rel in value.trim().trim_matches('"').split_whitespace() {
+                        links.push((rel.to_string(), target.clone()));
+                    }
+                }
+            }
+        }
+    }
+    links
+}
+
+/// Every `<link>` and `<a>` with both `rel` and `href`.
+fn link_elements(base: &Url, html: &str) -> Vec<(String, Url)> {
+    let mut links = vec![];
+    let lower = html.to_ascii_lowercase();
+    let mut rest = 0;
+    while let Some(start) = lower[rest..].find('<').map(|i| rest + i) {
+        let end = lower[start..].find('>').map_or(lower.len(), |i| start + i);
+        rest = end;
+        let tag = &html[start + 1..end];
+        let name = tag
+            .split(|c: char| c.is_ascii_whitespace())
+            .next()
+            .unwrap_or_default();
+        if !name.eq_ignore_ascii_case("link") && !name.eq_ignore_ascii_case("a") {
+            continue;
+        }
+        let attrs = attributes(&tag[name.len()..]);
+        let find = |key: &str| {
+            attrs
+                .iter()
+                .find(|(k, _)| k.eq_ignore_ascii_case(key))
+                .map(|(_, v)| v.as_str())
+        };
+        if let (Some(rel), Some(href)) = (find("rel"), find("href")) {
+            if let Ok(target) = base.join(href) {
+                for rel in rel.split_whitespace() {
+                    links.push((rel.to_string(), target.clone()));
+                }
+            }
+        }
+    }
+    links
+}
+
+/// `key="value" key='value' key=value key`, as found inside a tag.
+fn attributes(mut s: &str) -> Vec<(String, String)> {
+    let mut attrs = vec![];
+    loop {
+        s = s.trim_start_matches(|c: char| c.is_ascii_whitespace() || c == '/');
+        if s.is_empty() {
+            return attrs;
+        }
+        let key_end = s
+            .find(|c: char| c.is_ascii_whitespace() || c == '=')
+            .unwrap_or(s.len());
+        let key = &s[..key_end];
+        s = s[key_end..].trim_start();
+        let value = match s.strip_prefix('=') {
+            Some(after) => {
+                let after = after.trim_start();
+                let (value, rest) = match after.chars().next() {
+                    Some(quote @ ('"' | '\'')) => {
+                        let inner = &after[1..];
+                        let close = inner.find(quote).unwrap_or(inner.len());
+                        (&inner[..close], inner.get(close + 1..).unwrap_or_default())
+                    }
+                    _ => {
+                        let close = after
+                            .find(|c: char| c.is_ascii_whitespace())
+                            .unwrap_or(after.len());
+                        (&after[..close], &after[close..])
+                    }
+                };
+                s = rest;
+                value
+            }
+            None => "",
+        };
+        attrs.push((key.to_string(), value.replace("&amp;", "&")));
+    }
+}
@@ -0,0 +1,61 @@
+use rocket::figment::Figment;
+use serde::Deserialize;
+
+use crate::{
+    api::Result,
+    oauth::{self, TokenResponse},
+};
+
+use super::{get_json, instance_url, Provider, UpstreamUser};
+
+/// A Forgejo or Gitea instance at `url`. There's no default: codeberg.org is only one
+/// of many.
+pub struct Forgejo {
+    url: String,
+}
+
+impl Forgejo {
+    pub fn from_figment(figment: &Figment, name: &str) -> std::result::Result<Self, String> {
+        let url = instance_url(figment, name).ok_or_else(|| format!("oauth.{name} needs a url"))?;
+        Ok(Self { url })
+    }
+}
+
+#[derive(Default, Debug, Clone, PartialEq, Deserialize)]
+struct User {
+    // these are all we care about
+    id: i64,
+    login: String,
+    website: Option<String>,
+    email: Option<String>,
+}
+
+#[rocket::async_trait]
+impl Provider for Forgejo {
+    fn endpoints(&self) -> Option<oauth::Provider> {
+        Some(oauth::Provider::new(
+            format!("{}/login/oauth/authorize", self.url),
+            format!("{}/login/oauth/access_token", self.url),
+        ))
+    }
+
+    fn site(&self) -> Option<String> {
+        Some(self.url.clone())
+    }
+
+    fn scopes(&self) -> Vec<String> {
+        vec!["read:user".into()]
+    }
+
+    async fn user(&self, token: &TokenResponse, _nonce: Option<&str>) -> Result<UpstreamUser> {
+        let u: User = get_json(&format!("{}/api/v1/user", self.url), Some(token)).await?;
+        Ok(UpstreamUser {
+            id: u.id.to_string(),
+            profile: Some(format!("{}/{}", self.url, u.login)),
+            name: u.login,
+            website: u.website.filter(|w| !w.is_empty()),
+            email: u.email.filter(|e| !e.is_empty()),
+            email_verified: false,
+        })
+    }
+}
@@ -0,0 +1,71 @@
+use rocket::figment::Figment;
+use serde::Deserialize;
+
+use crate::{
+    api::Result,
+    oauth::{self, TokenResponse},
+};
+
+use super::{get_json, instance_url, Provider, UpstreamUser};
+
+const GITHUB_COM: &str = "https://github.com";
+
+/// github.com, or GitHub Enterprise Server at `url`. Register an OAuth app (not a
+/// GitHub app) under Developer settings.
+pub struct GitHub {
+    url: String,
+    /// github.com has its API on a host of its own, Enterprise Server under `/api/v3`.
+    api: String,
+}
+
+impl GitHub {
+    pub fn from_figment(figment: &Figment, name: &str) -> Self {
+        let url = instance_url(figment, name).unwrap_or_else(|| GITHUB_COM.to_string());
+        let api = match url.as_str() {
+            GITHUB_COM => "https://api.github.com".to_string(),
+            _ => format!("{url}/api/v3"),
+        };
+        Self { url, api }
+    }
+}
+
+#[derive(Default, Debug, Clone, PartialEq, Deserialize)]
+struct User {
+    // these are all we care about
+    id: i64,
+    login: String,
+    html_url: Option<String>,
+    blog: Option<String>,
+    /// Their public email, if they picked one.
+    email: Option<String>,
+}
+
+#[rocket::async_trait]
+impl Provider for GitHub {
+    fn endpoints(&self) -> Option<oauth::Provider> {
+        Some(oauth::Provider::new(
+            format!("{}/login/oauth/authorize", self.url),
+            format!("{}/login/oauth/access_token", self.url),
+        ))
+    }
+
+    fn site(&self) -> Option<String> {
+        Some(self.url.clone())
+    }
+
+    fn scopes(&self) -> Vec<String> {
+        vec!["read:user".into()]
+    }
+
+    async fn user(&self, token: &TokenResponse, _nonce: Option<&str>) -> Result<UpstreamUser> {
+        let u: User = get_json(&format!("{}/user", self.api), Some(token)).await?;
+        Ok(UpstreamUser {
+            id: u.id.to_string(),
+            name: u.login,
+            profile: u.html_url,
+            website: u.blog.filter(|w| !w.is_empty()),
+            email: u.email,
+            email_verified: false,
+        })
+    }
+}
@@ -0,0 +1,94 @@
+use rocket::figment::Figment;
+use serde::Deserialize;
+use url::Url;
+
+use crate::{
+    api::{Error, Result},
+    oauth::{self, TokenResponse},
+};
+
+use super::{get_json, instance_url, Provider, UpstreamUser};
+
+/// gitlab.com or a self-hosted instance, at `url`.
+pub struct GitLab {
+    url: String,
+}
+
+impl GitLab {
+    pub fn from_figment(figment: &Figment, name: &str) -> std::result::Result<Self, String> {
+        Ok(Self {
+            url: instance_url(figment, name).unwrap_or_else(|| "https://gitlab.com".to_string()),
+        })
+    }
+}
+
+#[derive(Default, Debug, Clone, PartialEq, Deserialize)]
+struct User {
+    // these are all we care about
+    id: i64,
+    name: String,
+    web_url: Option<String>,
+    website_url: Option<String>,
+    public_email: Option<String>,
+}
+
+impl From<User> for UpstreamUser {
+    fn from(u: User) -> Self {
+        UpstreamUser {
+            id: u.id.to_string(),
+            name: u.name,
+            profile: u.web_url,
+            website: u.website_url.filter(|w| !w.is_empty()),
+            email: u.public_email.filter(|e| !e.is_empty()),
+            email_verified: false,
+        }
+    }
+}
+
+/// An SSH or GPG key from `/users/:id/keys` or `/users/:id/gpg_keys`.
+#[derive(Debug, Deserialize)]
+struct Key {
+    key: String,
+}
+
+#[rocket::async_trait]
+impl Provider for GitLab {
+    fn endpoints(&self) -> Option<oauth::Provider> {
+        Some(oauth::Provider::new(
+            format!("{}/oauth/authorize", self.url),
+            format!("{}/oauth/token", self.url),
+        ))
+    }
+
+    fn site(&self) -> Option<String> {
+        Some(self.url.clone())
+    }
+
+    fn scopes(&self) -> Vec<String> {
+        vec!["read_user".into()]
+    }
+
+    async fn user(&self, token: &TokenResponse, _nonce: Option<&str>) -> Result<UpstreamUser> {
+        let u: User = get_json(&format!("{}/api/v4/user", self.url), Some(token)).await?;
+        Ok(u.into())
+    }
+
+    async fn public_keys(&self, username: &str) -> Result<Option<(UpstreamUser, Vec<String>)>> {
+        let mut url = Url::parse(&format!("{}/api/v4/users", self.url))
+            .map_err(|_| Error::InvalidUri(self.url.clone()))?;
+        url.query_pairs_mut().append_pair("username", username);
+        let found: Vec<User> = get_json(url.as_str(), None).await?;
+        let Some(u) = found.into_iter().next() else {
+            return Ok(None);
+        };
+        let ssh: Vec<Key> =
+            get_json(&format!("{}/api/v4/users/{}/keys", self.url, u.id), None).await?;
+        let gpg: Vec<Key> = get_json(
+            &format!("{}/api/v4/users/{}/gpg_keys", self.url, u.id),
+            None,
+        )
+        .await?;
+        let keys = ssh.into_iter().chain(gpg).map(|k| k.key).collect();
+        Ok(Some((u.into(), keys)))
+    }
+}
@@ -0,0 +1,243 @@
+//! Logging in through your own IndieAuth server, the way indielogin.com works: enter
+//! your URL, approve us there, and we check the `me` it vouches for.
+
+use rocket::{
+    get,
+    http::{CookieJar, SameSite},
+    response::Redirect,
+    serde::{Deserialize, Serialize},
+    State,
+};
+use tracing::instrument;
+use url::Url;
+
+use crate::{
+    api::{Error, Result},
+    fetch::{self, Fetcher},
+    oauth::pkce,
+    session::{PendingCookie, SessionKey, AMR_FEDERATED},
+    MainDatabase, ResolvedBaseUrl,
+};
+
+use super::{
+    discovery, finish_login, remember, Identity, LoggedIn, Login, UpstreamUser, Upstreams,
+    LOGIN_LIFETIME,
+};
+
+const PENDING: PendingCookie = PendingCookie::new("indieauth_login", LOGIN_LIFETIME, SameSite::Lax);
+
+/// `indieauth_login` in Rocket.toml.
+#[derive(Debug, Default, Deserialize)]
+#[serde(crate = "rocket::serde")]
+pub struct Config {
+    /// Profile URLs allowed to log in. Nobody is, unless listed here.
+    #[serde(default)]
+    pub allowed_users: Vec<String>,
+}
+
+impl Config {
+    fn allows(&self, me: &Url) -> bool {
+        self.allowed_users
+            .iter()
+            .filter_map(|user| discovery::normalize(user).ok())
+            .any(|user| &user == me)
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(crate = "rocket::serde")]
+struct Metadata {
+    issuer: Option<String>,
+    authorization_endpoint: String,
+}
+
+/// Where a login went, and what to check when it comes back.
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(crate = "rocket::serde")]
+struct Pending {
+    me: String,
+    authorization_endpoint: String,
+    issuer: Option<String>,
+    state: String,
+    code_verifier: String,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(crate = "rocket::serde")]
+struct Profile {
+    me: String,
+}
+
+/// Find someone's authorization endpoint, preferring their `indieauth-metadata`.
+///
+/// Metadata has to name its issuer: an https URL without a query or fragment, that
+/// the metadata URL starts with (IndieAuth section 4.1.1).
+async fn discover(fetcher: &Fetcher, me: &Url) -> Result<Metadata> {
+    let page = discovery::fetch(fetcher, me).await?;
+    if let Some(url) = page.rel("indieauth-metadata").next() {
+        let metadata: Metadata = fetcher.json(url).await?;
+        let valid = metadata.issuer.as_deref().map_or(false, |issuer| {
+            Url::parse(issuer).map_or(false, |parsed| {
+                parsed.scheme() == "https"
+                    && parsed.query().is_none()
+                    && parsed.fragment().is_none()
+                    && url.as_str().starts_with(issuer)
+            })
+        });
+        if !valid {
+            return Err(Error::OAuth2(format!(
+                "{url} doesn't name an issuer it belongs to"
+            )));
+        }
+        return Ok(metadata);
+    }
+    match page.rel("authorization_endpoint").next() {
+        Some(url) => Ok(Metadata {
+            issuer: None,
+            authorization_endpoint: url.to_string(),
+        }),
+        None => Err(Error::OAuth2(format!(
+            "{me} doesn't name an IndieAuth server"
+        ))),
+    }
+}
+
+fn redirect_uri(base: &ResolvedBaseUrl) -> String {
+    format!("{}/login/indieauth/callback", base.0)
+}
+
+#[get("/indieauth?<me>")]
+#[instrument(skip(upstreams, fetcher, base, cookies))]
+pub async fn start(
+    upstreams: &State<Upstreams>,
+    fetcher: &State<Fetcher>,
+    base: ResolvedBaseUrl,
+    cookies: &CookieJar<'_>,
+    me: &str,
+) -> std::result::Result<Redirect, Login> {
+    let failed = |why: Error| Login::new(upstreams, Some(format!("{why}")));
+    let me = discovery::normalize(me).map_err(failed)?;
+    let metadata = discover(fetcher, &me).await.map_err(failed)?;
+    let (code_verifier, code_challenge) = pkce::generate();
+    let state = crate::api::clients::random_string(32).map_err(failed)?;
+    let mut url = Url::parse(&metadata.authorization_endpoint)
+        .map_err(|_| failed(Error::InvalidUri(metadata.authorization_endpoint.clone())))?;
+    url.query_pairs_mut()
+        .append_pair("response_type", "code")
+        .append_pair("client_id", &format!("{}/", base.0))
+        .append_pair("redirect_uri", &redirect_uri(&base))
+        .append_pair("state", &state)
+        .append_pair("code_challenge", &code_challenge)
+        .append_pair("code_challenge_method", "S256")
+        .append_pair("me", me.as_str());
+    let pending = Pending {
+        me: me.to_string(),
+        authorization_endpoint: metadata.authorization_endpoint,
+        issuer: metadata.issuer,
+        state,
+        code_verifier,
+    };
+    PENDING.put(cookies, pending).map_err(failed)?;
+    Ok(Redirect::to(url.to_string()))
+}
+
+/// Redeem the code at the authorization endpoint, which is all it takes to learn who
+/// logged in.
+async fn redeem(
+    fetcher: &Fetcher,
+    pending: &Pending,
+    base: &ResolvedBaseUrl,
+    code: &str,
+) -> Result<Url> {
+    let endpoint = Url::parse(&pending.authorization_endpoint)
+        .map_err(|_| Error::InvalidUri(pending.authorization_endpoint.clone()))?;
+    let res = fetcher
+        .request(reqwest::Method::POST, &endpoint)?
+        .header("Accept", "application/json")
+        .form(&[
+            ("grant_type", "authorization_code"),
+            ("code", code),
+            ("client_id", format!("{}/", base.0).as_str()),
+            ("redirect_uri", redirect_uri(base).as_str()),
+            ("code_verifier", pending.code_verifier.as_str()),
+        ])
+        .send()
+        .await
+        .map_err(|why| Error::OAuth2(format!("{why}")))?;
+    if !res.status().is_success() {
+        return Err(Error::ExchangeError(res.status().as_u16()));
+    }
+    let profile: Profile = serde_json::from_slice(&fetch::read(res).await?)?;
+    discovery::normalize(&profile.me)
+}
+
+/// The server may answer for a different URL than the one entered, e.g. with a
+/// trailing slash or `www.`, but only one on the same host that names it too, as the
+/// same issuer.
+async fn check_me(fetcher: &Fetcher, pending: &Pending, me: &Url) -> Result {
+    let entered = discovery::normalize(&pending.me)?;
+    if me == &entered {
+        return Ok(());
+    }
+    if me.host() != entered.host() {
+        return Err(Error::Forbidden(format!(
+            "{me} isn't on the same host as {entered}"
+        )));
+    }
+    let metadata = discover(fetcher, me).await?;
+    if metadata.authorization_endpoint != pending.authorization_endpoint
+        || metadata.issuer != pending.issuer
+    {
+        return Err(Error::Forbidden(format!(
+            "{me} uses a different IndieAuth server"
+        )));
+    }
+    Ok(())
+}
+
+#[get("/indieauth/callback?<code>&<state>&<iss>")]
+#[instrument(
+    skip(db, session_key, config, fetcher, base, cookies, code, state),
+    err
+)]
+#[allow(clippy::too_many_arguments)]
+pub async fn callback(
+    db: MainDatabase,
+    session_key: &State<SessionKey>,
+    config: &State<Config>,
+    fetcher: &State<Fetcher>,
+    base: ResolvedBaseUrl,
+    cookies: &CookieJar<'_>,
+    code: String,
+    state: String,
+    iss: Option<String>,
+) -> Result<LoggedIn> {
+    let pending: Pending = PENDING
+        .take(cookies)
+        .ok_or_else(|| Error::OAuth2("missing login cookie".into()))?;
+    if pending.state != state {
+        return Err(Error::OAuth2("state mismatch".into()));
+    }
+    if pending.issuer.is_some() && pending.issuer != iss {
+        return Err(Error::OAuth2("issuer mismatch".into()));
+    }
+    let me = redeem(fetcher, &pending, &base, &code).await?;
+    check_me(fetcher, &pending, &me).await?;
+    if !config.allows(&me) {
+        return Err(Error::OAuth2(
+            "I'm sorry Dave, I'm afraid I can't do that.".into(),
+        ));
+    }
+    let user = UpstreamUser {
+        id: me.to_string(),
+        name: me.to_string(),
+        website: Some(me.to_string()),
+        ..Default::default()
+    };
+    remember(&db, me.as_str(), &user).await?;
+    let identity = Identity {
+        uid: format!("indieauth:{me}"),
+        sub: me.to_string(),
+    };
+    finish_login(&db, session_key, cookies, identity, &[AMR_FEDERATED]).await
+}
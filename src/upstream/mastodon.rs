@@ -0,0 +1,298 @@
+//! Logging in with a Mastodon (or compatible) account on any instance. There's no
+//! app to register up front: the first login from an instance registers one through
+//! `/api/v1/apps`, and its credentials are kept in `mastodon_apps`.
+
+use std::{collections::HashMap, sync::Arc};
+
+use chrono::Utc;
+use diesel::prelude::*;
+use rocket::{
+    get,
+    http::{CookieJar, SameSite},
+    response::Redirect,
+    serde::{Deserialize, Serialize},
+    State,
+};
+use rusty_ulid::generate_ulid_string;
+use tokio::sync::RwLock;
+use tracing::instrument;
+
+use crate::{
+    api::{Error, Result},
+    models,
+    oauth::{self, OAuth2, OAuthConfig},
+    schema,
+    session::{PendingCookie, SessionKey, AMR_FEDERATED},
+    MainDatabase, ResolvedBaseUrl, APPLICATION_NAME,
+};
+
+use super::{
+    finish_login, get_json, remember, Identity, LoggedIn, Login, UpstreamUser, Upstreams,
+    LOGIN_LIFETIME,
+};
+
+const PENDING: PendingCookie = PendingCookie::new("mastodon_login", LOGIN_LIFETIME, SameSite::Lax);
+
+/// Just enough to read the account.
+const SCOPES: &[&str] = &["read:accounts"];
+
+/// `mastodon_login` in Rocket.toml.
+#[derive(Debug, Default, Deserialize)]
+#[serde(crate = "rocket::serde")]
+pub struct Config {
+    /// Accounts allowed to log in, as `user@instance`. Nobody is, unless listed here.
+    #[serde(default)]
+    pub allowed_users: Vec<String>,
+    /// How instances are reached, `https` unless set. Only for testing against one
+    /// running locally.
+    scheme: Option<String>,
+}
+
+impl Config {
+    fn allows(&self, user: &str, instance: &str) -> bool {
+        self.allowed_users
+            .iter()
+            .filter_map(|allowed| parse_account(allowed).ok())
+            .any(|allowed| allowed.0 == user && allowed.1 == instance)
+    }
+
+    /// Where `instance` is, e.g. `https://mastodon.social`.
+    fn url(&self, instance: &str) -> String {
+        format!("{}://{instance}", self.scheme.as_deref().unwrap_or("https"))
+    }
+}
+
+/// The instances someone logged in from since the start, with their apps. The
+/// code verifiers of logins on their way live in these too.
+#[derive(Default)]
+pub struct Instances(RwLock<HashMap<String, Arc<OAuth2>>>);
+
+/// `user@instance`, or `@user@instance`, lowercased.
+fn parse_account(account: &str) -> Result<(String, String)> {
+    let account = account.trim().trim_start_matches('@').to_lowercase();
+    match account.split_once('@') {
+        Some((user, instance))
+            if !user.is_empty()
+                && !instance.is_empty()
+                && !instance.contains(['/', '@', '?', '#']) =>
+        {
+            Ok((user.to_string(), instance.to_string()))
+        }
+        _ => Err(Error::InvalidUri(account)),
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(crate = "rocket::serde")]
+struct RegisteredApp {
+    client_id: String,
+    client_secret: String,
+}
+
+/// Register ourselves on the instance at `url`.
+async fn register(url: &str, base: &ResolvedBaseUrl, redirect_uri: &str) -> Result<RegisteredApp> {
+    let res = reqwest::Client::new()
+        .post(format!("{url}/api/v1/apps"))
+        .header("Accept", "application/json")
+        .header("User-Agent", APPLICATION_NAME)
+        .form(&[
+            ("client_name", env!("CARGO_PKG_NAME")),
+            ("redirect_uris", redirect_uri),
+            ("scopes", SCOPES.join(" ").as_str()),
+            ("website", base.0.as_str()),
+        ])
+        .send()
+        .await
+        .map_err(|why| Error::OAuth2(format!("{why}")))?;
+    if !res.status().is_success() {
+        return Err(Error::OAuth2(format!(
+            "{url} answered with {}",
+            res.status()
+        )));
+    }
+    res.json()
+        .await
+        .map_err(|why| Error::OAuth2(format!("{why}")))
+}
+
+fn oauth2(url: &str, app: &models::MastodonApp) -> OAuth2 {
+    let provider = oauth::Provider::new(
+        format!("{url}/oauth/authorize"),
+        format!("{url}/oauth/token"),
+    );
+    OAuth2::new(
+        format!("mastodon:{}", app.instance),
+        OAuthConfig::new(
+            provider,
+            app.client_id.clone(),
+            Some(app.client_secret.clone()),
+            Some(app.redirect_uri.clone()),
+        ),
+    )
+}
+
+impl Instances {
+    /// Our app on `instance`, registering one if there's none yet.
+    async fn get(
+        &self,
+        db: &MainDatabase,
+        config: &Config,
+        base: &ResolvedBaseUrl,
+        instance: &str,
+    ) -> Result<Arc<OAuth2>> {
+        if let Some(oauth2) = self.0.read().await.get(instance) {
+            return Ok(oauth2.clone());
+        }
+        let name = instance.to_string();
+        let stored = db
+            .run(move |c| {
+                schema::mastodon_apps::table
+                    .find(&name)
+                    .first::<models::MastodonApp>(c)
+                    .optional()
+            })
+            .await?;
+        let redirect_uri = format!("{}/login/mastodon/callback", base.0);
+        let url = config.url(instance);
+        let app = match stored {
+            Some(app) if app.redirect_uri == redirect_uri => app,
+            _ => {
+                let registered = register(&url, base, &redirect_uri).await?;
+                let app = models::MastodonApp {
+                    instance: instance.to_string(),
+                    client_id: registered.client_id,
+                    client_secret: registered.client_secret,
+                    redirect_uri,
+                    created_at: Utc::now().to_rfc3339(),
+                };
+                let row = app.clone();
+                db.run(move |c| {
+                    diesel::replace_into(schema::mastodon_apps::table)
+                        .values(&row)
+                        .execute(c)
+                })
+                .await?;
+                app
+            }
+        };
+        let mut instances = self.0.write().await;
+        let oauth2 = instances
+            .entry(instance.to_string())
+            .or_insert_with(|| Arc::new(oauth2(&url, &app)));
+        Ok(oauth2.clone())
+    }
+
+    async fn cached(&self, instance: &str) -> Option<Arc<OAuth2>> {
+        self.0.read().await.get(instance).cloned()
+    }
+}
+
+/// Who a login on its way through an instance is meant to be.
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(crate = "rocket::serde")]
+struct Pending {
+    user: String,
+    instance: String,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(crate = "rocket::serde")]
+struct Account {
+    id: String,
+    username: String,
+}
+
+#[get("/mastodon?<account>")]
+#[instrument(skip(db, upstreams, config, instances, base, cookies))]
+pub async fn start(
+    db: MainDatabase,
+    upstreams: &State<Upstreams>,
+    config: &State<Config>,
+    instances: &State<Instances>,
+    base: ResolvedBaseUrl,
+    cookies: &CookieJar<'_>,
+    account: &str,
+) -> std::result::Result<Redirect, Login> {
+    let failed = |why: Error| Login::new(upstreams, Some(format!("{why}")));
+    let (user, instance) = parse_account(account).map_err(failed)?;
+    // don't go registering apps on instances for just anyone
+    if !config.allows(&user, &instance) {
+        return Err(failed(Error::OAuth2(
+            "I'm sorry Dave, I'm afraid I can't do that.".into(),
+        )));
+    }
+    let oauth2 = instances
+        .get(&db, config, &base, &instance)
+        .await
+        .map_err(failed)?;
+    let redirect = oauth2.get_redirect(cookies, SCOPES).await.map_err(failed)?;
+    PENDING
+        .put(cookies, Pending { user, instance })
+        .map_err(failed)?;
+    Ok(redirect)
+}
+
+#[get("/mastodon/callback?<code>&<state>")]
+#[instrument(skip(db, session_key, config, instances, cookies, code, state), err)]
+pub async fn callback(
+    db: MainDatabase,
+    session_key: &State<SessionKey>,
+    config: &State<Config>,
+    instances: &State<Instances>,
+    cookies: &CookieJar<'_>,
+    code: String,
+    state: String,
+) -> Result<LoggedIn> {
+    let pending: Pending = PENDING
+        .take(cookies)
+        .ok_or_else(|| Error::OAuth2("missing login cookie".into()))?;
+    let oauth2 = instances
+        .cached(&pending.instance)
+        .await
+        .ok_or_else(|| Error::OAuth2("this login started before a restart".into()))?;
+    let token = oauth2.exchange(cookies, code, &state).await?;
+    let account: Account = get_json(
+        &format!(
+            "{}/api/v1/accounts/verify_credentials",
+            config.url(&pending.instance)
+        ),
+        Some(&token),
+    )
+    .await?;
+    if !account.username.eq_ignore_ascii_case(&pending.user) {
+        return Err(Error::Forbidden(format!(
+            "logged in as {}, not {}",
+            account.username, pending.user
+        )));
+    }
+    let handle = format!("{}@{}", pending.user, pending.instance);
+    if !config.allows(&pending.user, &pending.instance) {
+        return Err(Error::OAuth2(
+            "I'm sorry Dave, I'm afraid I can't do that.".into(),
+        ));
+    }
+    let row = models::UpstreamToken {
+        id: generate_ulid_string(),
+        provider: format!("mastodon:{}", pending.instance),
+        user_id: account.id.clone(),
+        access_token: token.access_token().to_string(),
+        refresh_token: token.refresh_token().map(String::from),
+    };
+    db.run(move |c| {
+        diesel::insert_into(schema::upstream_tokens::table)
+            .values(&row)
+            .execute(c)
+    })
+    .await?;
+    let user = UpstreamUser {
+        id: account.id.clone(),
+        name: handle.clone(),
+        ..Default::default()
+    };
+    remember(&db, &handle, &user).await?;
+    let identity = Identity {
+        uid: format!("mastodon:{}@{}", account.id, pending.instance),
+        sub: handle,
+    };
+    finish_login(&db, session_key, cookies, identity, &[AMR_FEDERATED]).await
+}
@@ -0,0 +1,521 @@
+//! Upstream identity providers people log in with.
+//!
+//! Each `oauth.<name>` section in Rocket.toml configures a provider, served at
+//! `/login/<name>`. Its `kind`, the section name unless set, picks the [`Provider`]
+//! that knows how to find out who logged in, so several instances of one kind only
+//! take configuration. Any OpenID provider works with `kind = "oidc"` and its `issuer`.
+//!
+//! People with their own IndieAuth server can also log in with their URL; see
+//! [`indieauth`], and Fediverse accounts on any instance with [`mastodon`]. Anyone
+//! else whose homepage links to their profile on one of the providers can prove who
+//! they are with [`relme`], but doesn't become an owner. When none of them are
+//! reachable, [`email`] sends a login link instead, and [`signature`] takes a signed
+//! challenge from an SSH or OpenPGP key. `/login` lists all the ways in.
+
+use std::collections::BTreeMap;
+
+use askama::Template;
+use chrono::Utc;
+use diesel::prelude::*;
+use rocket::{
+    fairing::AdHoc,
+    figment::{value::Dict, Figment},
+    get,
+    http::{Cookie, CookieJar, SameSite},
+    response::Redirect,
+    serde::Deserialize,
+    Build, Rocket, State,
+};
+use rusty_ulid::generate_ulid_string;
+use serde::de::DeserializeOwned;
+use tracing::instrument;
+
+use crate::{
+    api::{self, Error, Result},
+    models,
+    oauth::{self, OAuth2, OAuthConfig, TokenResponse},
+    schema,
+    session::{Session, SessionKey, AMR_FEDERATED, AMR_MFA},
+    totp, MainDatabase, APPLICATION_NAME,
+};
+
+mod discovery;
+pub mod email;
+mod forgejo;
+mod github;
+mod gitlab;
+pub mod indieauth;
+pub mod mastodon;
+mod oidc;
+pub mod relme;
+pub mod signature;
+
+const NONCE_COOKIE: &str = "upstream_nonce";
+
+/// How long a login may spend at a provider before coming back, in seconds.
+const LOGIN_LIFETIME: i64 = 10 * 60;
+
+/// Someone as an upstream provider knows them.
+#[derive(Debug, Clone, Default)]
+pub struct UpstreamUser {
+    pub id: String,
+    pub name: String,
+    /// Their profile page, which RelMeAuth homepages link to.
+    pub profile: Option<String>,
+    /// The homepage they put on their profile.
+    pub website: Option<String>,
+    pub email: Option<String>,
+    /// Whether the provider checked they own `email`.
+    pub email_verified: bool,
+}
+
+/// Who someone is here: the user id their sessions are recorded under, and the
+/// subject of their tokens.
+#[derive(Debug, Clone)]
+pub struct Identity {
+    pub uid: String,
+    pub sub: String,
+}
+
+#[rocket::async_trait]
+pub trait Provider: Send + Sync {
+    /// The provider's endpoints, if they're always the same, so they can be left out
+    /// of the configuration.
+    fn endpoints(&self) -> Option<oauth::Provider> {
+        None
+    }
+
+    /// Whether this is an OpenID provider: it's always asked for the `openid` scope,
+    /// and sent a nonce to put in its ID tokens.
+    fn openid(&self) -> bool {
+        false
+    }
+
+    /// Where profile pages live, if this is somewhere RelMeAuth can point to.
+    fn site(&self) -> Option<String> {
+        None
+    }
+
+    /// Scopes to ask for, unless `scopes` is configured: just enough to find out who
+    /// the user is.
+    fn scopes(&self) -> Vec<String>;
+
+    /// Find out who a token response belongs to. OpenID providers get the nonce the
+    /// login started with.
+    async fn user(&self, token: &TokenResponse, nonce: Option<&str>) -> Result<UpstreamUser>;
+
+    /// Find someone by username, with the SSH and OpenPGP public keys they published,
+    /// for [`signature`] logins. Nothing, unless the provider publishes keys.
+    async fn public_keys(&self, _username: &str) -> Result<Option<(UpstreamUser, Vec<String>)>> {
+        Ok(None)
+    }
+
+    /// Map an upstream user to a local identity. Subjects are `name@provider`, so
+    /// users of different providers can't pass for each other.
+    fn identity(&self, provider: &str, user: &UpstreamUser) -> Identity {
+        Identity {
+            uid: format!("{provider}:{}", user.id),
+            sub: format!("{}@{provider}", user.name),
+        }
+    }
+}
+
+/// Build the provider for a `kind`. New kinds go here.
+async fn provider(
+    kind: &str,
+    name: &str,
+    figment: &Figment,
+) -> std::result::Result<Box<dyn Provider>, String> {
+    match kind {
+        "gitlab" => Ok(Box::new(gitlab::GitLab::from_figment(figment, name)?)),
+        "github" => Ok(Box::new(github::GitHub::from_figment(figment, name))),
+        "forgejo" | "gitea" => Ok(Box::new(forgejo::Forgejo::from_figment(figment, name)?)),
+        "oidc" => Ok(Box::new(oidc::Oidc::discover(figment, name).await?)),
+        _ => Err(format!("unknown provider kind {kind}")),
+    }
+}
+
+/// Read the `url` of a provider's instance.
+fn instance_url(figment: &Figment, name: &str) -> Option<String> {
+    figment
+        .extract_inner::<String>(&format!("oauth.{name}.url"))
+        .ok()
+        .map(|url| url.trim_end_matches('/').to_string())
+}
+
+/// `GET` some JSON from a provider, e.g. a user-info endpoint with an access token.
+async fn get_json<T: DeserializeOwned>(url: &str, token: Option<&TokenResponse>) -> Result<T> {
+    let c = reqwest::Client::new();
+    let mut r = c
+        .get(url)
+        .header("Accept", "application/json")
+        .header("User-Agent", APPLICATION_NAME);
+    if let Some(token) = token {
+        r = r.header("Authorization", format!("Bearer {}", token.access_token()));
+    }
+    let r = r.build().map_err(|why| Error::OAuth2(format!("{why}")))?;
+    let res = c
+        .execute(r)
+        .await
+        .map_err(|why| Error::OAuth2(format!("{why}")))?;
+    if !res.status().is_success() {
+        return Err(Error::OAuth2(format!(
+            "{url} answered with {}",
+            res.status()
+        )));
+    }
+    let bytes = res
+        .bytes()
+        .await
+        .map_err(|why| Error::OAuth2(format!("{why}")))?;
+    serde_json::from_slice(&bytes).map_err(Error::Json)
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(crate = "rocket::serde")]
+struct Config {
+    kind: Option<String>,
+    scopes: Option<Vec<String>>,
+    /// Upstream user ids allowed to log in. Nobody is, unless listed here.
+    #[serde(default)]
+    allowed_users: Vec<String>,
+    /// Use the bare upstream name as the subject, like before there were several
+    /// providers, so existing tokens keep their subject.
+    #[serde(default)]
+    bare_subjects: bool,
+}
+
+/// A configured provider.
+pub struct Upstream {
+    name: String,
+    oauth2: OAuth2,
+    provider: Box<dyn Provider>,
+    scopes: Vec<String>,
+    allowed_users: Vec<String>,
+    bare_subjects: bool,
+}
+
+impl Upstream {
+    async fn from_figment(figment: &Figment, name: &str) -> std::result::Result<Self, String> {
+        let config: Config = figment
+            .extract_inner(&format!("oauth.{name}"))
+            .map_err(|why| why.to_string())?;
+        let provider = provider(config.kind.as_deref().unwrap_or(name), name, figment).await?;
+        let oauth2 = OAuthConfig::from_figment(figment, name, provider.endpoints())
+            .map_err(|why| why.to_string())?;
+        let mut scopes = config.scopes.unwrap_or_else(|| provider.scopes());
+        if provider.openid() && !scopes.iter().any(|s| s == "openid") {
+            scopes.insert(0, "openid".into());
+        }
+        Ok(Self {
+            name: name.to_string(),
+            oauth2: OAuth2::new(name, oauth2),
+            scopes,
+            provider,
+            allowed_users: config.allowed_users,
+            bare_subjects: config.bare_subjects,
+        })
+    }
+
+    /// Send someone off to log in.
+    async fn redirect(&self, cookies: &CookieJar<'_>) -> Result<Redirect> {
+        let scopes: Vec<&str> = self.scopes.iter().map(String::as_str).collect();
+        if !self.provider.openid() {
+            return self.oauth2.get_redirect(cookies, &scopes).await;
+        }
+        let nonce = api::clients::random_string(32)?;
+        let redirect = self
+            .oauth2
+            .get_redirect_extras(cookies, &scopes, &[("nonce", &nonce)])
+            .await?;
+        cookies.add_private(
+            Cookie::build(NONCE_COOKIE, nonce)
+                .same_site(SameSite::Lax)
+                .finish(),
+        );
+        Ok(redirect)
+    }
+
+    fn identity(&self, user: &UpstreamUser) -> Identity {
+        let identity = self.provider.identity(&self.name, user);
+        if self.bare_subjects {
+            Identity {
+                sub: user.name.clone(),
+                ..identity
+            }
+        } else {
+            identity
+        }
+    }
+}
+
+/// The configured providers, by name.
+pub struct Upstreams {
+    providers: BTreeMap<String, Upstream>,
+    /// Whether anyone may log in with their own IndieAuth server.
+    indieauth: bool,
+    /// Whether RelMeAuth is on.
+    relme: bool,
+    /// Whether anyone may log in with a Mastodon account.
+    mastodon: bool,
+    /// Whether anyone may log in with a link sent by email.
+    email: bool,
+}
+
+impl Upstreams {
+    pub fn get(&self, name: &str) -> Result<&Upstream> {
+        self.providers.get(name).ok_or(Error::NotFound)
+    }
+
+    pub fn names(&self) -> impl Iterator<Item = &str> {
+        self.providers.keys().map(String::as_str)
+    }
+}
+
+#[derive(Template)]
+#[template(path = "login.html")]
+pub struct Login {
+    providers: Vec<String>,
+    indieauth: bool,
+    relme: bool,
+    mastodon: bool,
+    email: bool,
+    message: Option<String>,
+}
+
+impl Login {
+    fn new(upstreams: &Upstreams, message: Option<String>) -> Self {
+        Self {
+            providers: upstreams.names().map(String::from).collect(),
+            indieauth: upstreams.indieauth,
+            relme: upstreams.relme,
+            mastodon: upstreams.mastodon,
+            email: upstreams.email,
+            message,
+        }
+    }
+}
+
+/// A section that may be left out, but has to make sense if it isn't.
+fn optional<T: DeserializeOwned + Default>(
+    rocket: &Rocket<Build>,
+    key: &str,
+) -> std::result::Result<T, rocket::figment::Error> {
+    match rocket.figment().find_value(key) {
+        Ok(_) => rocket.figment().extract_inner(key),
+        Err(_) => Ok(T::default()),
+    }
+}
+
+/// Reads every `oauth.<name>` section, `indieauth_login`, `relme_login`,
+/// `mastodon_login` and `email_login`.
+pub fn fairing() -> AdHoc {
+    async fn fairing(rocket: Rocket<Build>) -> std::result::Result<Rocket<Build>, Rocket<Build>> {
+        let names: Vec<String> = rocket
+            .figment()
+            .extract_inner::<BTreeMap<String, Dict>>("oauth")
+            .map(|sections| sections.into_keys().collect())
+            .unwrap_or_default();
+        let mut upstreams = BTreeMap::new();
+        for name in names {
+            match Upstream::from_figment(rocket.figment(), &name).await {
+                Ok(upstream) => {
+                    if upstream.allowed_users.is_empty() {
+                        tracing::warn!("nobody is allowed to log in with {name}");
+                    }
+                    upstreams.insert(name, upstream);
+                }
+                Err(why) => {
+                    tracing::error!("invalid oauth.{name} configuration: {why}");
+                    return Err(rocket);
+                }
+            }
+        }
+        let indieauth: indieauth::Config = match optional(&rocket, "indieauth_login") {
+            Ok(config) => config,
+            Err(why) => {
+                tracing::error!("invalid indieauth_login configuration: {why}");
+                return Err(rocket);
+            }
+        };
+        let mastodon: mastodon::Config = match optional(&rocket, "mastodon_login") {
+            Ok(config) => config,
+            Err(why) => {
+                tracing::error!("invalid mastodon_login configuration: {why}");
+                return Err(rocket);
+            }
+        };
+        let email = match optional(&rocket, "email_login")
+            .map_err(|why| why.to_string())
+            .and_then(email::Email::from_config)
+        {
+            Ok(email) => email,
+            Err(why) => {
+                tracing::error!("invalid email_login configuration: {why}");
+                return Err(rocket);
+            }
+        };
+        let upstreams = Upstreams {
+            providers: upstreams,
+            indieauth: !indieauth.allowed_users.is_empty(),
+            relme: rocket
+                .figment()
+                .extract_inner("relme_login.enabled")
+                .unwrap_or(false),
+            mastodon: !mastodon.allowed_users.is_empty(),
+            email: email.is_some(),
+        };
+        Ok(rocket
+            .manage(upstreams)
+            .manage(indieauth)
+            .manage(mastodon)
+            .manage(mastodon::Instances::default())
+            .manage(signature::PublishedKeys::default())
+            .manage(email))
+    }
+    AdHoc::try_on_ignite("Upstream providers", fairing)
+}
+
+/// Keep what a provider said about someone logging in as `sub`, for OpenID Connect
+/// clients asking about them. The latest login wins.
+pub(crate) async fn remember(db: &MainDatabase, sub: &str, user: &UpstreamUser) -> Result {
+    let row = models::User {
+        sub: sub.to_string(),
+        name: user.name.clone(),
+        website: user.website.clone(),
+        email: user.email.clone(),
+        email_verified: user.email_verified,
+        updated_at: Utc::now().to_rfc3339(),
+    };
+    db.run(move |c| {
+        diesel::replace_into(schema::users::table)
+            .values(&row)
+            .execute(c)
+    })
+    .await?;
+    Ok(())
+}
+
+/// The page a login ends on. The session cookie is all a login hands out; tokens for
+/// anything else are minted at `/api/token/mint`, with a lifetime.
+#[derive(Template)]
+#[template(path = "logged_in.html")]
+pub struct Welcome {
+    sub: String,
+    message: Option<String>,
+}
+
+/// Where a login ends up: logged in, or on the way to a second factor.
+#[derive(rocket::Responder)]
+pub enum LoggedIn {
+    Done(Welcome),
+    SecondFactor(Redirect),
+}
+
+/// Finish logging in an owner, unless they have a second factor to enter first.
+pub async fn finish_login(
+    db: &MainDatabase,
+    session_key: &SessionKey,
+    cookies: &CookieJar<'_>,
+    identity: Identity,
+    amr: &[&str],
+) -> Result<LoggedIn> {
+    if !amr.contains(&AMR_MFA) && totp::enrolled(db, &identity.sub).await? {
+        return totp::defer(cookies, identity, amr).map(LoggedIn::SecondFactor);
+    }
+    complete_login(db, session_key, cookies, identity, amr)
+        .await
+        .map(LoggedIn::Done)
+}
+
+/// Start a session for an owner who just logged in.
+pub async fn complete_login(
+    db: &MainDatabase,
+    session_key: &SessionKey,
+    cookies: &CookieJar<'_>,
+    identity: Identity,
+    amr: &[&str],
+) -> Result<Welcome> {
+    Session::start(
+        db,
+        session_key,
+        cookies,
+        identity.uid,
+        identity.sub.clone(),
+        amr,
+        true,
+    )
+    .await?;
+    Ok(Welcome {
+        sub: identity.sub,
+        message: None,
+    })
+}
+
+#[get("/")]
+pub fn index(upstreams: &State<Upstreams>) -> Login {
+    Login::new(upstreams, None)
+}
+
+#[get("/<provider>")]
+#[instrument(skip(upstreams, cookies), err)]
+pub async fn login(
+    upstreams: &State<Upstreams>,
+    cookies: &CookieJar<'_>,
+    provider: &str,
+) -> Result<Redirect> {
+    relme::forget(cookies);
+    upstreams.get(provider)?.redirect(cookies).await
+}
+
+#[get("/<provider>/callback?<code>&<state>")]
+#[instrument(skip(db, session_key, upstreams, cookies, code, state), err)]
+pub async fn callback(
+    db: MainDatabase,
+    session_key: &State<SessionKey>,
+    upstreams: &State<Upstreams>,
+    cookies: &CookieJar<'_>,
+    provider: &str,
+    code: String,
+    state: String,
+) -> Result<LoggedIn> {
+    let upstream = upstreams.get(provider)?;
+    let token = upstream.oauth2.exchange(cookies, code, &state).await?;
+    let nonce = cookies.get_private(NONCE_COOKIE).map(|cookie| {
+        cookies.remove_private(cookie.clone());
+        cookie.value().to_string()
+    });
+    let user = upstream
+        .provider
+        .user(&token, nonce.as_deref())
+        .await
+        .map_err(|why| Error::OAuth2(format!("{why}")))?;
+    if let Some(pending) = relme::take(cookies) {
+        return relme::finish(&db, session_key, cookies, &user, pending)
+            .await
+            .map(LoggedIn::Done);
+    }
+    if !upstream.allowed_users.contains(&user.id) {
+        return Err(Error::OAuth2(
+            "I'm sorry Dave, I'm afraid I can't do that.".into(),
+        ));
+    }
+    let row = models::UpstreamToken {
+        id: generate_ulid_string(),
+        provider: upstream.name.clone(),
+        user_id: user.id.clone(),
+        access_token: token.access_token().to_string(),
+        refresh_token: token.refresh_token().map(String::from),
+    };
+    db.run(move |c| {
+        diesel::insert_into(schema::upstream_tokens::table)
+            .values(&row)
+            .execute(c)
+    })
+    .await
+    .map_err(Error::Database)
+    .map_err(|e| Error::OAuth2(format!("{e}")))?;
+    let identity = upstream.identity(&user);
+    remember(&db, &identity.sub, &user).await?;
+    finish_login(&db, session_key, cookies, identity, &[AMR_FEDERATED]).await
+}
@@ -0,0 +1,205 @@
+use std::time::{Duration, Instant};
+
+use chrono::Utc;
+use rocket::figment::Figment;
+use serde::Deserialize;
+use serde_json::Value;
+use tokio::sync::RwLock;
+
+use crate::{
+    api::{Error, Result},
+    jws,
+    oauth::{self, TokenResponse},
+};
+
+use super::{get_json, Provider, UpstreamUser};
+
+/// How long fetched keys are trusted before they're fetched again.
+const JWKS_LIFETIME: Duration = Duration::from_secs(60 * 60);
+
+/// Keys aren't fetched again for an unknown `kid` more often than this.
+const JWKS_MIN_REFRESH: Duration = Duration::from_secs(60);
+
+/// Leeway for clocks that don't quite agree, in seconds.
+const CLOCK_SKEW: i64 = 60;
+
+#[derive(Debug, Clone, Deserialize)]
+struct Metadata {
+    issuer: String,
+    authorization_endpoint: String,
+    token_endpoint: String,
+    jwks_uri: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct Config {
+    issuer: String,
+    client_id: String,
+    /// The claim that names the user.
+    #[serde(default = "default_name_claim")]
+    name_claim: String,
+}
+
+fn default_name_claim() -> String {
+    "preferred_username".into()
+}
+
+#[derive(Debug, Deserialize)]
+struct Jwks {
+    keys: Vec<Value>,
+}
+
+/// Any OpenID provider, configured by its `issuer` alone. Its endpoints are
+/// discovered on start, and who logged in is read off the ID token.
+pub struct Oidc {
+    metadata: Metadata,
+    client_id: String,
+    name_claim: String,
+    keys: RwLock<Option<(Instant, Vec<Value>)>>,
+}
+
+impl Oidc {
+    pub async fn discover(figment: &Figment, name: &str) -> std::result::Result<Self, String> {
+        let config: Config = figment
+            .extract_inner(&format!("oauth.{name}"))
+            .map_err(|why| why.to_string())?;
+        let issuer = config.issuer.trim_end_matches('/');
+        let metadata: Metadata =
+            get_json(&format!("{issuer}/.well-known/openid-configuration"), None)
+                .await
+                .map_err(|why| format!("discovery failed: {why}"))?;
+        if metadata.issuer.trim_end_matches('/') != issuer {
+            return Err(format!("{issuer} says its issuer is {}", metadata.issuer));
+        }
+        Ok(Self {
+            metadata,
+            client_id: config.client_id,
+            name_claim: config.name_claim,
+            keys: RwLock::new(None),
+        })
+    }
+
+    /// The keys an ID token may have been signed with, from the cache if it's fresh
+    /// and has any: signing keys of the right type for `alg`, and with `kid` if the
+    /// token names one.
+    async fn keys(&self, kid: Option<&str>, alg: &str) -> Result<Vec<Value>> {
+        let kty = match alg {
+            "RS256" | "RS384" | "RS512" | "PS256" => "RSA",
+            "ES256" | "ES384" => "EC",
+            "EdDSA" => "OKP",
+            _ => return Err(Error::InvalidJws(format!("unsupported alg {alg}"))),
+        };
+        let find = |keys: &[Value]| -> Vec<Value> {
+            let member = |key: &Value, name: &str| key.get(name).and_then(Value::as_str);
+            keys.iter()
+                .filter(|key| kid.is_none() || member(key, "kid") == kid)
+                .filter(|key| member(key, "use") != Some("enc"))
+                .filter(|key| member(key, "kty") == Some(kty))
+                .filter(|key| member(key, "alg").map_or(true, |a| a == alg))
+                .cloned()
+                .collect()
+        };
+        if let Some((fetched, keys)) = &*self.keys.read().await {
+            if fetched.elapsed() < JWKS_LIFETIME {
+                let found = find(keys);
+                if !found.is_empty() {
+                    return Ok(found);
+                }
+                if fetched.elapsed() < JWKS_MIN_REFRESH {
+                    return Err(Error::InvalidJws("no matching key".into()));
+                }
+            }
+        }
+        let jwks: Jwks = get_json(&self.metadata.jwks_uri, None).await?;
+        let found = find(&jwks.keys);
+        *self.keys.write().await = Some((Instant::now(), jwks.keys));
+        match found.is_empty() {
+            true => Err(Error::InvalidJws("no matching key".into())),
+            false => Ok(found),
+        }
+    }
+
+    async fn verify(&self, id_token: &str, nonce: &str) -> Result<Value> {
+        let header = jws::header(id_token)?;
+        let alg = header
+            .get("alg")
+            .and_then(Value::as_str)
+            .unwrap_or_default();
+        let keys = self
+            .keys(header.get("kid").and_then(Value::as_str), alg)
+            .await?;
+        // without a kid, any of them could be the one
+        let claims = keys
+            .iter()
+            .find_map(|key| jws::verify_jwk(id_token, key).ok())
+            .ok_or_else(|| Error::InvalidJws("bad signature".into()))?;
+        let invalid = |why: &str| Error::OAuth2(format!("invalid ID token: {why}"));
+        if claims.get("iss").and_then(Value::as_str) != Some(self.metadata.issuer.as_str()) {
+            return Err(invalid("wrong iss"));
+        }
+        let audience = match claims.get("aud") {
+            Some(Value::String(aud)) => vec![aud.as_str()],
+            Some(Value::Array(auds)) => auds.iter().filter_map(Value::as_str).collect(),
+            _ => vec![],
+        };
+        if !audience.contains(&self.client_id.as_str()) {
+            return Err(invalid("wrong aud"));
+        }
+        if audience.len() > 1
+            && claims.get("azp").and_then(Value::as_str) != Some(self.client_id.as_str())
+        {
+            return Err(invalid("wrong azp"));
+        }
+        match claims.get("exp").and_then(Value::as_i64) {
+            Some(exp) if exp + CLOCK_SKEW > Utc::now().timestamp() => {}
+            _ => return Err(invalid("expired")),
+        }
+        if claims.get("nonce").and_then(Value::as_str) != Some(nonce) {
+            return Err(invalid("wrong nonce"));
+        }
+        Ok(claims)
+    }
+}
+
+#[rocket::async_trait]
+impl Provider for Oidc {
+    fn endpoints(&self) -> Option<oauth::Provider> {
+        Some(oauth::Provider::new(
+            self.metadata.authorization_endpoint.clone(),
+            self.metadata.token_endpoint.clone(),
+        ))
+    }
+
+    fn openid(&self) -> bool {
+        true
+    }
+
+    fn scopes(&self) -> Vec<String> {
+        vec!["openid".into(), "profile".into()]
+    }
+
+    async fn user(&self, token: &TokenResponse, nonce: Option<&str>) -> Result<UpstreamUser> {
+        let id_token = token
+            .id_token()
+            .ok_or_else(|| Error::OAuth2("no ID token in the token response".into()))?;
+        let nonce = nonce.ok_or_else(|| Error::OAuth2("missing nonce cookie".into()))?;
+        let claims = self.verify(id_token, nonce).await?;
+        let sub = claims
+            .get("sub")
+            .and_then(Value::as_str)
+            .ok_or_else(|| Error::OAuth2("invalid ID token: no sub".into()))?;
+        let name = claims
+            .get(&self.name_claim)
+            .and_then(Value::as_str)
+            .unwrap_or(sub);
+        let claim = |name: &str| claims.get(name).and_then(Value::as_str).map(String::from);
+        Ok(UpstreamUser {
+            id: sub.to_string(),
+            name: name.to_string(),
+            profile: claim("profile"),
+            website: claim("website"),
+            email: claim("email"),
+            email_verified: claims.get("email_verified").and_then(Value::as_bool) == Some(true),
+        })
+    }
+}
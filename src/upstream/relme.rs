@@ -0,0 +1,134 @@
+//! RelMeAuth: your homepage links to your profile on one of our providers with
+//! `rel="me"`, that profile links back to your homepage, and you log in there.
+//!
+//! That proves who you are, not that you're the owner, so these sessions can't
+//! approve anything on the site's behalf, and get no admin token.
+
+use rocket::{
+    get,
+    http::{CookieJar, SameSite},
+    response::Redirect,
+    serde::{Deserialize, Serialize},
+    State,
+};
+use tracing::instrument;
+use url::Url;
+
+use crate::{
+    api::{Error, Result},
+    fetch::Fetcher,
+    session::{PendingCookie, Session, SessionKey, AMR_FEDERATED},
+    MainDatabase,
+};
+
+use super::{discovery, Login, UpstreamUser, Upstreams, Welcome, LOGIN_LIFETIME};
+
+const PENDING: PendingCookie = PendingCookie::new("relme_login", LOGIN_LIFETIME, SameSite::Lax);
+
+/// The homepage a RelMeAuth login is for, and the profile it has to come back as.
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(crate = "rocket::serde")]
+pub(super) struct Pending {
+    me: String,
+    /// The profile the homepage links to.
+    profile: String,
+}
+
+fn same_origin(a: &Url, b: &Url) -> bool {
+    a.scheme() == b.scheme()
+        && a.host_str().map(str::to_ascii_lowercase) == b.host_str().map(str::to_ascii_lowercase)
+        && a.port_or_known_default() == b.port_or_known_default()
+}
+
+/// Profile URLs compare without a trailing slash, since providers disagree on it.
+fn same_profile(a: &str, b: &str) -> bool {
+    a.trim_end_matches('/')
+        .eq_ignore_ascii_case(b.trim_end_matches('/'))
+}
+
+#[get("/relme?<me>")]
+#[instrument(skip(upstreams, fetcher, cookies))]
+pub async fn start(
+    upstreams: &State<Upstreams>,
+    fetcher: &State<Fetcher>,
+    cookies: &CookieJar<'_>,
+    me: &str,
+) -> std::result::Result<Redirect, Login> {
+    let failed = |why: Error| Login::new(upstreams, Some(format!("{why}")));
+    if !upstreams.relme {
+        return Err(failed(Error::NotFound));
+    }
+    let me = discovery::normalize(me).map_err(failed)?;
+    let page = discovery::fetch(fetcher, &me).await.map_err(failed)?;
+    // the first rel=me link to somewhere we can log in with
+    let found = page.rel("me").find_map(|link| {
+        upstreams.providers.values().find_map(|upstream| {
+            let site = Url::parse(&upstream.provider.site()?).ok()?;
+            same_origin(&site, link).then(|| (upstream, link.to_string()))
+        })
+    });
+    let (upstream, profile) = found.ok_or_else(|| {
+        failed(Error::OAuth2(format!(
+            "{me} doesn't link to a profile anywhere we know"
+        )))
+    })?;
+    let redirect = upstream.redirect(cookies).await.map_err(failed)?;
+    let pending = Pending {
+        me: me.to_string(),
+        profile,
+    };
+    PENDING.put(cookies, pending).map_err(failed)?;
+    Ok(redirect)
+}
+
+/// Drop a RelMeAuth login someone gave up on.
+pub(super) fn forget(cookies: &CookieJar<'_>) {
+    PENDING.forget(cookies);
+}
+
+/// The RelMeAuth login this provider callback finishes, if any.
+pub(super) fn take(cookies: &CookieJar<'_>) -> Option<Pending> {
+    PENDING.take(cookies)
+}
+
+/// Check that the account that just logged in is the one the homepage links to, and
+/// that it links back, then start a session for the homepage.
+pub(super) async fn finish(
+    db: &MainDatabase,
+    session_key: &SessionKey,
+    cookies: &CookieJar<'_>,
+    user: &UpstreamUser,
+    pending: Pending,
+) -> Result<Welcome> {
+    match &user.profile {
+        Some(profile) if same_profile(profile, &pending.profile) => {}
+        _ => {
+            return Err(Error::Forbidden(format!(
+                "{} doesn't link to this account",
+                pending.me
+            )))
+        }
+    }
+    let me = discovery::normalize(&pending.me)?;
+    let website = user.website.as_deref().map(discovery::normalize);
+    if !matches!(website, Some(Ok(website)) if website == me) {
+        return Err(Error::Forbidden(format!(
+            "{} doesn't link back to {me}",
+            pending.profile
+        )));
+    }
+    Session::start(
+        db,
+        session_key,
+        cookies,
+        format!("relme:{me}"),
+        me.to_string(),
+        &[AMR_FEDERATED],
+        false,
+    )
+    .await?;
+    Ok(Welcome {
+        sub: me.to_string(),
+        message: Some("That's not an owner, so you can't approve anything here.".into()),
+    })
+}
@@ -0,0 +1,573 @@
+//! Logging in by signing a challenge with an SSH key (`ssh-keygen -Y sign`) or an
+//! OpenPGP key (`gpg --clearsign`). The keys that count are the ones an owner
+//! registered at `/keys`, and the ones allowed users published on a provider that
+//! lists them, like GitLab.
+
+use std::{
+    collections::HashMap,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use askama::Template;
+use chrono::{DateTime, Utc};
+use diesel::prelude::*;
+use pgp::{
+    composed::cleartext::CleartextSignedMessage,
+    packet::{Signature as PgpSignature, SignatureType},
+    Deserializable, SignedPublicKey, SignedPublicSubKey,
+};
+use rocket::{
+    form::Form,
+    get,
+    http::{CookieJar, SameSite},
+    post,
+    response::Redirect,
+    FromForm, State,
+};
+use rusty_ulid::generate_ulid_string;
+use ssh_key::SshSig;
+use tracing::instrument;
+use url::Url;
+
+use crate::{
+    api::{clients::random_string, Result},
+    models, schema,
+    session::{PendingCookie, Session, SessionKey, AMR_SWK},
+    MainDatabase, ResolvedBaseUrl,
+};
+
+use super::{finish_login, Identity, LoggedIn, Upstream, UpstreamUser, Upstreams};
+
+/// A challenge handed out, only good in the browser it was shown in, and for five
+/// minutes.
+const CHALLENGE: PendingCookie =
+    PendingCookie::new("signature_challenge", 5 * 60, SameSite::Strict);
+
+/// The namespace SSH signatures have to be made in (`ssh-keygen -Y sign -n`), so one
+/// made for anything else doesn't count.
+const SSH_NAMESPACE: &str = concat!(env!("CARGO_PKG_NAME"), "-login");
+
+/// A public key to check signatures with.
+enum PublicKey {
+    Ssh(ssh_key::PublicKey),
+    Pgp(SignedPublicKey),
+}
+
+impl PublicKey {
+    /// An OpenSSH public key line, or an armored OpenPGP key.
+    fn parse(text: &str) -> std::result::Result<Self, String> {
+        let text = text.trim();
+        if text.starts_with("-----BEGIN PGP PUBLIC KEY BLOCK-----") {
+            let (key, _) = SignedPublicKey::from_string(text).map_err(|why| format!("{why}"))?;
+            key.verify().map_err(|why| format!("{why}"))?;
+            Ok(Self::Pgp(key))
+        } else {
+            ssh_key::PublicKey::from_openssh(text)
+                .map(Self::Ssh)
+                .map_err(|why| format!("{why}"))
+        }
+    }
+
+    fn kind(&self) -> &'static str {
+        match self {
+            Self::Ssh(_) => "SSH",
+            Self::Pgp(_) => "OpenPGP",
+        }
+    }
+
+    /// Whether `signature` is this key's, over `challenge`.
+    fn verifies(&self, signature: &Signature, challenge: &str) -> bool {
+        match (self, signature) {
+            (Self::Ssh(key), Signature::Ssh(sig)) => {
+                // `echo` without `-n` adds a newline, which is easy to forget
+                [challenge.to_string(), format!("{challenge}\n")]
+                    .iter()
+                    .any(|msg| key.verify(SSH_NAMESPACE, msg.as_bytes(), sig).is_ok())
+            }
+            (Self::Pgp(key), Signature::Pgp(msg)) => {
+                let now = Utc::now();
+                msg.signed_text().trim_end() == challenge
+                    && ((primary_signs(key, now) && msg.verify(key).is_ok())
+                        || signing_subkeys(key, now).any(|sub| msg.verify(sub).is_ok()))
+            }
+            _ => false,
+        }
+    }
+}
+
+/// Whether the primary key of `key` can make signatures that count at `now`: it
+/// isn't revoked or expired, and its self-signatures let it sign.
+fn primary_signs(key: &SignedPublicKey, now: DateTime<Utc>) -> bool {
+    let bindings = key
+        .details
+        .users
+        .iter()
+        .flat_map(|user| &user.signatures)
+        .chain(&key.details.direct_signatures);
+    !revoked(key)
+        && key.expires_at().map_or(true, |at| at > now)
+        && can_sign(bindings, *key.primary_key.created_at(), now)
+}
+
+/// The subkeys of `key` that can make signatures that count at `now`. None do once
+/// the primary key is revoked or expired.
+fn signing_subkeys(
+    key: &SignedPublicKey,
+    now: DateTime<Utc>,
+) -> impl Iterator<Item = &SignedPublicSubKey> {
+    let usable = !revoked(key) && key.expires_at().map_or(true, |at| at > now);
+    key.public_subkeys.iter().filter(move |sub| {
+        usable
+            && !sub
+                .signatures
+                .iter()
+                .any(|sig| sig.typ() == SignatureType::SubkeyRevocation)
+            && can_sign(
+                sub.signatures
+                    .iter()
+                    .filter(|sig| sig.typ() == SignatureType::SubkeyBinding),
+                *sub.key.created_at(),
+                now,
+            )
+    })
+}
+
+fn revoked(key: &SignedPublicKey) -> bool {
+    key.details
+        .revocation_signatures
+        .iter()
+        .any(|sig| sig.typ() == SignatureType::KeyRevocation)
+}
+
+/// Whether a key created at `created_at` may sign at `now`, going by the
+/// self-signatures binding it: one has to allow signing without having let the key
+/// expire.
+fn can_sign<'a>(
+    bindings: impl IntoIterator<Item = &'a PgpSignature>,
+    created_at: DateTime<Utc>,
+    now: DateTime<Utc>,
+) -> bool {
+    bindings.into_iter().any(|sig| {
+        sig.key_flags().sign()
+            && sig
+                .key_expiration_time()
+                .map_or(true, |lifetime| created_at + *lifetime > now)
+    })
+}
+
+/// A signed challenge, as pasted.
+enum Signature {
+    Ssh(SshSig),
+    Pgp(CleartextSignedMessage),
+}
+
+impl Signature {
+    fn parse(text: &str) -> std::result::Result<Self, String> {
+        let text = text.trim();
+        if text.starts_with("-----BEGIN SSH SIGNATURE-----") {
+            SshSig::from_pem(text)
+                .map(Self::Ssh)
+                .map_err(|why| format!("{why}"))
+        } else if text.starts_with("-----BEGIN PGP SIGNED MESSAGE-----") {
+            CleartextSignedMessage::from_string(text)
+                .map(|(msg, _)| Self::Pgp(msg))
+                .map_err(|why| format!("{why}"))
+        } else {
+            Err("that's neither an SSH signature nor a clearsigned message".into())
+        }
+    }
+}
+
+fn new_challenge(cookies: &CookieJar<'_>, base: &ResolvedBaseUrl) -> Result<String> {
+    let host = Url::parse(&base.0)
+        .ok()
+        .and_then(|url| url.host_str().map(String::from))
+        .unwrap_or_else(|| base.0.clone());
+    let challenge = format!("{host} login {}", random_string(24)?);
+    CHALLENGE.put(cookies, &challenge)?;
+    Ok(challenge)
+}
+
+fn take_challenge(cookies: &CookieJar<'_>) -> Option<String> {
+    CHALLENGE.take(cookies)
+}
+
+/// Someone a signature could be from, with one of their keys.
+struct Candidate {
+    identity: Identity,
+    key: PublicKey,
+    /// The `login_keys` row, for keys registered here.
+    registered: Option<String>,
+}
+
+/// How long what a provider said about a username is reused for.
+const PUBLISHED_KEYS_TTL: Duration = Duration::from_secs(5 * 60);
+
+/// How many lookups are remembered at once. Past that, the oldest lookup that didn't
+/// turn up an allowed user is forgotten first.
+const MAX_CACHED: usize = 256;
+
+type Published = Option<(UpstreamUser, Vec<String>)>;
+
+/// What providers said recently about whose keys are whose, by provider and username,
+/// so anyone posting signatures can't make us ask them over and over.
+#[derive(Default)]
+pub struct PublishedKeys(Mutex<HashMap<(String, String), Cached>>);
+
+struct Cached {
+    at: Instant,
+    allowed: bool,
+    published: Published,
+}
+
+impl PublishedKeys {
+    async fn get(&self, upstream: &Upstream, username: &str) -> Published {
+        let key = (upstream.name.clone(), username.to_string());
+        {
+            let mut found = self.0.lock().expect("published keys lock poisoned");
+            found.retain(|_, cached| cached.at.elapsed() < PUBLISHED_KEYS_TTL);
+            if let Some(cached) = found.get(&key) {
+                return cached.published.clone();
+            }
+        }
+        // failures are remembered too, a provider that's down won't be up in a second
+        let published = upstream
+            .provider
+            .public_keys(username)
+            .await
+            .unwrap_or_else(|why| {
+                tracing::warn!(provider = %upstream.name, "failed to fetch keys: {why}");
+                None
+            });
+        let allowed = published
+            .as_ref()
+            .map_or(false, |(user, _)| upstream.allowed_users.contains(&user.id));
+        let mut found = self.0.lock().expect("published keys lock poisoned");
+        if found.len() >= MAX_CACHED {
+            let oldest = found
+                .iter()
+                .min_by_key(|(_, cached)| (cached.allowed, cached.at))
+                .map(|(key, _)| key.clone());
+            if let Some(oldest) = oldest {
+                found.remove(&oldest);
+            }
+        }
+        found.insert(
+            key,
+            Cached {
+                at: Instant::now(),
+                allowed,
+                published: published.clone(),
+            },
+        );
+        published
+    }
+}
+
+/// Every key `username` could have signed with: the ones registered here for that
+/// subject, then the ones published on providers by an allowed user of that name.
+async fn candidates(
+    db: &MainDatabase,
+    upstreams: &Upstreams,
+    published: &PublishedKeys,
+    username: &str,
+) -> Vec<Candidate> {
+    let sub = username.to_string();
+    let registered = db
+        .run(move |c| {
+            use schema::login_keys::dsl;
+            dsl::login_keys
+                .filter(dsl::sub.eq(&sub))
+                .load::<models::LoginKey>(c)
+        })
+        .await
+        .unwrap_or_else(|why| {
+            tracing::error!("failed to load login keys: {why}");
+            vec![]
+        });
+    let mut candidates: Vec<Candidate> = registered
+        .into_iter()
+        .filter_map(|row| {
+            Some(Candidate {
+                key: PublicKey::parse(&row.public_key).ok()?,
+                identity: Identity {
+                    uid: row.user_id,
+                    sub: row.sub,
+                },
+                registered: Some(row.id),
+            })
+        })
+        .collect();
+    for upstream in upstreams.providers.values() {
+        let Some((user, keys)) = published.get(upstream, username).await else {
+            continue;
+        };
+        if !upstream.allowed_users.contains(&user.id) {
+            continue;
+        }
+        let identity = upstream.identity(&user);
+        candidates.extend(keys.iter().filter_map(|key| {
+            Some(Candidate {
+                key: PublicKey::parse(key).ok()?,
+                identity: identity.clone(),
+                registered: None,
+            })
+        }));
+    }
+    candidates
+}
+
+#[derive(Template)]
+#[template(path = "signature.html")]
+pub struct SignatureLogin {
+    challenge: String,
+    namespace: &'static str,
+    message: Option<String>,
+}
+
+impl SignatureLogin {
+    fn new(
+        cookies: &CookieJar<'_>,
+        base: &ResolvedBaseUrl,
+        message: Option<String>,
+    ) -> Result<Self> {
+        Ok(Self {
+            challenge: new_challenge(cookies, base)?,
+            namespace: SSH_NAMESPACE,
+            message,
+        })
+    }
+}
+
+#[get("/signature")]
+#[instrument(skip(base, cookies), err)]
+pub fn login_page(base: ResolvedBaseUrl, cookies: &CookieJar<'_>) -> Result<SignatureLogin> {
+    SignatureLogin::new(cookies, &base, None)
+}
+
+#[derive(FromForm, Debug)]
+pub struct Signed {
+    username: String,
+    signature: String,
+}
+
+#[derive(rocket::Responder)]
+pub enum Verified {
+    LoggedIn(LoggedIn),
+    Retry(SignatureLogin),
+}
+
+#[post("/signature", data = "<signed>")]
+#[instrument(
+    skip(db, session_key, upstreams, published, base, cookies, signed),
+    err
+)]
+pub async fn login(
+    db: MainDatabase,
+    session_key: &State<SessionKey>,
+    upstreams: &State<Upstreams>,
+    published: &State<PublishedKeys>,
+    base: ResolvedBaseUrl,
+    cookies: &CookieJar<'_>,
+    signed: Form<Signed>,
+) -> Result<Verified> {
+    let retry =
+        |message: String| SignatureLogin::new(cookies, &base, Some(message)).map(Verified::Retry);
+    let Some(challenge) = take_challenge(cookies) else {
+        return retry("That challenge expired, sign this one instead.".into());
+    };
+    let signature = match Signature::parse(&signed.signature) {
+        Ok(signature) => signature,
+        Err(why) => return retry(why),
+    };
+    let username = signed.username.trim();
+    let found = candidates(&db, upstreams, published, username)
+        .await
+        .into_iter()
+        .find(|candidate| candidate.key.verifies(&signature, &challenge));
+    let Some(candidate) = found else {
+        return retry(format!("That isn't a signature by any key of {username}."));
+    };
+    if let Some(id) = candidate.registered {
+        db.run(move |c| {
+            use schema::login_keys::dsl;
+            diesel::update(dsl::login_keys.find(&id))
+                .set(dsl::last_used_at.eq(Utc::now().to_rfc3339()))
+                .execute(c)
+        })
+        .await?;
+    }
+    finish_login(&db, session_key, cookies, candidate.identity, &[AMR_SWK])
+        .await
+        .map(Verified::LoggedIn)
+}
+
+/// A registered key, as listed.
+pub struct Listed {
+    id: String,
+    name: String,
+    kind: &'static str,
+    created_at: String,
+    last_used_at: String,
+}
+
+#[derive(Template)]
+#[template(path = "keys.html")]
+pub struct Keys {
+    sub: String,
+    keys: Vec<Listed>,
+    message: Option<String>,
+}
+
+async fn keys_page(db: &MainDatabase, session: Session, message: Option<String>) -> Result<Keys> {
+    let uid = session.uid.clone();
+    let keys = db
+        .run(move |c| {
+            use schema::login_keys::dsl;
+            dsl::login_keys
+                .filter(dsl::user_id.eq(&uid))
+                .order(dsl::created_at.asc())
+                .load::<models::LoginKey>(c)
+        })
+        .await?;
+    Ok(Keys {
+        sub: session.sub,
+        keys: keys
+            .into_iter()
+            .map(|k| Listed {
+                kind: PublicKey::parse(&k.public_key).map_or("unreadable", |key| key.kind()),
+                id: k.id,
+                name: k.name,
+                created_at: k.created_at,
+                last_used_at: k.last_used_at.unwrap_or_else(|| "never".into()),
+            })
+            .collect(),
+        message,
+    })
+}
+
+#[get("/keys")]
+#[instrument(skip(db), err)]
+pub async fn manage(db: MainDatabase, session: Session) -> Result<Keys> {
+    session.require_owner()?;
+    keys_page(&db, session, None).await
+}
+
+#[derive(FromForm, Debug)]
+pub struct NewKey {
+    name: String,
+    public_key: String,
+}
+
+#[post("/keys", data = "<new>")]
+#[instrument(skip(db, new), err)]
+pub async fn add(db: MainDatabase, session: Session, new: Form<NewKey>) -> Result<Keys> {
+    session.require_owner()?;
+    if let Err(why) = PublicKey::parse(&new.public_key) {
+        return keys_page(&db, session, Some(format!("That key didn't work: {why}"))).await;
+    }
+    let new = new.into_inner();
+    let row = models::LoginKey {
+        id: generate_ulid_string(),
+        user_id: session.uid.clone(),
+        sub: session.sub.clone(),
+        name: new.name,
+        public_key: new.public_key.trim().to_string(),
+        created_at: Utc::now().to_rfc3339(),
+        last_used_at: None,
+    };
+    db.run(move |c| {
+        diesel::insert_into(schema::login_keys::table)
+            .values(&row)
+            .execute(c)
+    })
+    .await?;
+    keys_page(&db, session, None).await
+}
+
+#[post("/keys/<id>/remove")]
+#[instrument(skip(db), err)]
+pub async fn remove(db: MainDatabase, session: Session, id: String) -> Result<Redirect> {
+    session.require_owner()?;
+    let uid = session.uid.clone();
+    db.run(move |c| {
+        use schema::login_keys::dsl;
+        diesel::delete(
+            dsl::login_keys
+                .filter(dsl::id.eq(&id))
+                .filter(dsl::user_id.eq(&uid)),
+        )
+        .execute(c)
+    })
+    .await?;
+    Ok(Redirect::to("/keys"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// What every fixture signature is over.
+    const SIGNED: &str = "localhost login testchallenge";
+
+    fn verifies(key: &str, signature: &str, challenge: &str) -> bool {
+        let key = PublicKey::parse(key).expect("fixture key parses");
+        let signature = Signature::parse(signature).expect("fixture signature parses");
+        key.verifies(&signature, challenge)
+    }
+
+    const SSH_KEY: &str = include_str!("../../tests/fixtures/signature/ssh.pub");
+    const PGP_KEY: &str = include_str!("../../tests/fixtures/signature/pgp.asc");
+
+    #[test]
+    fn ssh_signature() {
+        let signature = include_str!("../../tests/fixtures/signature/ssh-login.sig");
+        assert!(verifies(SSH_KEY, signature, SIGNED));
+        assert!(!verifies(
+            SSH_KEY,
+            signature,
+            "localhost login otherchallenge"
+        ));
+    }
+
+    #[test]
+    fn ssh_signature_wrong_namespace() {
+        let signature = include_str!("../../tests/fixtures/signature/ssh-file.sig");
+        assert!(!verifies(SSH_KEY, signature, SIGNED));
+    }
+
+    #[test]
+    fn ssh_signature_other_key() {
+        let other = include_str!("../../tests/fixtures/signature/ssh-other.pub");
+        let signature = include_str!("../../tests/fixtures/signature/ssh-login.sig");
+        assert!(!verifies(other, signature, SIGNED));
+    }
+
+    #[test]
+    fn pgp_signature() {
+        let primary = include_str!("../../tests/fixtures/signature/pgp-primary.asc");
+        let subkey = include_str!("../../tests/fixtures/signature/pgp-subkey.asc");
+        assert!(verifies(PGP_KEY, primary, SIGNED));
+        assert!(verifies(PGP_KEY, subkey, SIGNED));
+        assert!(!verifies(PGP_KEY, subkey, "localhost login otherchallenge"));
+    }
+
+    #[test]
+    fn pgp_signature_revoked_subkey() {
+        let signature = include_str!("../../tests/fixtures/signature/pgp-revoked.asc");
+        assert!(!verifies(PGP_KEY, signature, SIGNED));
+    }
+
+    #[test]
+    fn pgp_signature_expired_subkey() {
+        let signature = include_str!("../../tests/fixtures/signature/pgp-expired.asc");
+        assert!(!verifies(PGP_KEY, signature, SIGNED));
+    }
+
+    #[test]
+    fn mismatched_kinds() {
+        let signature = include_str!("../../tests/fixtures/signature/ssh-login.sig");
+        assert!(!verifies(PGP_KEY, signature, SIGNED));
+    }
+}
@@ -1,4 +1,17 @@
-use rocket::get;
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD as BASE64_URL_SAFE_NO_PAD, Engine};
+use rocket::{
+    get,
+    http::Header,
+    serde::{json::Json, Serialize},
+    Responder, State,
+};
+use serde_json::{json, Value};
+
+use crate::{
+    api::{clients, device, oidc, par, token},
+    paseto::{KeyRing, Keypair, Version},
+    subject, ResolvedBaseUrl,
+};
 
 #[get("/.well-known/botinfo")]
 pub async fn botinfo() -> &'static str {
@@ -14,3 +27,155 @@ pub async fn robots() -> String {
 pub async fn security() -> String {
     include_str!("./security.txt").to_string()
 }
+
+#[derive(Serialize, Debug)]
+#[serde(crate = "rocket::serde")]
+pub struct PublicKey {
+    kid: String,
+    version: Version,
+    status: &'static str,
+    paserk: String,
+}
+
+impl PublicKey {
+    fn new(kp: &Keypair, status: &'static str) -> Self {
+        Self {
+            kid: kp.pid(),
+            version: kp.version(),
+            status,
+            paserk: kp.paserk(),
+        }
+    }
+}
+
+#[derive(Serialize, Debug)]
+#[serde(crate = "rocket::serde")]
+pub struct PublicKeys {
+    keys: Vec<PublicKey>,
+}
+
+#[derive(Responder)]
+pub struct KeySet {
+    inner: Json<PublicKeys>,
+    cache_control: Header<'static>,
+}
+
+/// The keys tokens are signed with, so other services can verify them offline.
+///
+/// Verifiers should refetch this when they see a `kid` they don't know, as a
+/// freshly rotated key is used straight away.
+#[get("/.well-known/paseto-keys")]
+pub async fn paseto_keys(ring: &State<KeyRing>) -> KeySet {
+    let active = ring.active_keys();
+    let retired = ring.retired();
+    let keys = active
+        .iter()
+        .map(|kp| PublicKey::new(kp, "active"))
+        .chain(retired.iter().map(|kp| PublicKey::new(kp, "retired")))
+        .filter(|key| ring.accepted().contains(&key.version))
+        .collect();
+    KeySet {
+        inner: Json(PublicKeys { keys }),
+        cache_control: Header::new("Cache-Control", "public, max-age=600"),
+    }
+}
+
+/// OpenID Connect discovery, which also serves as RFC 8414 authorization server
+/// metadata.
+#[get("/.well-known/openid-configuration")]
+pub async fn openid_configuration(
+    base: ResolvedBaseUrl,
+    par: &State<par::Config>,
+    registration: &State<clients::Registration>,
+) -> Json<Value> {
+    let base = base.0;
+    let mut config = json!({
+        "issuer": base,
+        "authorization_endpoint": format!("{base}/api/auth"),
+        "token_endpoint": format!("{base}/api/token"),
+        "userinfo_endpoint": format!("{base}/api/userinfo"),
+        "jwks_uri": format!("{base}/.well-known/jwks.json"),
+        "pushed_authorization_request_endpoint": format!("{base}/api/par"),
+        "require_pushed_authorization_requests": par.required,
+        "device_authorization_endpoint": format!("{base}/api/device_authorization"),
+        "scopes_supported": [oidc::OPENID_SCOPE, oidc::PROFILE_SCOPE, oidc::EMAIL_SCOPE],
+        "response_types_supported": ["code"],
+        "grant_types_supported": [
+            "authorization_code",
+            "client_credentials",
+            device::GRANT_TYPE,
+            token::TOKEN_EXCHANGE_GRANT_TYPE,
+        ],
+        "subject_types_supported": [subject::PUBLIC, subject::PAIRWISE],
+        "id_token_signing_alg_values_supported": ["EdDSA"],
+        "token_endpoint_auth_methods_supported": [
+            "none",
+            "client_secret_basic",
+            "client_secret_post",
+            "private_key_jwt",
+        ],
+        "token_endpoint_auth_signing_alg_values_supported": ["EdDSA"],
+        "code_challenge_methods_supported": ["S256"],
+        "dpop_signing_alg_values_supported": ["EdDSA", "ES256"],
+        "claims_supported": [
+            "iss", "sub", "aud", "exp", "iat", "auth_time", "nonce", "acr", "amr",
+            "name", "preferred_username", "website", "email", "email_verified",
+        ],
+    });
+    if registration.enabled() {
+        config["registration_endpoint"] = format!("{base}/api/register").into();
+    }
+    Json(config)
+}
+
+#[derive(Serialize, Debug)]
+#[serde(crate = "rocket::serde")]
+pub struct Jwk {
+    kty: &'static str,
+    crv: &'static str,
+    x: String,
+    kid: String,
+    #[serde(rename = "use")]
+    use_: &'static str,
+    alg: &'static str,
+}
+
+impl From<&Keypair> for Jwk {
+    fn from(kp: &Keypair) -> Self {
+        Self {
+            kty: "OKP",
+            crv: "Ed25519",
+            x: BASE64_URL_SAFE_NO_PAD.encode(kp.public()),
+            kid: kp.kid().to_string(),
+            use_: "sig",
+            alg: "EdDSA",
+        }
+    }
+}
+
+#[derive(Serialize, Debug)]
+#[serde(crate = "rocket::serde")]
+pub struct Jwks {
+    keys: Vec<Jwk>,
+}
+
+#[derive(Responder)]
+pub struct JwkSet {
+    inner: Json<Jwks>,
+    cache_control: Header<'static>,
+}
+
+/// The same keys as [`paseto_keys`], as a JWK set for verifying ID tokens.
+#[get("/.well-known/jwks.json")]
+pub async fn jwks(ring: &State<KeyRing>) -> JwkSet {
+    let keys = ring
+        .active_keys()
+        .iter()
+        .chain(ring.retired().iter())
+        .map(Jwk::from)
+        .collect();
+    JwkSet {
+        inner: Json(Jwks { keys }),
+        cache_control: Header::new("Cache-Control", "public, max-age=600"),
+    }
+}
@@ -0,0 +1,79 @@
+// Drives the WebAuthn ceremonies for /passkeys and /login/passkey. Binary fields
+// travel as base64url, both ways.
+
+const toBytes = (s) => Uint8Array.from(atob(s.replace(/-/g, '+').replace(/_/g, '/')), (c) => c.charCodeAt(0));
+const fromBytes = (buf) => btoa(String.fromCharCode(...new Uint8Array(buf))).replace(/\+/g, '-').replace(/\//g, '_').replace(/=+$/, '');
+
+async function post(url, body) {
+  const res = await fetch(url, {
+    method: 'POST',
+    credentials: 'same-origin',
+    headers: body ? { 'Content-Type': 'application/json' } : {},
+    body: body ? JSON.stringify(body) : undefined,
+  });
+  if (!res.ok) {
+    throw new Error(`${url} answered with ${res.status}`);
+  }
+  return res;
+}
+
+function report(message) {
+  document.getElementById('passkey-status').textContent = message;
+}
+
+async function registerPasskey() {
+  const options = await (await post('/passkeys/options')).json();
+  options.challenge = toBytes(options.challenge);
+  options.user.id = toBytes(options.user.id);
+  options.excludeCredentials = options.excludeCredentials.map((c) => ({ ...c, id: toBytes(c.id) }));
+  const credential = await navigator.credentials.create({ publicKey: options });
+  await post('/passkeys', {
+    id: credential.id,
+    clientDataJSON: fromBytes(credential.response.clientDataJSON),
+    authenticatorData: fromBytes(credential.response.getAuthenticatorData()),
+    publicKey: fromBytes(credential.response.getPublicKey()),
+    publicKeyAlgorithm: credential.response.getPublicKeyAlgorithm(),
+    name: document.getElementById('passkey-name').value,
+  });
+  location.reload();
+}
+
+async function removePasskey(id) {
+  const res = await fetch(`/passkeys/${id}`, { method: 'DELETE', credentials: 'same-origin' });
+  if (!res.ok) {
+    throw new Error(`removing the passkey failed with ${res.status}`);
+  }
+  location.reload();
+}
+
+async function logInWithPasskey() {
+  const options = await (await post('/login/passkey/options')).json();
+  options.challenge = toBytes(options.challenge);
+  const credential = await navigator.credentials.get({ publicKey: options });
+  const res = await post('/login/passkey', {
+    id: credential.id,
+    clientDataJSON: fromBytes(credential.response.clientDataJSON),
+    authenticatorData: fromBytes(credential.response.authenticatorData),
+    signature: fromBytes(credential.response.signature),
+    userHandle: credential.response.userHandle ? fromBytes(credential.response.userHandle) : null,
+  });
+  if (res.redirected) {
+    // on to a second factor
+    location.assign(res.url);
+    return;
+  }
+  report(await res.text());
+}
+
+document.addEventListener('click', (event) => {
+  const action = event.target.dataset.passkey;
+  const run = {
+    register: () => registerPasskey(),
+    remove: () => removePasskey(event.target.dataset.id),
+    login: () => logInWithPasskey(),
+  }[action];
+  if (run) {
+    event.preventDefault();
+    run().catch((err) => report(err.message));
+  }
+});
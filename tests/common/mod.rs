@@ -0,0 +1,47 @@
+//! What every integration test starts with: the whole server, on a database of its
+//! own, with whatever configuration the test adds.
+
+#![allow(dead_code)]
+
+use diesel::{Connection, SqliteConnection};
+use diesel_migrations::{embed_migrations, EmbeddedMigrations, MigrationHarness};
+use rocket::local::asynchronous::Client;
+use serde_json::Value;
+use url::Url;
+
+const MIGRATIONS: EmbeddedMigrations = embed_migrations!("./migrations");
+
+pub const BASE_URL: &str = "http://localhost:7778";
+
+/// A server configured with `config`, as `(key, value)` pairs like `("oauth.github",
+/// json!({...}))`.
+pub async fn client(config: &[(&str, Value)]) -> Client {
+    let db = std::env::temp_dir().join(format!(
+        "indieauth-test-{}.db",
+        rusty_ulid::generate_ulid_string()
+    ));
+    let db = db.to_str().expect("temporary paths are UTF-8").to_string();
+    let mut conn = SqliteConnection::establish(&db).expect("can't create the test database");
+    conn.run_pending_migrations(MIGRATIONS)
+        .expect("can't run migrations");
+    let mut figment = rocket::Config::figment()
+        .merge(("databases.main_data.url", &db))
+        .merge(("asset_path", "static"))
+        .merge(("base_url", BASE_URL))
+        .merge(("log_level", "off"));
+    for (key, value) in config {
+        figment = figment.merge((*key, value));
+    }
+    Client::tracked(indieauth::build(figment))
+        .await
+        .expect("the server should start")
+}
+
+/// A query parameter of the URL a response redirects to.
+pub fn redirect_param(location: &str, name: &str) -> Option<String> {
+    Url::parse(location)
+        .ok()?
+        .query_pairs()
+        .find(|(key, _)| key == name)
+        .map(|(_, value)| value.into_owned())
+}
@@ -0,0 +1,158 @@
+//! Logging in with a link sent by email, through a local SMTP sink.
+
+mod common;
+
+use std::time::Duration;
+
+use rocket::{
+    http::{ContentType, Status},
+    local::asynchronous::Client,
+};
+use serde_json::json;
+use tokio::{
+    io::{AsyncBufReadExt, AsyncWriteExt, BufReader},
+    net::TcpListener,
+    sync::mpsc,
+    time::timeout,
+};
+
+use common::client;
+
+const ADDRESS: &str = "someone@example.com";
+
+/// An SMTP server that accepts anything and hands over each message it's given.
+async fn sink() -> (u16, mpsc::UnboundedReceiver<String>) {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let port = listener.local_addr().unwrap().port();
+    let (tx, rx) = mpsc::unbounded_channel();
+    tokio::spawn(async move {
+        while let Ok((stream, _)) = listener.accept().await {
+            let tx = tx.clone();
+            tokio::spawn(async move {
+                let (read, mut write) = stream.into_split();
+                let mut lines = BufReader::new(read).lines();
+                write.write_all(b"220 sink\r\n").await?;
+                while let Some(line) = lines.next_line().await? {
+                    let command = line.to_ascii_uppercase();
+                    if command.starts_with("DATA") {
+                        write.write_all(b"354 go ahead\r\n").await?;
+                        let mut message = String::new();
+                        while let Some(line) = lines.next_line().await? {
+                            if line == "." {
+                                break;
+                            }
+                            message.push_str(&line);
+                            message.push('\n');
+                        }
+                        let _ = tx.send(message);
+                        write.write_all(b"250 queued\r\n").await?;
+                    } else if command.starts_with("QUIT") {
+                        write.write_all(b"221 bye\r\n").await?;
+                        break;
+                    } else {
+                        write.write_all(b"250 ok\r\n").await?;
+                    }
+                }
+                std::io::Result::Ok(())
+            });
+        }
+    });
+    (port, rx)
+}
+
+async fn server(port: u16, lifetime: i64) -> Client {
+    client(&[(
+        "email_login",
+        json!({
+            "smtp_url": format!("smtp://127.0.0.1:{port}"),
+            "from": "Login <login@example.com>",
+            "accounts": { ADDRESS: { "uid": "1", "sub": "someone" } },
+            "lifetime": lifetime,
+        }),
+    )])
+    .await
+}
+
+async fn start(client: &Client, address: &str) {
+    let res = client
+        .post("/login/email")
+        .header(ContentType::Form)
+        .body(format!("email={address}"))
+        .dispatch()
+        .await;
+    assert_eq!(res.status(), Status::Ok);
+}
+
+/// The token in the login link of the next message.
+async fn token(mail: &mut mpsc::UnboundedReceiver<String>) -> String {
+    let message = timeout(Duration::from_secs(5), mail.recv())
+        .await
+        .expect("a login link should arrive")
+        .unwrap();
+    let (_, rest) = message.split_once("token=").expect("a link with a token");
+    rest.chars()
+        .take_while(|c| c.is_ascii_alphanumeric() || matches!(c, '.' | '-' | '_'))
+        .collect()
+}
+
+async fn confirm(client: &Client, token: &str) -> Status {
+    client
+        .post("/login/email/confirm")
+        .header(ContentType::Form)
+        .body(format!("token={token}"))
+        .dispatch()
+        .await
+        .status()
+}
+
+#[rocket::async_test]
+async fn login() {
+    let (port, mut mail) = sink().await;
+    let client = server(port, 900).await;
+
+    start(&client, ADDRESS).await;
+    let token = token(&mut mail).await;
+    let res = client
+        .get(format!("/login/email?token={token}"))
+        .dispatch()
+        .await;
+    assert_eq!(res.status(), Status::Ok);
+    assert!(client.cookies().get("session").is_none());
+
+    assert_eq!(confirm(&client, &token).await, Status::Ok);
+    assert!(client.cookies().get("session").is_some());
+}
+
+#[rocket::async_test]
+async fn link_works_once() {
+    let (port, mut mail) = sink().await;
+    let client = server(port, 900).await;
+
+    start(&client, ADDRESS).await;
+    let token = token(&mut mail).await;
+    assert_eq!(confirm(&client, &token).await, Status::Ok);
+    assert_eq!(confirm(&client, &token).await, Status::Forbidden);
+}
+
+#[rocket::async_test]
+async fn expired_link() {
+    let (port, mut mail) = sink().await;
+    let client = server(port, 1).await;
+
+    start(&client, ADDRESS).await;
+    let token = token(&mut mail).await;
+    tokio::time::sleep(Duration::from_secs(2)).await;
+    assert_ne!(confirm(&client, &token).await, Status::Ok);
+    assert!(client.cookies().get("session").is_none());
+}
+
+#[rocket::async_test]
+async fn unlisted_address_gets_nothing() {
+    let (port, mut mail) = sink().await;
+    let client = server(port, 900).await;
+
+    start(&client, "someone-else@example.com").await;
+    assert!(timeout(Duration::from_millis(500), mail.recv())
+        .await
+        .is_err());
+}
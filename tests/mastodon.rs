@@ -0,0 +1,123 @@
+//! Logging in with a Mastodon account, against a mock instance.
+
+mod common;
+
+use rocket::{http::Status, local::asynchronous::Client};
+use serde_json::json;
+use wiremock::{
+    matchers::{body_string_contains, header, method, path},
+    Mock, MockServer, ResponseTemplate,
+};
+
+use common::{client, redirect_param};
+
+/// An instance where `username` logs in with the code `the-code`, expecting us to
+/// register `apps` times.
+async fn instance(username: &str, apps: u64) -> MockServer {
+    let server = MockServer::start().await;
+    Mock::given(method("POST"))
+        .and(path("/api/v1/apps"))
+        .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+            "client_id": "app-id",
+            "client_secret": "app-secret",
+        })))
+        .expect(apps)
+        .mount(&server)
+        .await;
+    Mock::given(method("POST"))
+        .and(path("/oauth/token"))
+        .and(body_string_contains("code=the-code"))
+        .and(body_string_contains("client_id=app-id"))
+        .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+            "access_token": "masto-token",
+            "token_type": "Bearer",
+        })))
+        .mount(&server)
+        .await;
+    Mock::given(method("GET"))
+        .and(path("/api/v1/accounts/verify_credentials"))
+        .and(header("Authorization", "Bearer masto-token"))
+        .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+            "id": "109",
+            "username": username,
+        })))
+        .mount(&server)
+        .await;
+    server
+}
+
+async fn server(allowed: &str) -> Client {
+    client(&[(
+        "mastodon_login",
+        json!({ "allowed_users": [allowed], "scheme": "http" }),
+    )])
+    .await
+}
+
+#[rocket::async_test]
+async fn login() {
+    let instance = instance("someone", 1).await;
+    let account = format!("someone@{}", instance.address());
+    let client = server(&account).await;
+
+    let res = client
+        .get(format!("/login/mastodon?account={account}"))
+        .dispatch()
+        .await;
+    assert_eq!(res.status(), Status::SeeOther);
+    let location = res.headers().get_one("Location").unwrap();
+    assert!(location.starts_with(&format!("{}/oauth/authorize?", instance.uri())));
+    assert_eq!(
+        redirect_param(location, "client_id").as_deref(),
+        Some("app-id")
+    );
+    let state = redirect_param(location, "state").unwrap();
+
+    let res = client
+        .get(format!(
+            "/login/mastodon/callback?code=the-code&state={state}"
+        ))
+        .dispatch()
+        .await;
+    assert_eq!(res.status(), Status::Ok);
+    assert!(client.cookies().get("session").is_some());
+}
+
+#[rocket::async_test]
+async fn not_allowed_never_reaches_the_instance() {
+    let instance = instance("someone", 0).await;
+    let client = server(&format!("someone@{}", instance.address())).await;
+
+    let res = client
+        .get(format!(
+            "/login/mastodon?account=someone-else@{}",
+            instance.address()
+        ))
+        .dispatch()
+        .await;
+    assert!(res.headers().get_one("Location").is_none());
+    assert!(client.cookies().get("session").is_none());
+}
+
+#[rocket::async_test]
+async fn someone_else_logged_in() {
+    let instance = instance("someone-else", 1).await;
+    let account = format!("someone@{}", instance.address());
+    let client = server(&account).await;
+
+    let res = client
+        .get(format!("/login/mastodon?account={account}"))
+        .dispatch()
+        .await;
+    let location = res.headers().get_one("Location").unwrap();
+    let state = redirect_param(location, "state").unwrap();
+
+    let res = client
+        .get(format!(
+            "/login/mastodon/callback?code=the-code&state={state}"
+        ))
+        .dispatch()
+        .await;
+    assert_eq!(res.status(), Status::Forbidden);
+    assert!(client.cookies().get("session").is_none());
+}
@@ -0,0 +1,205 @@
+//! Logging in with OAuth providers, against mock ones.
+
+mod common;
+
+use rocket::http::Status;
+use serde_json::{json, Value};
+use wiremock::{
+    matchers::{body_string_contains, header, method, path},
+    Mock, MockServer, ResponseTemplate,
+};
+
+use common::{client, redirect_param, BASE_URL};
+
+/// A provider that hands out `token` for the code `the-code` at `token_path`, and
+/// says who it belongs to at `user_path`.
+async fn provider(token_path: &str, user_path: &str, token: &str, user: Value) -> MockServer {
+    let server = MockServer::start().await;
+    Mock::given(method("POST"))
+        .and(path(token_path))
+        .and(body_string_contains("code=the-code"))
+        .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+            "access_token": token,
+            "token_type": "bearer",
+        })))
+        .mount(&server)
+        .await;
+    Mock::given(method("GET"))
+        .and(path(user_path))
+        .and(header("Authorization", format!("Bearer {token}").as_str()))
+        .respond_with(ResponseTemplate::new(200).set_body_json(user))
+        .mount(&server)
+        .await;
+    server
+}
+
+async fn github() -> MockServer {
+    provider(
+        "/login/oauth/access_token",
+        "/api/v3/user",
+        "gho_token",
+        json!({
+            "id": 1,
+            "login": "octocat",
+            "html_url": "https://github.example.com/octocat",
+            "blog": "https://octocat.example",
+            "email": "octocat@example.com",
+        }),
+    )
+    .await
+}
+
+fn github_config(name: &str, server: &MockServer, allowed: &str) -> Value {
+    json!({
+        "kind": "github",
+        "url": server.uri(),
+        "client_id": "id",
+        "client_secret": "secret",
+        "redirect_uri": format!("{BASE_URL}/login/{name}/callback"),
+        "allowed_users": [allowed],
+    })
+}
+
+/// Start logging in with `name`, returning the state sent along to the provider.
+async fn start(client: &rocket::local::asynchronous::Client, name: &str) -> String {
+    let res = client.get(format!("/login/{name}")).dispatch().await;
+    assert_eq!(res.status(), Status::SeeOther);
+    let location = res.headers().get_one("Location").unwrap().to_string();
+    redirect_param(&location, "state").expect("a state")
+}
+
+#[rocket::async_test]
+async fn github_login() {
+    let server = github().await;
+    let client = client(&[("oauth.github", github_config("github", &server, "1"))]).await;
+
+    let res = client.get("/login/github").dispatch().await;
+    assert_eq!(res.status(), Status::SeeOther);
+    let location = res.headers().get_one("Location").unwrap();
+    assert!(location.starts_with(&format!("{}/login/oauth/authorize?", server.uri())));
+    assert_eq!(redirect_param(location, "client_id").as_deref(), Some("id"));
+    let state = redirect_param(location, "state").unwrap();
+
+    let res = client
+        .get(format!(
+            "/login/github/callback?code=the-code&state={state}"
+        ))
+        .dispatch()
+        .await;
+    assert_eq!(res.status(), Status::Ok);
+    assert!(client.cookies().get("session").is_some());
+    let body = res.into_string().await.unwrap();
+    assert!(body.contains("Logged in"));
+    assert!(!body.contains("v4.public."));
+}
+
+#[rocket::async_test]
+async fn github_endpoint_overrides() {
+    let server = provider(
+        "/custom/token",
+        "/api/v3/user",
+        "gho_token",
+        json!({ "id": 1, "login": "octocat" }),
+    )
+    .await;
+    let mut config = github_config("github", &server, "1");
+    config["auth_uri"] = json!(format!("{}/custom/authorize", server.uri()));
+    config["token_uri"] = json!(format!("{}/custom/token", server.uri()));
+    let client = client(&[("oauth.github", config)]).await;
+
+    let res = client.get("/login/github").dispatch().await;
+    let location = res.headers().get_one("Location").unwrap();
+    assert!(location.starts_with(&format!("{}/custom/authorize?", server.uri())));
+    let state = redirect_param(location, "state").unwrap();
+
+    let res = client
+        .get(format!(
+            "/login/github/callback?code=the-code&state={state}"
+        ))
+        .dispatch()
+        .await;
+    assert_eq!(res.status(), Status::Ok);
+}
+
+#[rocket::async_test]
+async fn gitlab_login() {
+    let server = provider(
+        "/oauth/token",
+        "/api/v4/user",
+        "glpat",
+        json!({ "id": 34, "name": "someone", "public_email": "" }),
+    )
+    .await;
+    let client = client(&[(
+        "oauth.gitlab",
+        json!({
+            "url": server.uri(),
+            "client_id": "id",
+            "client_secret": "secret",
+            "redirect_uri": format!("{BASE_URL}/login/gitlab/callback"),
+            "allowed_users": ["34"],
+        }),
+    )])
+    .await;
+
+    let state = start(&client, "gitlab").await;
+    let res = client
+        .get(format!(
+            "/login/gitlab/callback?code=the-code&state={state}"
+        ))
+        .dispatch()
+        .await;
+    assert_eq!(res.status(), Status::Ok);
+    assert!(client.cookies().get("session").is_some());
+}
+
+#[rocket::async_test]
+async fn wrong_state() {
+    let server = github().await;
+    let client = client(&[("oauth.github", github_config("github", &server, "1"))]).await;
+
+    start(&client, "github").await;
+    let res = client
+        .get("/login/github/callback?code=the-code&state=not-the-state")
+        .dispatch()
+        .await;
+    assert_ne!(res.status(), Status::Ok);
+    assert!(client.cookies().get("session").is_none());
+}
+
+#[rocket::async_test]
+async fn state_of_another_provider() {
+    let server = github().await;
+    let other = github().await;
+    let client = client(&[
+        ("oauth.github", github_config("github", &server, "1")),
+        ("oauth.other", github_config("other", &other, "1")),
+    ])
+    .await;
+
+    let state = start(&client, "other").await;
+    let res = client
+        .get(format!(
+            "/login/github/callback?code=the-code&state={state}"
+        ))
+        .dispatch()
+        .await;
+    assert_ne!(res.status(), Status::Ok);
+    assert!(client.cookies().get("session").is_none());
+}
+
+#[rocket::async_test]
+async fn not_allowed() {
+    let server = github().await;
+    let client = client(&[("oauth.github", github_config("github", &server, "2"))]).await;
+
+    let state = start(&client, "github").await;
+    let res = client
+        .get(format!(
+            "/login/github/callback?code=the-code&state={state}"
+        ))
+        .dispatch()
+        .await;
+    assert_ne!(res.status(), Status::Ok);
+    assert!(client.cookies().get("session").is_none());
+}